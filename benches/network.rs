@@ -0,0 +1,129 @@
+// Benchmark of the shared network on its own, outside of the simulator. Every thread finds the path
+// of a payment and then adds and removes an HTLC on every channel of the path, as the payment router
+// does. The same work is run once with a single lock around the whole network and once with the lock
+// of every channel, and the payments per second of both are printed for an increasing number of
+// threads. This only tells how the network scales when a program shares it between threads: the
+// simulation of a scenario runs on one thread, so its payments never contend for the locks. Its
+// payments per second on the same network are printed last for reference.
+//
+// cargo bench --bench network
+use lnsimulator::{dijkstra, new_channel, write_graph, Graph, Network, Scenario};
+use petgraph::graph::NodeIndex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::hint::black_box;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+// Size of the random network, and number of payments every thread sends.
+const NODES: usize = 2000;
+const CHANNELS_PER_NODE: usize = 4;
+const PAYMENTS_PER_THREAD: usize = 200;
+const AMOUNT: u64 = 1_000_000; // msat
+const SIMULATED_PAYMENTS: u64 = 5000;
+
+// Function to build a random network with the given seed, where every node opens a few channels to
+// uniformly chosen nodes.
+fn random_graph(seed: u64) -> Graph {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut graph = Graph::new();
+    let nodes: Vec<NodeIndex> = (0..NODES).map(|id| graph.add_node(id)).collect();
+    for &node in &nodes {
+        for _ in 0..CHANNELS_PER_NODE {
+            let peer = nodes[rng.gen_range(0..NODES)];
+            if peer == node || graph.find_edge(node, peer).is_some() {
+                continue;
+            }
+            for (from, to) in [(node, peer), (peer, node)] {
+                let basefee = rng.gen_range(0..2000);
+                let feerate = rng.gen_range(0..1000);
+                let balance = rng.gen_range(10_000_000..1_000_000_000);
+                let delay = rng.gen_range(40..144);
+                let attrs = new_channel(balance, basefee, feerate, delay);
+                graph.add_edge(from, to, attrs);
+            }
+        }
+    }
+    graph
+}
+
+// Function to send one payment: find its path, then add and remove an HTLC on every channel of the
+// path, locking one channel at a time.
+fn send_payment(network: &Network, rng: &mut StdRng) {
+    let sender = NodeIndex::new(rng.gen_range(0..NODES));
+    let recipient = NodeIndex::new(rng.gen_range(0..NODES));
+    let (path, _, amounts) = dijkstra(network, recipient, sender, AMOUNT);
+    for i in 0..path.len().saturating_sub(1) {
+        if let Some(edge) = network.find_edge(path[i], path[i + 1]) {
+            let mut attrs = network.channel(edge);
+            attrs.balance = attrs.balance.saturating_sub(amounts[i]);
+            attrs.pending_htlcs += 1;
+        }
+    }
+    for i in 0..path.len().saturating_sub(1) {
+        if let Some(edge) = network.find_edge(path[i], path[i + 1]) {
+            let mut attrs = network.channel(edge);
+            attrs.balance += amounts[i];
+            attrs.pending_htlcs -= 1;
+        }
+    }
+    black_box(path);
+}
+
+// Function to measure the payments per second of the given number of threads, each sending its
+// payments through the given function.
+fn throughput<F: Fn(&mut StdRng) + Sync>(threads: usize, send: F) -> f64 {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for t in 0..threads {
+            let send = &send;
+            scope.spawn(move || {
+                let mut rng = StdRng::seed_from_u64(t as u64);
+                for _ in 0..PAYMENTS_PER_THREAD {
+                    send(&mut rng);
+                }
+            });
+        }
+    });
+    (threads * PAYMENTS_PER_THREAD) as f64 / start.elapsed().as_secs_f64()
+}
+
+// Function to measure the payments per second of the simulation of a scenario on the given graph, on
+// the single-threaded runtime with a paused clock the simulator runs on.
+fn simulation_throughput(graph: &Graph) -> f64 {
+    let dir = std::env::temp_dir().join(format!("lnsimulator-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("graph.json");
+    write_graph(graph, &path).unwrap();
+    let mut scenario = Scenario::default();
+    scenario.graph.path = path;
+    scenario.stop.max_payments = Some(SIMULATED_PAYMENTS);
+    scenario.output.dir = dir.clone();
+    scenario.output.payments_csv = false;
+    scenario.output.payments_jsonl = false;
+    scenario.output.summary = false;
+    scenario.validate().unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().start_paused(true).build().unwrap();
+    let start = Instant::now();
+    let summary = runtime.block_on(lnsimulator::simulate(scenario)).unwrap();
+    let rate = summary.payments as f64 / start.elapsed().as_secs_f64();
+    let _ = std::fs::remove_dir_all(dir);
+    rate
+}
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    println!("{} nodes, {} channel directions, {} cores", NODES, random_graph(1).edge_count(), cores);
+    println!("{:>8} {:>16} {:>16} {:>8}", "threads", "global lock/s", "channel locks/s", "ratio");
+    for threads in [1, 2, 4, 8, 16] {
+        // A single lock held for the whole payment, as with the former Arc<Mutex<DiGraph>>.
+        let global = Mutex::new(Network::new(random_graph(1)));
+        let global_rate = throughput(threads, |rng| send_payment(&global.lock().unwrap(), rng));
+        // The lock of every channel, held only while the channel is read or updated.
+        let network = Network::new(random_graph(1));
+        let channel_rate = throughput(threads, |rng| send_payment(&network, rng));
+        println!("{:>8} {:>16.0} {:>16.0} {:>7.2}x", threads, global_rate, channel_rate, channel_rate / global_rate);
+    }
+    println!("simulation of a single run, on one thread: {:.0} payments/s", simulation_throughput(&random_graph(1)));
+}
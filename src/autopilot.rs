@@ -0,0 +1,340 @@
+use log::{info, error};
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use serde::{de, Deserialize, Deserializer};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::create_graph::{msat_to_sat, sat_to_msat};
+use crate::latency::LatencyModel;
+use crate::ledger::Ledger;
+use crate::network::Network;
+use crate::payment_scheduler;
+use crate::scenario;
+use crate::topology::{self, default_base_fee_msat, default_cltv_expiry_delta, default_fee_rate_ppm};
+
+// How an autopilot chooses the peers of its new channels, among the nodes with an open channel that
+// it has no open channel with yet.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(tag = "strategy", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AutopilotStrategy {
+    // Peers drawn with a probability proportional to their number of open channels, as the
+    // preferential attachment heuristic of the LND autopilot.
+    PreferentialAttachment,
+    // Peers with the highest betweenness centrality, which puts the node on many shortest paths. The
+    // centrality is estimated from the shortest paths in hops out of the given number of sources
+    // drawn at random, as computing it exactly is too slow on the full snapshot.
+    Betweenness {
+        #[serde(default = "default_samples")]
+        samples: usize,
+    },
+    // Peers drawn uniformly at random.
+    Random,
+    // Peers that earned the most routing revenue over their channels so far, and the best-connected
+    // nodes while no revenue has been earned yet.
+    FeeRevenue,
+}
+
+fn default_samples() -> usize {
+    100
+}
+
+// Autopilot of a node that opens channels to the peers of its strategy, funding them from a budget,
+// at the start of the run and then every interval if given. The node is named by its id in the
+// snapshot, as for the other agents, and without one a new node joins the network at the start. Every
+// round opens up to the given number of channels of the given capacity, with the same forwarding
+// policy in both directions, until the budget can no longer fund one.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(remote = "Self")]
+pub struct Autopilot {
+    #[serde(flatten)]
+    pub strategy: AutopilotStrategy,
+    #[serde(default)]
+    pub node: Option<usize>, // Id of the node in the snapshot, its index in the graph once resolved
+    pub budget: f64, // Total capacity in satoshis of the channels the autopilot may open
+    pub channel_capacity: f64, // Capacity in satoshis of every channel, funded by the node
+    #[serde(default = "default_channels")]
+    pub channels: usize, // Channels opened per round at most
+    #[serde(default)]
+    pub interval_secs: Option<f64>, // Time between two rounds, or a single round at the start
+    #[serde(default = "default_base_fee_msat")]
+    pub base_fee_msat: u64,
+    #[serde(default = "default_fee_rate_ppm")]
+    pub fee_rate_ppm: u64,
+    #[serde(default = "default_cltv_expiry_delta")]
+    pub cltv_expiry_delta: u32,
+}
+
+fn default_channels() -> usize {
+    5
+}
+
+// Fields of an autopilot next to those of its strategy. Serde does not reject unknown fields next to
+// a flattened strategy, so the autopilot is read as a table and its fields are checked first.
+const FIELDS: &[&str] = &["strategy", "node", "budget", "channel_capacity", "channels", "interval_secs", "base_fee_msat", "fee_rate_ppm", "cltv_expiry_delta"];
+
+impl AutopilotStrategy {
+    // Fields of the strategy with the given name.
+    fn fields(strategy: &str) -> &'static [&'static str] {
+        match strategy {
+            "betweenness" => &["samples"],
+            _ => &[],
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Autopilot {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let table = toml::Table::deserialize(deserializer)?;
+        let strategy = table.get("strategy").and_then(toml::Value::as_str).unwrap_or_default();
+        scenario::deny_unknown_fields(&table, FIELDS, AutopilotStrategy::fields(strategy))?;
+        // The inherent function derived by serde reads the fields themselves.
+        Autopilot::deserialize(toml::Value::Table(table)).map_err(de::Error::custom)
+    }
+}
+
+impl Autopilot {
+    // Function to check that the budget, the channels and the interval make sense.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.channel_capacity.is_finite() || self.channel_capacity <= 0.0 {
+            return Err("channel_capacity must be positive".to_string());
+        }
+        if !self.budget.is_finite() || self.budget < self.channel_capacity {
+            return Err("budget must fund at least one channel".to_string());
+        }
+        if self.channels == 0 {
+            return Err("channels must be positive".to_string());
+        }
+        if self.interval_secs.is_some_and(|interval| !interval.is_finite() || interval <= 0.0) {
+            return Err("interval_secs must be positive".to_string());
+        }
+        match self.strategy {
+            AutopilotStrategy::Betweenness { samples: 0 } => Err("samples must be positive".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Structure to hold the channels opened by the autopilots. Capacities are in msat.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AutopilotReport {
+    pub joined: usize, // Nodes that joined the network to run their autopilot
+    pub opened: usize,
+    pub capacity: u64, // Total capacity of the channels opened
+    pub failed: usize, // Channels that could not be opened
+}
+
+// Open channels of the network as lists of peers by node, in both directions.
+fn open_peers(network: &Network) -> BTreeMap<NodeIndex, BTreeSet<NodeIndex>> {
+    let topology = network.topology();
+    let mut peers: BTreeMap<NodeIndex, BTreeSet<NodeIndex>> = BTreeMap::new();
+    for edge in topology.edge_references() {
+        if network.channel(edge.id()).is_open() {
+            peers.entry(edge.source()).or_default().insert(edge.target());
+            peers.entry(edge.target()).or_default().insert(edge.source());
+        }
+    }
+    peers
+}
+
+// Function to estimate the betweenness centrality of every node with the algorithm of Brandes,
+// counting the shortest paths in hops out of the sources only.
+fn betweenness(peers: &BTreeMap<NodeIndex, BTreeSet<NodeIndex>>, sources: &[NodeIndex]) -> BTreeMap<NodeIndex, f64> {
+    let mut centrality: BTreeMap<NodeIndex, f64> = BTreeMap::new();
+    for &source in sources {
+        let mut order = Vec::new();
+        let mut predecessors: BTreeMap<NodeIndex, Vec<NodeIndex>> = BTreeMap::new();
+        let mut paths: BTreeMap<NodeIndex, f64> = BTreeMap::from([(source, 1.0)]);
+        let mut distance: BTreeMap<NodeIndex, usize> = BTreeMap::from([(source, 0)]);
+        let mut queue = VecDeque::from([source]);
+        while let Some(node) = queue.pop_front() {
+            order.push(node);
+            for &peer in peers.get(&node).into_iter().flatten() {
+                if !distance.contains_key(&peer) {
+                    distance.insert(peer, distance[&node] + 1);
+                    queue.push_back(peer);
+                }
+                if distance[&peer] == distance[&node] + 1 {
+                    *paths.entry(peer).or_default() += paths[&node];
+                    predecessors.entry(peer).or_default().push(node);
+                }
+            }
+        }
+        let mut dependency: BTreeMap<NodeIndex, f64> = BTreeMap::new();
+        for &node in order.iter().rev() {
+            let share = (1.0 + dependency.get(&node).copied().unwrap_or(0.0)) / paths[&node];
+            for &predecessor in predecessors.get(&node).into_iter().flatten() {
+                *dependency.entry(predecessor).or_default() += paths[&predecessor] * share;
+            }
+            if node != source {
+                *centrality.entry(node).or_default() += dependency.get(&node).copied().unwrap_or(0.0);
+            }
+        }
+    }
+    centrality
+}
+
+// State of a running autopilot: the network it opens channels in, its node, the rest of its budget in
+// msat and the channels it opened so far.
+struct Pilot {
+    network: Arc<Network>,
+    autopilot: Autopilot,
+    node: NodeIndex,
+    remaining: u64,
+    latency: LatencyModel,
+    ledger: Arc<Mutex<Ledger>>,
+    rng: StdRng,
+    report: AutopilotReport,
+}
+
+impl Pilot {
+    // Function to choose the peers of the new channels of the node with the strategy of the
+    // autopilot.
+    fn choose_peers(&mut self, count: usize) -> Vec<NodeIndex> {
+        let peers = open_peers(&self.network);
+        let connected = peers.get(&self.node).cloned().unwrap_or_default();
+        let mut candidates: Vec<NodeIndex> = peers.keys().copied().filter(|&peer| peer != self.node && !connected.contains(&peer)).collect();
+        let degree = |peer: &NodeIndex| peers.get(peer).map_or(0, BTreeSet::len);
+        match self.autopilot.strategy {
+            AutopilotStrategy::PreferentialAttachment => {
+                return candidates
+                    .choose_multiple_weighted(&mut self.rng, count, |peer| degree(peer) as f64)
+                    .map_or_else(|_| Vec::new(), |peers| peers.copied().collect());
+            }
+            AutopilotStrategy::Random => return candidates.choose_multiple(&mut self.rng, count).copied().collect(),
+            AutopilotStrategy::Betweenness { samples } => {
+                let nodes: Vec<NodeIndex> = peers.keys().copied().collect();
+                let sources: Vec<NodeIndex> = nodes.choose_multiple(&mut self.rng, samples).copied().collect();
+                let centrality = betweenness(&peers, &sources);
+                let score = |peer: &NodeIndex| centrality.get(peer).copied().unwrap_or(0.0);
+                candidates.sort_by(|a, b| score(b).total_cmp(&score(a)).then(a.cmp(b)));
+            }
+            AutopilotStrategy::FeeRevenue => {
+                // Only the forwarding fees of a channel are earned by the node forwarding over it, as
+                // the upfront and hold fees follow the policy of its incoming channel.
+                let mut revenue: BTreeMap<NodeIndex, u64> = BTreeMap::new();
+                if let Ok(ledger) = self.ledger.lock() {
+                    for ((from, _), account) in ledger.channel_report() {
+                        *revenue.entry(from).or_default() += account.forwarding_fees;
+                    }
+                }
+                let score = |peer: &NodeIndex| (revenue.get(peer).copied().unwrap_or(0), degree(peer));
+                candidates.sort_by(|a, b| score(b).cmp(&score(a)).then(a.cmp(b)));
+            }
+        }
+        candidates.truncate(count);
+        candidates
+    }
+
+    // Function to run a round of the autopilot, opening channels to the peers it chooses as long as
+    // the rest of its budget can fund them.
+    fn open_round(&mut self) {
+        let autopilot = self.autopilot;
+        let capacity = sat_to_msat(autopilot.channel_capacity);
+        let count = autopilot.channels.min((self.remaining / capacity) as usize);
+        if count == 0 {
+            return;
+        }
+        for peer in self.choose_peers(count) {
+            let mut forward = topology::new_channel(capacity, autopilot.base_fee_msat, autopilot.fee_rate_ppm, autopilot.cltv_expiry_delta);
+            let mut backward = topology::new_channel(0, autopilot.base_fee_msat, autopilot.fee_rate_ppm, autopilot.cltv_expiry_delta);
+            let opened = self.latency.sample(&mut self.rng).and_then(|latency| {
+                forward.latency = latency;
+                backward.latency = self.latency.sample(&mut self.rng)?;
+                self.network.open_channel(self.node, peer, forward, backward)
+            });
+            match opened {
+                Ok(()) => {
+                    self.remaining -= capacity;
+                    self.report.opened += 1;
+                    self.report.capacity += capacity;
+                    info!("Autopilot {:?} of node {:?} opened a channel to {:?} with a capacity of {:?} msat", autopilot.strategy, self.node, peer, capacity);
+                }
+                Err(e) => {
+                    self.report.failed += 1;
+                    error!("Autopilot {:?} of node {:?} failed to open a channel to {:?}: {}", autopilot.strategy, self.node, peer, e);
+                }
+            }
+        }
+    }
+}
+
+// Function to run an autopilot until the payments stop or its budget can no longer fund a channel.
+async fn run_autopilot(network: Arc<Network>, autopilot: Autopilot, latency: LatencyModel, ledger: Arc<Mutex<Ledger>>, rng: StdRng, stopped: Arc<AtomicBool>) -> AutopilotReport {
+    let mut report = AutopilotReport::default();
+    let node = match autopilot.node {
+        Some(node) => NodeIndex::new(node),
+        None => {
+            let id = network.topology().node_weights().max().map_or(0, |id| id + 1);
+            match network.add_node(id) {
+                Ok(node) => {
+                    report.joined += 1;
+                    info!("Node {:?} joined the network as {:?} to run its autopilot", id, node);
+                    node
+                }
+                Err(e) => {
+                    error!("Autopilot of a new node cannot start: {}", e);
+                    return report;
+                }
+            }
+        }
+    };
+    let remaining = sat_to_msat(autopilot.budget);
+    let mut pilot = Pilot { network, autopilot, node, remaining, latency, ledger, rng, report };
+    let capacity = sat_to_msat(autopilot.channel_capacity);
+    let mut next = Instant::now();
+    while pilot.remaining >= capacity && payment_scheduler::wait_until(next, &stopped).await {
+        pilot.open_round();
+        let Some(interval) = autopilot.interval_secs else {
+            break;
+        };
+        next += Duration::from_secs_f64(interval);
+    }
+    pilot.report
+}
+
+// Function to run the autopilots next to the payments. Every autopilot draws its peers from its own
+// generator, seeded from the given one in the order of the autopilots. The channels open at once, and
+// reach the senders through the gossip when enabled. Returns the channels opened by all of them.
+pub async fn run_autopilots(network: Arc<Network>, autopilots: Vec<Autopilot>, latency: LatencyModel, ledger: Arc<Mutex<Ledger>>, mut rng: StdRng, stopped: Arc<AtomicBool>) -> AutopilotReport {
+    let handles: Vec<_> = autopilots.into_iter()
+        .map(|autopilot| {
+            let rng = StdRng::seed_from_u64(rng.gen());
+            tokio::spawn(run_autopilot(Arc::clone(&network), autopilot, latency.clone(), Arc::clone(&ledger), rng, Arc::clone(&stopped)))
+        })
+        .collect();
+    let mut report = AutopilotReport::default();
+    for handle in handles {
+        if let Ok(autopilot) = handle.await {
+            report.joined += autopilot.joined;
+            report.opened += autopilot.opened;
+            report.capacity += autopilot.capacity;
+            report.failed += autopilot.failed;
+        }
+    }
+    if report.opened + report.failed > 0 {
+        info!(
+            "Autopilots: {} nodes joined, {} channels opened with {} sat of capacity, {} failed",
+            report.joined, report.opened, msat_to_sat(report.capacity), report.failed
+        );
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let autopilot: Autopilot = toml::from_str("strategy = \"betweenness\"\nsamples = 10\nbudget = 4.0\nchannel_capacity = 2.0").unwrap();
+        assert_eq!((autopilot.strategy, autopilot.node), (AutopilotStrategy::Betweenness { samples: 10 }, None));
+        assert!(toml::from_str::<Autopilot>("strategy = \"random\"\nbudjet = 4.0\nbudget = 4.0\nchannel_capacity = 2.0").is_err());
+        assert!(toml::from_str::<Autopilot>("strategy = \"random\"\nsamples = 10\nbudget = 4.0\nchannel_capacity = 2.0").is_err());
+    }
+}
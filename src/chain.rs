@@ -0,0 +1,121 @@
+use petgraph::graph::NodeIndex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Smallest number of blocks the recipient accepts between the current height and the expiry of the
+// HTLC it receives, the default min_final_cltv_expiry_delta of BOLT 11.
+pub const MIN_FINAL_CLTV_EXPIRY_DELTA: u32 = 18;
+// Blocks the sender adds on top of the final delta, as LND does, so that a block found while the
+// payment is in flight does not make the recipient reject it.
+pub const BLOCK_PADDING: u32 = 3;
+
+// Settings of the simulated chain. Payments settle in well under a second, so blocks come much
+// faster than on mainnet by default to keep the expiry of held HTLCs within the length of a run.
+// Setting the interval to 600 seconds gives the timing of mainnet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChainConfig {
+    pub block_interval: Duration, // Time between two blocks
+    pub start_height: u32, // Block height at the start of the run
+    pub force_close_fee: u64, // On-chain fee in msat paid by the node that force-closes a channel
+    pub to_self_delay: u32, // Blocks for which the funds of the node that force-closes stay locked
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        ChainConfig { block_interval: Duration::from_secs(1), start_height: 0, force_close_fee: 2_000_000, to_self_delay: 144 }
+    }
+}
+
+// Output of a closed channel on chain, which its owner can spend once the chain reaches the given
+// height.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Output {
+    pub owner: NodeIndex,
+    pub amount: u64, // Amount in msat
+    pub spendable_at: u32, // Height from which the output can be spent
+}
+
+// Force close of a channel by the node that offered an HTLC over it once the HTLC expired.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ForceClose {
+    pub closer: NodeIndex, // Node that broadcast its commitment transaction
+    pub peer: NodeIndex, // Node at the other end of the channel
+    pub payment_id: u64, // Payment whose expired HTLC triggered the close
+    pub height: u32, // Height at which the channel was closed
+    pub fee: u64, // On-chain fee in msat paid by the closer
+}
+
+// Funds that left the channels for the chain, and the force closes that sent them there. Liquidity
+// operations spend the outputs of a node first and draw the rest from its wallet outside the
+// simulated network, which is counted as deposited, so that the funds in the channels and on chain
+// can be reconciled with those at the start.
+#[derive(Debug, Clone, Default)]
+pub struct OnChain {
+    pub outputs: Vec<Output>,
+    pub force_closes: Vec<ForceClose>,
+    pub deposits: u64, // Funds in msat brought in from outside the simulated network
+    pub operation_fees: u64, // On-chain fees in msat paid for the liquidity operations
+}
+
+impl OnChain {
+    // Funds in msat a node can spend at the given height.
+    pub fn spendable(&self, owner: NodeIndex, height: u32) -> u64 {
+        self.outputs.iter().filter(|output| output.owner == owner && output.spendable_at <= height).map(|output| output.amount).sum()
+    }
+
+    // Funds in msat of every node still locked at the given height.
+    pub fn locked(&self, height: u32) -> u64 {
+        self.outputs.iter().filter(|output| output.spendable_at > height).map(|output| output.amount).sum()
+    }
+
+    // On-chain fees in msat paid for the force closes.
+    pub fn fees(&self) -> u64 {
+        self.force_closes.iter().map(|close| close.fee).sum()
+    }
+
+    // Function to spend funds of a node on chain at the given height, from its spendable outputs
+    // first and from its wallet outside the simulated network for the rest.
+    pub fn spend(&mut self, owner: NodeIndex, amount: u64, height: u32) {
+        let mut left = amount;
+        for output in self.outputs.iter_mut().filter(|output| output.owner == owner && output.spendable_at <= height) {
+            let spent = output.amount.min(left);
+            output.amount -= spent;
+            left -= spent;
+        }
+        self.outputs.retain(|output| output.amount > 0);
+        self.deposits += left;
+    }
+}
+
+// Clock of the simulated chain. The height advances by one block every block interval of virtual
+// time from the moment the chain is created.
+#[derive(Debug, Clone, Copy)]
+pub struct Chain {
+    config: ChainConfig,
+    start: Instant,
+}
+
+impl Chain {
+    // Function to start the chain at its start height.
+    pub fn new(config: ChainConfig) -> Self {
+        Chain { config, start: Instant::now() }
+    }
+
+    // Settings of the chain.
+    pub fn config(&self) -> ChainConfig {
+        self.config
+    }
+
+    // Current block height.
+    pub fn height(&self) -> u32 {
+        let blocks = self.start.elapsed().as_nanos() / self.config.block_interval.as_nanos().max(1);
+        self.config.start_height.saturating_add(blocks.min(u32::MAX as u128) as u32)
+    }
+
+    // Function to compute the time left until the chain reaches a height, zero if it already has.
+    pub fn time_until(&self, height: u32) -> Duration {
+        let blocks = height.saturating_sub(self.config.start_height);
+        let reached_at = self.start + self.config.block_interval * blocks;
+        reached_at.saturating_duration_since(Instant::now())
+    }
+}
@@ -1,48 +1,356 @@
 use petgraph::graph::{NodeIndex, DiGraph};
-use serde_json::Value;
+use petgraph::visit::EdgeRef;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::fs;
 use std::path::Path;
+use std::time::Duration;
+use crate::reputation::ChannelReputation;
+
+// Millisatoshis in a satoshi. Amounts, balances and fees are kept in whole millisatoshis inside the
+// simulator, as nodes account for them, and are converted from the satoshis of the snapshots,
+// workloads and traces where those are read.
+pub const MSAT_PER_SAT: u64 = 1000;
+// Proportional fees are kept in millionths of the amount, as in the fee_proportional_millionths of
+// BOLT 7, and are converted from the fractions of the snapshots.
+pub const PPM: u64 = 1_000_000;
+
+// Default number of concurrent HTLCs a channel accepts in one direction. 483 is the limit imposed
+// by BOLT 2 so that the commitment transaction stays within the standard transaction size.
+pub const MAX_ACCEPTED_HTLCS: u16 = 483;
+
+// Graph of the network, with the id of every node in the snapshot and the properties of every
+// channel direction.
+pub type Graph = DiGraph<usize, EdgeAttributes>;
 
 // Structure to hold the properties of every channel in the graph.
 #[derive(Debug, Clone, PartialOrd, PartialEq, Copy)]
 pub struct EdgeAttributes {
-    pub basefee: f32,
-    pub feerate: f32,
-    pub balance: f32,
-    pub delay: f32,
+    pub basefee: u64, // Base fee in msat
+    pub feerate: u64, // Proportional fee in millionths of the amount forwarded
+    pub upfrontfee: u64, // Fee in msat paid when the HTLC is locked, whether or not the payment succeeds
+    pub holdfee: u64, // Fee in millionths of the HTLC amount per second for which the HTLC stays locked
+    pub balance: u64, // Balance in msat
+    pub delay: u32, // cltv_expiry_delta in blocks the node requires to forward over the channel
     pub age: i64,
+    pub max_accepted_htlcs: u16, // Maximum number of HTLCs that can be in flight at once
+    pub max_htlc_value_in_flight: u64, // Maximum total value in msat of the HTLCs in flight
+    pub pending_htlcs: u16, // Number of HTLCs currently in flight
+    pub value_in_flight: u64, // Total value in msat of the HTLCs currently in flight
+    pub rejected_htlcs: u32, // Number of HTLCs rejected for lack of balance or HTLC limits
+    pub reputation: ChannelReputation, // Reputation and resource buckets for jamming mitigation
+    pub latency: Duration, // Time an HTLC takes to cross the channel
+    pub closed: bool, // Whether the channel was closed on chain
+    pub closing: bool, // Whether the channel is being closed and takes no new HTLC
+}
+
+impl EdgeAttributes {
+    // Whether the channel takes new HTLCs, that is, it is neither closed nor being closed.
+    pub fn is_open(&self) -> bool {
+        !self.closed && !self.closing
+    }
+
+    // Function to compute the fee in msat for forwarding the given amount in msat over the channel,
+    // as in BOLT 7. The proportional part is rounded down, as nodes compute it in integers.
+    pub fn fee(&self, amount: u64) -> u64 {
+        self.basefee + (amount as u128 * self.feerate as u128 / PPM as u128) as u64
+    }
+
+    // Function to compute the hold fee in msat for an HTLC of the given amount in msat that stayed
+    // locked in the channel for the given time, rounded down.
+    pub fn hold_fee(&self, amount: u64, hold_time: Duration) -> u64 {
+        (amount as u128 * self.holdfee as u128 * hold_time.as_micros() / (PPM as u128 * 1_000_000)) as u64
+    }
+}
+
+// Function to convert an amount in satoshis, as found in snapshots, workloads and traces, into whole
+// millisatoshis. Negative amounts become zero.
+pub fn sat_to_msat(sat: f64) -> u64 {
+    (sat * MSAT_PER_SAT as f64).round() as u64
+}
+
+// Function to convert an amount in millisatoshis into satoshis for reports and snapshots.
+pub fn msat_to_sat(msat: u64) -> f64 {
+    msat as f64 / MSAT_PER_SAT as f64
+}
+
+// Function to convert a fee rate given as a fraction of the amount into millionths of the amount.
+pub fn fraction_to_ppm(fraction: f64) -> u64 {
+    (fraction * PPM as f64).round() as u64
+}
+
+// How the capacity of every channel is split between its two directions at the start of the
+// simulation.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    // Keep the balances of the snapshot.
+    #[default]
+    Snapshot,
+    // Split the capacity evenly between both directions.
+    Even,
+    // Split the capacity at a uniformly drawn point.
+    Random,
+}
+
+// A channel of a snapshot in the CSV format, with one row per direction of every channel. The source
+// and target are indices of the nodes in the graph, as in the links of the networkx format.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+struct ChannelRecord {
+    source: usize,
+    target: usize,
+    basefee: f64,
+    feerate: f64,
+    #[serde(default)]
+    upfrontfee: f64,
+    #[serde(default)]
+    holdfee: f64,
+    balance: f64,
+    delay: i64,
+    age: i64,
+    max_accepted_htlcs: Option<u16>,
+    max_htlc_value_in_flight: Option<f64>, // Left empty when the value in flight is not limited
 }
 
-// Function to convert an existing snapshot (originally in networkx format) in the networkx format to a petgraph format
-pub fn convert_networkx_to_petgraph<P: AsRef<Path>>(file_path: P) -> DiGraph<usize, EdgeAttributes> {
+// Function to convert an existing snapshot (originally in networkx format) in the networkx format to a petgraph format.
+// Errors name the node or link of the snapshot that is missing a field or has one of the wrong type.
+pub fn convert_networkx_to_petgraph<P: AsRef<Path>>(file_path: P) -> Result<DiGraph<usize, EdgeAttributes>, String> {
     // Read and parse the JSON file
-    let file_content = fs::read_to_string(file_path).expect("Error reading file");
-    let json: Value = serde_json::from_str(&file_content).expect("Error parsing JSON");
+    let file_content = fs::read_to_string(file_path).map_err(|e| format!("Error reading file: {}", e))?;
+    let json: Value = serde_json::from_str(&file_content).map_err(|e| format!("Error parsing JSON: {}", e))?;
 
     // Create a new Petgraph graph (Directed Graph)
     let mut graph = DiGraph::new();
 
     // Transfer nodes
     if let Some(nodes) = json.get("nodes") {
-        for node in nodes.as_array().unwrap() {
-            graph.add_node(node["id"].as_i64().unwrap() as usize);
+        for (i, node) in nodes.as_array().ok_or("nodes is not a list")?.iter().enumerate() {
+            let id = node["id"].as_u64().ok_or(format!("node {} has no valid id", i))?;
+            graph.add_node(id as usize);
         }
     }
 
-    // Transfer edges and edge properties
+    // Transfer edges and edge properties. The upfront and hold fees are optional in the snapshot
+    // and default to zero. The HTLC limits are optional as well and fall back to the protocol
+    // maximum for the number of HTLCs and no limit for the value in flight. The amounts of the
+    // snapshot are in satoshis and the fee rates are fractions of the amount.
     if let Some(edges) = json.get("links") {
-        for edge in edges.as_array().unwrap() {
-            let start = NodeIndex::new(edge["source"].as_u64().unwrap() as usize);
-            let end = NodeIndex::new(edge["target"].as_u64().unwrap() as usize);
+        for (i, edge) in edges.as_array().ok_or("links is not a list")?.iter().enumerate() {
+            let number = |field: &str| edge[field].as_f64().ok_or(format!("link {} has no valid {}", i, field));
+            let integer = |field: &str| edge[field].as_i64().ok_or(format!("link {} has no valid {}", i, field));
+            let node = |field: &str| {
+                edge[field].as_u64().map(|index| NodeIndex::new(index as usize))
+                    .filter(|index| index.index() < graph.node_count())
+                    .ok_or(format!("link {} has no valid {}", i, field))
+            };
+            let (start, end) = (node("source")?, node("target")?);
             let attrs = EdgeAttributes {
-                basefee: edge["basefee"].as_f64().unwrap() as f32,
-                feerate: edge["feerate"].as_f64().unwrap() as f32,
-                delay: edge["delay"].as_i64().unwrap() as f32,
-                balance: edge["balance"].as_f64().unwrap() as f32,
-                age: edge["age"].as_i64().unwrap() as i64,
+                basefee: sat_to_msat(number("basefee")?),
+                feerate: fraction_to_ppm(number("feerate")?),
+                upfrontfee: sat_to_msat(edge["upfrontfee"].as_f64().unwrap_or(0.0)),
+                holdfee: fraction_to_ppm(edge["holdfee"].as_f64().unwrap_or(0.0)),
+                delay: u32::try_from(integer("delay")?).map_err(|_| format!("link {} has no valid delay", i))?,
+                balance: sat_to_msat(number("balance")?),
+                age: integer("age")?,
+                max_accepted_htlcs: match &edge["max_accepted_htlcs"] {
+                    Value::Null => MAX_ACCEPTED_HTLCS,
+                    value => value.as_u64().and_then(|v| u16::try_from(v).ok()).ok_or(format!("link {} has no valid max_accepted_htlcs", i))?,
+                },
+                max_htlc_value_in_flight: edge["max_htlc_value_in_flight"].as_f64().map_or(u64::MAX, sat_to_msat),
+                pending_htlcs: 0,
+                value_in_flight: 0,
+                rejected_htlcs: 0,
+                reputation: ChannelReputation::default(),
+                latency: Duration::ZERO,
+                closed: false,
+                closing: false,
             };
             graph.add_edge(start, end, attrs);
         }
     }
-    graph
+    Ok(graph)
+}
+
+// Function to apply the same HTLC limits to every channel in the graph, overriding the limits read
+// from the snapshot. A limit that is not given is left as in the snapshot. The value in flight is in
+// msat.
+pub fn set_htlc_limits(graph: &mut DiGraph<usize, EdgeAttributes>, max_accepted_htlcs: Option<u16>, max_htlc_value_in_flight: Option<u64>) {
+    for attrs in graph.edge_weights_mut() {
+        if let Some(max_accepted_htlcs) = max_accepted_htlcs {
+            attrs.max_accepted_htlcs = max_accepted_htlcs;
+        }
+        if let Some(max_htlc_value_in_flight) = max_htlc_value_in_flight {
+            attrs.max_htlc_value_in_flight = max_htlc_value_in_flight;
+        }
+    }
+}
+
+// Function to split the capacity of every channel between its two directions. The capacity is the
+// sum of the balances of both directions in the snapshot, and channels known in a single direction
+// are left as they are.
+pub fn init_balances<R: Rng + ?Sized>(graph: &mut DiGraph<usize, EdgeAttributes>, strategy: BalanceStrategy, rng: &mut R) {
+    if strategy == BalanceStrategy::Snapshot {
+        return;
+    }
+    for edge in graph.edge_indices() {
+        let (start, end) = graph.edge_endpoints(edge).unwrap();
+        // Handle every channel once, from the direction starting at the smaller index.
+        if start >= end {
+            continue;
+        }
+        let Some(reverse) = graph.find_edge(end, start) else {
+            continue;
+        };
+        let capacity = graph[edge].balance + graph[reverse].balance;
+        graph[edge].balance = match strategy {
+            BalanceStrategy::Even => capacity / 2,
+            _ => (capacity as f64 * rng.gen::<f64>()) as u64,
+        };
+        graph[reverse].balance = capacity - graph[edge].balance;
+    }
+}
+
+// Function to read a snapshot in either format. Files ending in .csv are read as channel lists, and
+// every other file as a networkx snapshot.
+pub fn load_graph<P: AsRef<Path>>(file_path: P) -> Result<DiGraph<usize, EdgeAttributes>, String> {
+    let file_path = file_path.as_ref();
+    if !file_path.is_file() {
+        return Err(format!("Graph snapshot {} not found", file_path.display()));
+    }
+    if file_path.extension().is_some_and(|extension| extension == "csv") {
+        load_channels_csv(file_path)
+    } else {
+        convert_networkx_to_petgraph(file_path)
+    }
+}
+
+// Function to read a snapshot from a CSV list of channels. The nodes are numbered from zero up to
+// the largest index in the list, and every node id is its index. As in the networkx format, the
+// amounts are in satoshis and the fee rates are fractions of the amount.
+pub fn load_channels_csv<P: AsRef<Path>>(file_path: P) -> Result<DiGraph<usize, EdgeAttributes>, String> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_path(file_path).map_err(|e| e.to_string())?;
+    let records: Vec<ChannelRecord> = reader.deserialize().collect::<Result<_, _>>().map_err(|e| e.to_string())?;
+    let node_count = records.iter().map(|record| record.source.max(record.target) + 1).max().unwrap_or(0);
+    let mut graph = DiGraph::new();
+    for id in 0..node_count {
+        graph.add_node(id);
+    }
+    for (i, record) in records.into_iter().enumerate() {
+        let attrs = EdgeAttributes {
+            basefee: sat_to_msat(record.basefee),
+            feerate: fraction_to_ppm(record.feerate),
+            upfrontfee: sat_to_msat(record.upfrontfee),
+            holdfee: fraction_to_ppm(record.holdfee),
+            balance: sat_to_msat(record.balance),
+            delay: u32::try_from(record.delay).map_err(|_| format!("channel {} has no valid delay", i))?,
+            age: record.age,
+            max_accepted_htlcs: record.max_accepted_htlcs.unwrap_or(MAX_ACCEPTED_HTLCS),
+            max_htlc_value_in_flight: record.max_htlc_value_in_flight.map_or(u64::MAX, sat_to_msat),
+            pending_htlcs: 0,
+            value_in_flight: 0,
+            rejected_htlcs: 0,
+            reputation: ChannelReputation::default(),
+            latency: Duration::ZERO,
+            closed: false,
+            closing: false,
+        };
+        graph.add_edge(NodeIndex::new(record.source), NodeIndex::new(record.target), attrs);
+    }
+    Ok(graph)
+}
+
+// Function to write a graph as a snapshot, in the CSV format if the file ends in .csv and in the
+// networkx format otherwise, with the amounts converted back to satoshis. Only the channel policies
+// and balances are written, not the HTLCs in flight.
+pub fn write_graph<P: AsRef<Path>>(graph: &DiGraph<usize, EdgeAttributes>, file_path: P) -> Result<(), String> {
+    let file_path = file_path.as_ref();
+    let records = graph.edge_references().map(|edge| {
+        let attrs = edge.weight();
+        ChannelRecord {
+            source: edge.source().index(),
+            target: edge.target().index(),
+            basefee: msat_to_sat(attrs.basefee),
+            feerate: attrs.feerate as f64 / PPM as f64,
+            upfrontfee: msat_to_sat(attrs.upfrontfee),
+            holdfee: attrs.holdfee as f64 / PPM as f64,
+            balance: msat_to_sat(attrs.balance),
+            delay: attrs.delay as i64,
+            age: attrs.age,
+            max_accepted_htlcs: Some(attrs.max_accepted_htlcs),
+            max_htlc_value_in_flight: Some(attrs.max_htlc_value_in_flight).filter(|&value| value != u64::MAX).map(msat_to_sat),
+        }
+    });
+    if file_path.extension().is_some_and(|extension| extension == "csv") {
+        let mut writer = csv::Writer::from_path(file_path).map_err(|e| e.to_string())?;
+        for record in records {
+            writer.serialize(record).map_err(|e| e.to_string())?;
+        }
+        writer.flush().map_err(|e| e.to_string())
+    } else {
+        let nodes: Vec<Value> = graph.node_weights().map(|id| json!({ "id": id })).collect();
+        let links = serde_json::to_value(records.collect::<Vec<_>>()).map_err(|e| e.to_string())?;
+        let snapshot = json!({ "directed": true, "multigraph": false, "graph": {}, "nodes": nodes, "links": links });
+        fs::write(file_path, snapshot.to_string()).map_err(|e| e.to_string())
+    }
+}
+
+// Function to find the index of the node with the given id in the snapshot.
+pub fn node_index(graph: &DiGraph<usize, EdgeAttributes>, id: usize) -> Option<NodeIndex> {
+    graph.node_indices().find(|&index| graph[index] == id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topology::new_channel;
+
+    #[test]
+    fn fee_adds_base_fee_and_rounds_proportional_part_down() {
+        let attrs = new_channel(0, 1000, 1, 80);
+        assert_eq!(attrs.fee(0), 1000);
+        // 999_999 msat at 1 ppm is 0.999999 msat, which nodes round down.
+        assert_eq!(attrs.fee(999_999), 1000);
+        assert_eq!(attrs.fee(1_000_000), 1001);
+        assert_eq!(attrs.fee(2_999_999), 1002);
+    }
+
+    #[test]
+    fn fee_does_not_overflow_on_large_amounts() {
+        let attrs = new_channel(0, 0, 5000, 80);
+        // 21 million bitcoin in msat at 5000 ppm.
+        assert_eq!(attrs.fee(2_100_000_000_000_000_000), 10_500_000_000_000_000);
+    }
+
+    #[test]
+    fn malformed_snapshot_is_an_error() {
+        let link = r#"{"source": 0, "target": 1, "basefee": 1.0, "feerate": 0.000001, "delay": 40, "balance": 1000.0, "age": 0}"#;
+        let snapshots = [
+            ("valid", format!(r#"{{"nodes": [{{"id": 0}}, {{"id": 1}}], "links": [{}]}}"#, link)),
+            ("no_id", format!(r#"{{"nodes": [{{"id": 0}}, {{}}], "links": [{}]}}"#, link)),
+            ("no_balance", format!(r#"{{"nodes": [{{"id": 0}}, {{"id": 1}}], "links": [{}]}}"#, link.replace(r#", "balance": 1000.0"#, ""))),
+            ("unknown_target", format!(r#"{{"nodes": [{{"id": 0}}], "links": [{}]}}"#, link)),
+            ("too_many_htlcs", format!(r#"{{"nodes": [{{"id": 0}}, {{"id": 1}}], "links": [{}]}}"#, link.replace(r#""age": 0"#, r#""age": 0, "max_accepted_htlcs": 70000"#))),
+            ("not_json", "{".to_string()),
+        ];
+        for (name, snapshot) in snapshots {
+            let path = std::env::temp_dir().join(format!("lnsimulator_snapshot_{}.json", name));
+            fs::write(&path, snapshot).unwrap();
+            let graph = load_graph(&path);
+            fs::remove_file(&path).unwrap();
+            assert_eq!(graph.is_ok(), name == "valid", "{}: {:?}", name, graph.map(|graph| graph.edge_count()));
+        }
+    }
+
+    #[test]
+    fn negative_delay_in_csv_is_an_error() {
+        let header = "source,target,basefee,feerate,balance,delay,age,max_accepted_htlcs,max_htlc_value_in_flight";
+        for (name, delay) in [("valid", 40), ("negative_delay", -1)] {
+            let path = std::env::temp_dir().join(format!("lnsimulator_channels_{}.csv", name));
+            fs::write(&path, format!("{}\n0,1,1.0,0.000001,1000.0,{},0,,\n", header, delay)).unwrap();
+            let graph = load_graph(&path);
+            fs::remove_file(&path).unwrap();
+            assert_eq!(graph.is_ok(), name == "valid", "{}: {:?}", name, graph.map(|graph| graph.edge_count()));
+        }
+    }
 }
@@ -0,0 +1,260 @@
+use log::info;
+use petgraph::graph::{EdgeIndex, NodeIndex};
+use petgraph::visit::EdgeRef;
+use serde::{de, Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::ledger::Ledger;
+use crate::network::Network;
+use crate::payment_scheduler;
+use crate::scenario;
+
+// How a fee agent sets the forwarding policy of the channels of its nodes.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(tag = "strategy", rename_all = "snake_case", deny_unknown_fields)]
+pub enum FeeStrategy {
+    // Fee rate proportional to the share of the capacity on the side of the peer, as the
+    // proportional strategy of charge-lnd: min_ppm when the node holds the whole capacity and
+    // max_ppm when it holds none of it, so that depleted channels become expensive. The base fee is
+    // kept unless given.
+    Proportional { min_ppm: u64, max_ppm: u64, base_fee_msat: Option<u64> },
+    // Revenue-maximising hill climbing: every interval the fee rate moves by the step, a fraction of
+    // the rate, in the direction that raised the forwarding fees the channel earned over the last
+    // interval, and turns around when they fell. Upfront and hold fees are left out, as the policy of
+    // the incoming channel sets them.
+    HillClimbing {
+        #[serde(default = "default_step")]
+        step: f64,
+        #[serde(default = "default_min_ppm")]
+        min_ppm: u64,
+        #[serde(default = "default_max_ppm")]
+        max_ppm: u64,
+    },
+    // Zero base fee, with the fee rate kept unless given.
+    ZeroBase { fee_rate_ppm: Option<u64> },
+}
+
+fn default_step() -> f64 {
+    0.1
+}
+
+fn default_min_ppm() -> u64 {
+    1
+}
+
+fn default_max_ppm() -> u64 {
+    5000
+}
+
+// Agent setting the fees of the channels of a group of nodes, every interval of virtual time from the
+// start of the run. Without nodes, the agent sets the fees of every node, including the ones that
+// join during the run.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(remote = "Self")]
+pub struct FeeAgent {
+    #[serde(flatten)]
+    pub strategy: FeeStrategy,
+    #[serde(default)]
+    pub nodes: Option<Vec<usize>>, // Ids of the nodes in the snapshot, their indices in the graph once resolved
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: f64,
+}
+
+fn default_interval_secs() -> f64 {
+    600.0
+}
+
+// Fields of a fee agent next to those of its strategy. Serde does not reject unknown fields next to a
+// flattened strategy, so the agent is read as a table and its fields are checked first.
+const FIELDS: &[&str] = &["strategy", "nodes", "interval_secs"];
+
+impl FeeStrategy {
+    // Fields of the strategy with the given name.
+    fn fields(strategy: &str) -> &'static [&'static str] {
+        match strategy {
+            "proportional" => &["min_ppm", "max_ppm", "base_fee_msat"],
+            "hill_climbing" => &["step", "min_ppm", "max_ppm"],
+            "zero_base" => &["fee_rate_ppm"],
+            _ => &[],
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FeeAgent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let table = toml::Table::deserialize(deserializer)?;
+        let strategy = table.get("strategy").and_then(toml::Value::as_str).unwrap_or_default();
+        scenario::deny_unknown_fields(&table, FIELDS, FeeStrategy::fields(strategy))?;
+        // The inherent function derived by serde reads the fields themselves.
+        FeeAgent::deserialize(toml::Value::Table(table)).map_err(de::Error::custom)
+    }
+}
+
+impl FeeAgent {
+    // Function to check that the parameters of the agent make sense.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.interval_secs.is_finite() || self.interval_secs <= 0.0 {
+            return Err("interval_secs must be positive".to_string());
+        }
+        match self.strategy {
+            FeeStrategy::Proportional { min_ppm, max_ppm, .. } | FeeStrategy::HillClimbing { min_ppm, max_ppm, .. } if min_ppm > max_ppm => {
+                Err("min_ppm must not exceed max_ppm".to_string())
+            }
+            FeeStrategy::HillClimbing { step, .. } if !(step > 0.0 && step < 1.0) => Err("step must be between 0 and 1".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+// State of a channel under hill climbing: its revenue when the rate last moved, the revenue over the
+// interval before and the direction the rate moves in.
+#[derive(Debug, Clone, Copy)]
+struct Climb {
+    revenue: u64,
+    last_gain: u64,
+    up: bool,
+}
+
+// Function to compute the new fee rate of a channel under hill climbing, from its current rate and
+// the total forwarding fees it earned so far. The first call only records the revenue.
+fn climb(state: &mut BTreeMap<EdgeIndex, Climb>, edge: EdgeIndex, feerate: u64, revenue: u64, step: f64, min_ppm: u64, max_ppm: u64) -> u64 {
+    let Some(climb) = state.get_mut(&edge) else {
+        state.insert(edge, Climb { revenue, last_gain: 0, up: true });
+        return feerate;
+    };
+    let gain = revenue - climb.revenue;
+    if gain < climb.last_gain {
+        climb.up = !climb.up;
+    }
+    climb.revenue = revenue;
+    climb.last_gain = gain;
+    // The rate moves by at least one ppm, so that low rates do not get stuck.
+    let change = ((feerate as f64 * step).round() as u64).max(1);
+    let feerate = if climb.up { feerate + change } else { feerate.saturating_sub(change) };
+    feerate.clamp(min_ppm, max_ppm)
+}
+
+// Function to set the fees of the open channels of the nodes of an agent once.
+fn update(network: &Network, agent: &FeeAgent, ledger: &Mutex<Ledger>, state: &mut BTreeMap<EdgeIndex, Climb>) -> usize {
+    let (nodes, channels) = {
+        let topology = network.topology();
+        let nodes: Vec<NodeIndex> = match &agent.nodes {
+            Some(nodes) => nodes.iter().map(|&node| NodeIndex::new(node)).collect(),
+            None => topology.node_indices().collect(),
+        };
+        let channels: Vec<(EdgeIndex, NodeIndex, NodeIndex)> = nodes.iter()
+            .flat_map(|&node| topology.edges(node).map(|edge| (edge.id(), edge.source(), edge.target())))
+            .collect();
+        (nodes.len(), channels)
+    };
+    let mut updates = 0;
+    for (edge, from, to) in channels {
+        let attrs = network.attributes(edge);
+        if !attrs.is_open() {
+            continue;
+        }
+        let (basefee, feerate) = match agent.strategy {
+            FeeStrategy::Proportional { min_ppm, max_ppm, base_fee_msat } => {
+                let local = attrs.balance + attrs.value_in_flight;
+                let remote = network.find_edge(to, from).map_or(0, |reverse| {
+                    let reverse = network.attributes(reverse);
+                    reverse.balance + reverse.value_in_flight
+                });
+                let capacity = (local + remote).max(1);
+                let feerate = max_ppm - ((max_ppm - min_ppm) as u128 * local as u128 / capacity as u128) as u64;
+                (base_fee_msat.unwrap_or(attrs.basefee), feerate)
+            }
+            FeeStrategy::HillClimbing { step, min_ppm, max_ppm } => {
+                let revenue = ledger.lock().map_or(0, |ledger| ledger.channel(from, to).forwarding_fees);
+                (attrs.basefee, climb(state, edge, attrs.feerate, revenue, step, min_ppm, max_ppm))
+            }
+            FeeStrategy::ZeroBase { fee_rate_ppm } => (0, fee_rate_ppm.unwrap_or(attrs.feerate)),
+        };
+        if network.set_policy(edge, basefee, feerate) {
+            updates += 1;
+        }
+    }
+    info!("Fee agent {:?} updated {} channels of {} nodes", agent.strategy, updates, nodes);
+    updates
+}
+
+// Function to run a fee agent until the payments stop, setting the fees at the start and then every
+// interval. Returns the number of channel updates it sent.
+async fn run_agent(network: Arc<Network>, agent: FeeAgent, ledger: Arc<Mutex<Ledger>>, stopped: Arc<AtomicBool>) -> usize {
+    let interval = Duration::from_secs_f64(agent.interval_secs);
+    let mut state = BTreeMap::new();
+    let mut updates = 0;
+    let mut next = Instant::now();
+    loop {
+        if !payment_scheduler::wait_until(next, &stopped).await {
+            return updates;
+        }
+        updates += update(&network, &agent, &ledger, &mut state);
+        next += interval;
+    }
+}
+
+// Function to run the fee agents next to the payments. The channel updates they send change the
+// fees the forwarding nodes charge at once, and reach the senders through the gossip when enabled.
+pub async fn run_fee_agents(network: Arc<Network>, agents: Vec<FeeAgent>, ledger: Arc<Mutex<Ledger>>, stopped: Arc<AtomicBool>) {
+    let handles: Vec<_> = agents.into_iter()
+        .map(|agent| tokio::spawn(run_agent(Arc::clone(&network), agent, Arc::clone(&ledger), Arc::clone(&stopped))))
+        .collect();
+    let mut updates = 0;
+    for handle in handles {
+        updates += handle.await.unwrap_or(0);
+    }
+    if updates > 0 {
+        info!("Fee agents sent {} channel updates", updates);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_graph::Graph;
+    use crate::find_path;
+    use crate::payment_router::Payment;
+    use crate::topology::new_channel;
+
+    // Records a settled payment of the amount along the line and returns the forwarding fees every
+    // channel of the line earned from it.
+    fn forward(network: &Network, nodes: &[NodeIndex], amount: u64) -> Vec<u64> {
+        let (path, timelocks, amounts) = find_path::route_through(network, nodes.to_vec(), amount);
+        let mut ledger = Ledger::default();
+        ledger.record(&Payment::new(1, path, timelocks, amounts), true);
+        nodes.windows(2).map(|hop| ledger.channel(hop[0], hop[1]).forwarding_fees).collect()
+    }
+
+    #[test]
+    fn raising_the_fee_of_a_channel_raises_the_revenue_of_that_channel() {
+        let mut graph = Graph::new();
+        let nodes: Vec<NodeIndex> = (0..4).map(|id| graph.add_node(id)).collect();
+        for hop in nodes.windows(2) {
+            graph.add_edge(hop[0], hop[1], new_channel(10_000_000_000, 1000, 100, 40));
+        }
+        let network = Network::new(graph);
+        let before = forward(&network, &nodes, 1_000_000);
+
+        let agent = FeeAgent { strategy: FeeStrategy::ZeroBase { fee_rate_ppm: Some(5000) }, nodes: Some(vec![1]), interval_secs: 600.0 };
+        assert_eq!(update(&network, &agent, &Mutex::new(Ledger::default()), &mut BTreeMap::new()), 1);
+        let after = forward(&network, &nodes, 1_000_000);
+
+        // Only the channel node 1 forwards over changes its revenue, to the fee of its new policy.
+        assert_eq!(after[0], before[0]);
+        assert_eq!(after[1], 1_001_100 * 5000 / 1_000_000);
+        assert_ne!(after[1], before[1]);
+        assert_eq!(after[2], before[2]);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let agent: FeeAgent = toml::from_str("strategy = \"hill_climbing\"\nstep = 0.2\nnodes = [1]").unwrap();
+        assert_eq!(agent.strategy, FeeStrategy::HillClimbing { step: 0.2, min_ppm: 1, max_ppm: 5000 });
+        assert!(toml::from_str::<FeeAgent>("strategy = \"zero_base\"\nfee_rate_ppm = 1\nnode = [1]").is_err());
+        assert!(toml::from_str::<FeeAgent>("strategy = \"zero_base\"\nstep = 0.2").is_err());
+    }
+}
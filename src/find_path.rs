@@ -1,16 +1,36 @@
-use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::graph::NodeIndex;
 use petgraph::Direction;
 use std::collections::{BinaryHeap, HashMap};
 use std::cmp::Ordering;
-use crate::create_graph::EdgeAttributes;
+use serde::Deserialize;
+use crate::chain::{BLOCK_PADDING, MIN_FINAL_CLTV_EXPIRY_DELTA};
+use crate::create_graph::{EdgeAttributes, PPM};
+use crate::network::Network;
 use ordered_float::OrderedFloat;
-use std::sync::{Arc, Mutex};
-use log::info;
 
 // Risk factor for locking funds. This basically quantifies the cost for locking unit value for unit
 // time. The cost function uses this value to compute channel cost for addition to the optimal path.
 // This value can be changed as per the need of the simulator.
 const RF: f32 = 0.0000000015;
+// Time in seconds for which an HTLC is expected to stay locked. The cost function uses this value to
+// price the hold fees of a channel.
+const EXPECTED_HOLD_TIME: f32 = 1.0;
+
+// Parameters of the cost function a sender uses to rank paths. Different classes of nodes can weigh
+// the time their funds are locked differently.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CostModel {
+    pub risk_factor: f32, // Cost of locking unit value for unit time
+    pub expected_hold_time: f32, // Time in seconds for which an HTLC is expected to stay locked
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        CostModel { risk_factor: RF, expected_hold_time: EXPECTED_HOLD_TIME }
+    }
+}
+
 // A struct to represent items in the priority queue. The priority queue  is used to construct the
 // best path.
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -33,37 +53,63 @@ impl PartialOrd for State {
     }
 }
 
-// Cost function as per LND, the most popular lightning routing client.
-pub fn lnd_cost_fn(attrs:EdgeAttributes,amt:f32) -> f32 {
-    let wght = amt*attrs.delay*RF + attrs.basefee + amt*attrs.feerate;
-    wght
+// Cost function as per LND, the most popular lightning routing client, extended with the upfront
+// fee and the expected hold fee of the channel. The amount and the cost are in msat.
+pub fn lnd_cost_fn(attrs:EdgeAttributes,amt:u64,model:CostModel) -> f32 {
+    let hold_fee = amt as f32 * attrs.holdfee as f32 / PPM as f32 * model.expected_hold_time;
+    amt as f32*attrs.delay as f32*model.risk_factor + attrs.fee(amt) as f32 + attrs.upfrontfee as f32 + hold_fee
 }
 
-// The cost for the first channel is a little different as the sender does not collect fees.
-pub fn lnd_cost_fn_snd(attrs:EdgeAttributes,amt:f32) -> f32 {
-    let wght = amt*attrs.delay*RF;
-    wght
+// The cost for the first channel is a little different as the sender does not collect fees. It
+// still pays the upfront fee and the hold fee of its own channel.
+pub fn lnd_cost_fn_snd(attrs:EdgeAttributes,amt:u64,model:CostModel) -> f32 {
+    let hold_fee = amt as f32 * attrs.holdfee as f32 / PPM as f32 * model.expected_hold_time;
+    amt as f32*attrs.delay as f32*model.risk_factor + attrs.upfrontfee as f32 + hold_fee
+}
+
+// Function to compute the CLTV expiry of the HTLC the recipient receives, the final delta the
+// recipient requires plus the padding of the sender on top of the current height.
+fn final_expiry(network: &Network) -> u32 {
+    network.chain().height() + MIN_FINAL_CLTV_EXPIRY_DELTA + BLOCK_PADDING
 }
 
 // Function to compute the best path from the sender to the recipient for a certain transaction
-// amount. The path computation is based on Dijkstra's algorithm but with the LND cost function.
-pub fn dijkstra(graph: Arc<Mutex<DiGraph<usize, EdgeAttributes>>>, r: NodeIndex, s:NodeIndex, amt:f32) -> (Vec<NodeIndex>,Vec<f32>,Vec<f32>){
-
-    // Lock the shared graph for computing the path.
-    let guard = match graph.lock() {
-        Ok(g) => g,
-        Err(e) => {
-            // Handle the error, e.g., by logging, returning an error, or retrying
-            info!("Failed to acquire lock: {}", e);
-            return (Vec::new(), Vec::new(),Vec::new());
-        }
-    };
+// amount in msat. The path computation is based on Dijkstra's algorithm but with the LND cost
+// function. The amounts returned are those of the HTLC every node offers to the next one, in msat,
+// as in BOLT 7: every forwarding node charges the fee of its outgoing channel on top of the amount
+// it forwards, while the sender pays no fee for its own channel and the HTLC reaching the recipient
+// carries exactly the amount. The last entry is the amount the recipient receives. The timelocks are the absolute CLTV expiries in blocks of the HTLC every node
+// offers to the next one, as in BOLT 4: the HTLC reaching the recipient expires after the final
+// delta, and every forwarding node adds the cltv_expiry_delta of its outgoing channel. The last
+// entry is the expiry the recipient receives.
+pub fn dijkstra(network: &Network, r: NodeIndex, s:NodeIndex, amt:u64) -> (Vec<NodeIndex>,Vec<u32>,Vec<u64>){
+    dijkstra_with_cost(network, r, s, amt, CostModel::default())
+}
+
+// Function to compute the best path in the same way as dijkstra, with the cost function parameters of
+// the sender. The search only holds the topology for reading, so that no channel opens under it, and
+// only reads the state of every channel it considers, so that it does not hold up the payments in
+// flight. With gossip, the sender knows the channels of other nodes and their policies only from its
+// view of the network, which may be out of date, while it knows its own channels as they are.
+pub fn dijkstra_with_cost(network: &Network, r: NodeIndex, s:NodeIndex, amt:u64, model: CostModel) -> (Vec<NodeIndex>,Vec<u32>,Vec<u64>){
+    dijkstra_via(network, r, s, None, amt, model)
+}
+
+// Function to compute the best path in the same way as dijkstra_with_cost, with the first hop of the
+// sender fixed to the given peer if there is one. A node rebalancing its channels uses it to send a
+// circular payment out over a chosen channel.
+pub fn dijkstra_via(network: &Network, r: NodeIndex, s:NodeIndex, first_hop: Option<NodeIndex>, amt:u64, model: CostModel) -> (Vec<NodeIndex>,Vec<u32>,Vec<u64>){
+    let topology = network.topology();
+    let view = network.gossip_view(s);
 
     // Initialize the distance map and the values of the timelocks and amounts (including fees) for
-    // every node in the graph.
-    let mut dist: HashMap<NodeIndex, OrderedFloat<f32>> = guard.node_indices().map(|n| (n, OrderedFloat(f32::INFINITY))).collect();
-    let mut timelock: HashMap<NodeIndex, f32> = guard.node_indices().map(|n| (n, 0.0)).collect();
-    let mut amount: HashMap<NodeIndex, f32> = guard.node_indices().map(|n| (n, 0.0)).collect();
+    // every node in the graph. Along with the timelock of the HTLC a node offers, the delta of the
+    // channel it forwards over is kept for the node before it. The amount of a node is the one it has
+    // to receive, which covers the fee of the channel it forwards over.
+    let mut dist: HashMap<NodeIndex, OrderedFloat<f32>> = topology.node_indices().map(|n| (n, OrderedFloat(f32::INFINITY))).collect();
+    let mut timelock: HashMap<NodeIndex, u32> = topology.node_indices().map(|n| (n, 0)).collect();
+    let mut delta: HashMap<NodeIndex, u32> = topology.node_indices().map(|n| (n, 0)).collect();
+    let mut amount: HashMap<NodeIndex, u64> = topology.node_indices().map(|n| (n, 0)).collect();
     let mut predecessors: HashMap<NodeIndex, NodeIndex> = HashMap::new();
     let mut heap = BinaryHeap::new();
 
@@ -71,7 +117,7 @@ pub fn dijkstra(graph: Arc<Mutex<DiGraph<usize, EdgeAttributes>>>, r: NodeIndex,
     // in the path is calculated based on the amount that it has to forward. Add the recipient to
     // the priority queue.
     dist.insert(r,OrderedFloat(0.0));
-    timelock.insert(r, 0.0);
+    timelock.insert(r, final_expiry(network));
     amount.insert(r,amt);
     heap.push(State { cost: OrderedFloat(0.0), position: r});
 
@@ -85,52 +131,159 @@ pub fn dijkstra(graph: Arc<Mutex<DiGraph<usize, EdgeAttributes>>>, r: NodeIndex,
         if position == s {
             let mut path = vec![s];
             let mut delays = vec![timelock[&s]];
-            let mut amounts = vec![amount[&s]];
             let mut current = s;
             while let Some(&predecessor) = predecessors.get(&current) {
                 path.push(predecessor);
-                delays.push(timelock[&predecessor] / 1000.0);
-                amounts.push(amount[&predecessor]);
+                delays.push(timelock[&predecessor]);
                 current = predecessor;
                 if current == s {
                     break;
                 }
             }
+            // Every node offers the next one the amount that node has to receive.
+            let amounts = path.iter().skip(1).map(|node| amount[node]).chain([amt]).collect();
             return ( path, delays, amounts);
         }
         // Update best paths for every neighbor of the current best node. If the sender is a
         // neighbor, the cost is calculated in a different manner as compared to non-senders.
         // Additionally, we keep in mind that the sender knows its balances but not the balances of
         // other channels.
-        for neighbor in guard.neighbors_directed(position, Direction::Incoming) {
-            if let Some(edge1) = guard.find_edge(neighbor, position) {
-                let attrs = &guard[edge1];
-                let mut next_cost:OrderedFloat<f32> = OrderedFloat(f32::INFINITY);
-                if neighbor == s{
-                    next_cost = OrderedFloat(cost.into_inner() + lnd_cost_fn_snd(attrs.clone(), amount[&position]));
-                }else{
-                    next_cost = OrderedFloat(cost.into_inner() + lnd_cost_fn(attrs.clone(), amount[&position]));
-                }
-                if let Some(edge) = guard.find_edge(neighbor, position) {
-                    let attrs = &guard[edge];
-                    if next_cost < *dist.get(&neighbor).unwrap_or(&OrderedFloat(f32::INFINITY)) {
-                        if (attrs.balance >= amount[&position] && neighbor ==s) || (attrs.balance + attrs.balance >= amount[&position] && neighbor!=s)  {
-                            heap.push(State { cost: next_cost, position: neighbor });
-                            dist.insert(neighbor, next_cost);
-                            timelock.insert(neighbor, timelock[&position] + attrs.delay);
-                            amount.insert(neighbor, amount[&position] + attrs.basefee + amount[&position] * attrs.feerate);
-                            predecessors.insert(neighbor, position);
-                        }
+        for neighbor in topology.neighbors_directed(position, Direction::Incoming) {
+            if neighbor == s && first_hop.is_some_and(|hop| hop != position) {
+                continue;
+            }
+            if let Some(edge1) = topology.find_edge(neighbor, position) {
+                let mut attrs = network.attributes(edge1);
+                if let (Some(view), true) = (&view, neighbor != s) {
+                    match view.policy(edge1) {
+                        Some(policy) => attrs = policy.apply(attrs),
+                        None => continue,
                     }
                 }
+                if !attrs.is_open() {
+                    continue;
+                }
+                let next_cost = if neighbor == s{
+                    OrderedFloat(cost.into_inner() + lnd_cost_fn_snd(attrs, amount[&position], model))
+                }else{
+                    OrderedFloat(cost.into_inner() + lnd_cost_fn(attrs, amount[&position], model))
+                };
+                if next_cost < *dist.get(&neighbor).unwrap_or(&OrderedFloat(f32::INFINITY)) && ((attrs.balance >= amount[&position] && neighbor ==s) || (attrs.balance + attrs.balance >= amount[&position] && neighbor!=s)) {
+                    heap.push(State { cost: next_cost, position: neighbor });
+                    dist.insert(neighbor, next_cost);
+                    timelock.insert(neighbor, timelock[&position] + delta[&position]);
+                    delta.insert(neighbor, attrs.delay);
+                    let fee = if neighbor == s { 0 } else { attrs.fee(amount[&position]) };
+                    amount.insert(neighbor, amount[&position] + fee);
+                    predecessors.insert(neighbor, position);
+                }
             }
 
 
         }
     }
 
-    // Drop the lock the shared lock
-    drop(guard);
     (Vec::new(), Vec::new(),Vec::new())
 }
 
+
+// Function to compute the timelocks and amounts (including fees) along a path chosen by the caller
+// rather than by the path finding algorithm. The values follow the same conventions as the ones
+// returned by dijkstra. Empty vectors are returned if a channel along the path does not exist or
+// is closed or being closed.
+pub fn route_through(network: &Network, path: Vec<NodeIndex>, amt: u64) -> (Vec<NodeIndex>,Vec<u32>,Vec<u64>){
+    if path.len() < 2 {
+        return (Vec::new(), Vec::new(),Vec::new());
+    }
+
+    // Walk the path backwards from the recipient, adding the fees and delays of every channel. Both
+    // are charged by the node forwarding over the channel, so they apply to the HTLC offered to it.
+    let mut delays = vec![final_expiry(network); path.len()];
+    let mut amounts = vec![amt; path.len()];
+    let mut delta = 0;
+    let mut fee = 0;
+    for i in (0..path.len() - 1).rev() {
+        let Some(edge) = network.find_edge(path[i], path[i + 1]) else {
+            return (Vec::new(), Vec::new(),Vec::new());
+        };
+        let attrs = network.attributes(edge);
+        if !attrs.is_open() {
+            return (Vec::new(), Vec::new(),Vec::new());
+        }
+        amounts[i] = amounts[i + 1] + fee;
+        delays[i] = delays[i + 1] + delta;
+        delta = attrs.delay;
+        fee = attrs.fee(amounts[i]);
+    }
+    (path, delays, amounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_graph::Graph;
+    use crate::topology::new_channel;
+
+    // Line of four nodes 0 -> 1 -> 2 -> 3 with a distinct policy on every channel and enough balance
+    // in both directions, so that the fee charged at every hop can be told apart.
+    fn line() -> Network {
+        let mut graph = Graph::new();
+        let nodes: Vec<NodeIndex> = (0..4).map(|id| graph.add_node(id)).collect();
+        let policies = [(1000, 100, 40), (2000, 200, 50), (3000, 300, 60)];
+        for (i, &(basefee, feerate, delay)) in policies.iter().enumerate() {
+            graph.add_edge(nodes[i], nodes[i + 1], new_channel(10_000_000_000, basefee, feerate, delay));
+            graph.add_edge(nodes[i + 1], nodes[i], new_channel(10_000_000_000, basefee, feerate, delay));
+        }
+        Network::new(graph)
+    }
+
+    #[test]
+    fn route_through_charges_the_outgoing_channel_of_every_forwarding_node() {
+        let network = line();
+        let path: Vec<NodeIndex> = (0..4).map(NodeIndex::new).collect();
+        let (_, timelocks, amounts) = route_through(&network, path, 1_000_000);
+        // The recipient receives the amount, node 2 charges the fee of channel 2 -> 3 and node 1 the
+        // fee of channel 1 -> 2, while the sender pays nothing for channel 0 -> 1.
+        let fee_2 = 3000 + 1_000_000 * 300 / 1_000_000;
+        let fee_1 = 2000 + (1_000_000 + fee_2) * 200 / 1_000_000;
+        assert_eq!(amounts, vec![1_000_000 + fee_2 + fee_1, 1_000_000 + fee_2, 1_000_000, 1_000_000]);
+        // The expiries follow the same rule with the cltv_expiry_delta of the outgoing channels.
+        let final_expiry = timelocks[3];
+        assert_eq!(timelocks, vec![final_expiry + 60 + 50, final_expiry + 60, final_expiry, final_expiry]);
+    }
+
+    #[test]
+    fn dijkstra_amounts_match_route_through() {
+        let network = line();
+        let (path, timelocks, amounts) = dijkstra(&network, NodeIndex::new(3), NodeIndex::new(0), 1_000_000);
+        assert_eq!(path, (0..4).map(NodeIndex::new).collect::<Vec<_>>());
+        assert_eq!((timelocks, amounts), {
+            let (_, timelocks, amounts) = route_through(&network, path, 1_000_000);
+            (timelocks, amounts)
+        });
+    }
+
+    #[test]
+    fn direct_payment_pays_no_fee() {
+        let network = line();
+        let (path, _, amounts) = dijkstra(&network, NodeIndex::new(1), NodeIndex::new(0), 1_000_000);
+        assert_eq!(path.len(), 2);
+        assert_eq!(amounts, vec![1_000_000, 1_000_000]);
+    }
+
+    #[test]
+    fn dijkstra_via_sends_over_the_given_first_hop() {
+        // Square 0 -> {1, 2} -> 3 where the route over node 1 is the cheaper one.
+        let mut graph = Graph::new();
+        let nodes: Vec<NodeIndex> = (0..4).map(|id| graph.add_node(id)).collect();
+        for (from, to, basefee) in [(0, 1, 1000), (1, 3, 1000), (0, 2, 1000), (2, 3, 9000)] {
+            graph.add_edge(nodes[from], nodes[to], new_channel(10_000_000_000, basefee, 100, 40));
+        }
+        let network = Network::new(graph);
+        let (path, _, _) = dijkstra(&network, nodes[3], nodes[0], 1_000_000);
+        assert_eq!(path, vec![nodes[0], nodes[1], nodes[3]]);
+        let (path, _, amounts) = dijkstra_via(&network, nodes[3], nodes[0], Some(nodes[2]), 1_000_000, CostModel::default());
+        assert_eq!(path, vec![nodes[0], nodes[2], nodes[3]]);
+        assert_eq!(amounts, vec![1_000_000 + 9000 + 100, 1_000_000, 1_000_000]);
+    }
+}
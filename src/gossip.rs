@@ -0,0 +1,216 @@
+use petgraph::graph::{EdgeIndex, NodeIndex};
+use rand::Rng;
+use rand::rngs::StdRng;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::create_graph::{EdgeAttributes, Graph};
+use crate::workload::{exponential, NodeClass};
+
+// Which nodes share a view of the network. Every view receives the gossip messages on its own, so
+// per-node views cost memory in the number of nodes times the number of channels, and suit small
+// graphs.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ViewScope {
+    // A single view for every node.
+    Shared,
+    // A view for every node class of the workload, and one for the nodes outside of every class.
+    #[default]
+    PerClass,
+    // A view for every node.
+    PerNode,
+}
+
+// Settings of the gossip. When enabled, senders find paths on their view of the network, which
+// learns of the channel_announcement and channel_update messages of the other nodes with a delay
+// drawn from an exponential distribution of the given mean, and misses some of them altogether.
+// When disabled, senders see the live state of every channel.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GossipConfig {
+    pub enabled: bool,
+    pub views: ViewScope,
+    pub delay_secs: f64, // Mean time for a message to reach a view
+    pub loss_rate: f64, // Probability that a view never receives a message
+}
+
+// Messages take about a minute to reach most of the network, as nodes batch them before passing them
+// on.
+impl Default for GossipConfig {
+    fn default() -> Self {
+        GossipConfig { enabled: false, views: ViewScope::PerClass, delay_secs: 60.0, loss_rate: 0.0 }
+    }
+}
+
+impl GossipConfig {
+    // Function to check that the delay and the loss rate make sense.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.delay_secs.is_finite() || self.delay_secs < 0.0 {
+            return Err("delay_secs must be a non-negative number of seconds".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.loss_rate) {
+            return Err("loss_rate must be between 0 and 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Forwarding policy of a channel direction as the gossip announces it. A channel is announced as
+// closed once it is closed on chain, so a channel being closed cooperatively still looks open.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelPolicy {
+    pub basefee: u64,
+    pub feerate: u64,
+    pub upfrontfee: u64,
+    pub holdfee: u64,
+    pub delay: u32,
+    pub open: bool,
+}
+
+impl ChannelPolicy {
+    // Function to read the policy of a channel direction from its current state.
+    pub fn of(attrs: &EdgeAttributes) -> Self {
+        ChannelPolicy { basefee: attrs.basefee, feerate: attrs.feerate, upfrontfee: attrs.upfrontfee, holdfee: attrs.holdfee, delay: attrs.delay, open: !attrs.closed }
+    }
+
+    // Function to replace the policy of the state of a channel direction with this one, as a sender
+    // that only knows the gossip sees it.
+    pub fn apply(&self, attrs: EdgeAttributes) -> EdgeAttributes {
+        EdgeAttributes {
+            basefee: self.basefee,
+            feerate: self.feerate,
+            upfrontfee: self.upfrontfee,
+            holdfee: self.holdfee,
+            delay: self.delay,
+            closed: !self.open,
+            closing: false,
+            ..attrs
+        }
+    }
+}
+
+// Message on its way to a view, with the time it was sent so that a message overtaken by a newer one
+// for the same channel is ignored, as with the timestamps of BOLT 7.
+#[derive(Debug, Clone, Copy)]
+struct Message {
+    edge: EdgeIndex,
+    policy: ChannelPolicy,
+    sent: Instant,
+}
+
+// View of the network of a group of nodes: the policy of every channel direction it knows of, and
+// the messages that have not reached it yet.
+#[derive(Debug, Default)]
+pub struct View {
+    policies: Vec<Option<(ChannelPolicy, Instant)>>, // Known policy and time it was sent, by edge index
+    pending: BTreeMap<(Instant, u64), Message>, // Messages by arrival time and sequence number
+}
+
+impl View {
+    // Policy of a channel direction as the view knows it, if it knows of the channel at all.
+    pub fn policy(&self, edge: EdgeIndex) -> Option<ChannelPolicy> {
+        self.policies.get(edge.index()).copied().flatten().map(|(policy, _)| policy)
+    }
+
+    // Function to apply the messages that have reached the view by the given time.
+    fn catch_up(&mut self, now: Instant) {
+        while let Some(entry) = self.pending.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let message = entry.remove();
+            if self.policies.len() <= message.edge.index() {
+                self.policies.resize(message.edge.index() + 1, None);
+            }
+            let known = &mut self.policies[message.edge.index()];
+            if known.is_none_or(|(_, sent)| sent <= message.sent) {
+                *known = Some((message.policy, message.sent));
+            }
+        }
+    }
+}
+
+// Counts of the gossip messages sent during a run.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GossipStats {
+    pub messages: u64, // Messages broadcast
+    pub deliveries: u64, // Messages scheduled to reach a view
+    pub lost: u64, // Messages a view never receives
+}
+
+// Gossip of the network: the views of the groups of nodes and the random delays and losses of the
+// messages on their way. The views start with the policies of the snapshot.
+pub struct Gossip {
+    config: GossipConfig,
+    view_of: Vec<usize>, // View of every node of the snapshot, nodes that join later use the first one
+    views: Vec<Mutex<View>>,
+    rng: Mutex<(StdRng, GossipStats)>,
+}
+
+impl Gossip {
+    // Function to create the views of the nodes of the graph, grouped as the config requires with
+    // the node classes of the workload. The first view is that of the nodes outside of every group,
+    // including the nodes that join during the run.
+    pub fn new(config: GossipConfig, graph: &Graph, classes: &[NodeClass], rng: StdRng) -> Self {
+        let view_of: Vec<usize> = match config.views {
+            ViewScope::Shared => vec![0; graph.node_count()],
+            ViewScope::PerNode => (1..=graph.node_count()).collect(),
+            ViewScope::PerClass => {
+                let mut view_of = vec![0; graph.node_count()];
+                for (i, class) in classes.iter().enumerate() {
+                    for &node in &class.nodes {
+                        if let Some(view) = view_of.get_mut(node) {
+                            *view = i + 1;
+                        }
+                    }
+                }
+                view_of
+            }
+        };
+        let view_count = view_of.iter().max().map_or(1, |&last| last + 1);
+        let start = Instant::now();
+        let views = (0..view_count)
+            .map(|_| {
+                let policies = graph.edge_weights().map(|attrs| Some((ChannelPolicy::of(attrs), start))).collect();
+                Mutex::new(View { policies, pending: BTreeMap::new() })
+            })
+            .collect();
+        Gossip { config, view_of, views, rng: Mutex::new((rng, GossipStats::default())) }
+    }
+
+    // Function to broadcast the policy of a channel direction to every view. Each view receives it
+    // after its own delay, unless it misses it.
+    pub fn broadcast(&self, edge: EdgeIndex, policy: ChannelPolicy) {
+        let sent = Instant::now();
+        let mut guard = self.rng.lock().unwrap_or_else(PoisonError::into_inner);
+        let (rng, stats) = &mut *guard;
+        stats.messages += 1;
+        for view in &self.views {
+            if rng.gen::<f64>() < self.config.loss_rate {
+                stats.lost += 1;
+                continue;
+            }
+            let delay = if self.config.delay_secs > 0.0 { exponential(rng, 1.0 / self.config.delay_secs) } else { 0.0 };
+            let arrival = sent + Duration::from_secs_f64(delay);
+            let message = Message { edge, policy, sent };
+            view.lock().unwrap_or_else(PoisonError::into_inner).pending.insert((arrival, stats.deliveries), message);
+            stats.deliveries += 1;
+        }
+    }
+
+    // Function to lock the view of a node, up to date with the messages that have reached it.
+    pub fn view(&self, node: NodeIndex) -> MutexGuard<'_, View> {
+        let index = self.view_of.get(node.index()).copied().unwrap_or(0);
+        let mut view = self.views[index].lock().unwrap_or_else(PoisonError::into_inner);
+        view.catch_up(Instant::now());
+        view
+    }
+
+    // Counts of the messages sent so far.
+    pub fn stats(&self) -> GossipStats {
+        self.rng.lock().unwrap_or_else(PoisonError::into_inner).1
+    }
+}
@@ -0,0 +1,293 @@
+use petgraph::graph::{EdgeIndex, NodeIndex};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+use log::{info, error};
+use tokio::time::sleep;
+use crate::create_graph::sat_to_msat;
+use crate::find_path;
+use crate::network::Network;
+use crate::payment_router::{Payment, RecipientBehaviour};
+use crate::reputation::EndorsementConfig;
+use crate::ledger::Ledger;
+
+// Fraction of the shortest timelock in the path for which a slow jamming HTLC is held before the
+// attacker fails it. Holding it a little less than the full timelock keeps the attacker from having
+// its own channels closed.
+const HOLD_FRACTION: f32 = 0.95;
+// Payment ids of the attackers start at this offset so that they never collide with the ids given
+// out by the scheduler.
+const ATTACK_PAYMENT_ID_OFFSET: u64 = 1 << 32;
+
+// The two kinds of jamming. A slow jammer holds its HTLCs until just before they expire while a
+// fast jammer keeps sending HTLCs that the recipient fails right away.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JammingStrategy {
+    Slow,
+    Fast,
+}
+
+// Structure to hold the configuration of an attacker. The attacker controls both the sender and the
+// recipient of its payments, and routes them through the target channel.
+#[derive(Debug, Clone, Copy)]
+pub struct Attacker {
+    pub strategy: JammingStrategy,
+    pub sender: NodeIndex, // Node sending the jamming HTLCs
+    pub recipient: NodeIndex, // Node receiving and then holding or failing the jamming HTLCs
+    pub target: (NodeIndex, NodeIndex), // Channel under attack
+    pub amount: u64, // Amount in msat of every jamming HTLC
+    pub htlcs: usize, // Number of jamming HTLCs kept in flight at the same time
+    pub interval: Duration, // Delay between two HTLCs sent by a fast jammer
+    pub endorsed: bool, // Whether the attacker endorses its HTLCs
+}
+
+// Structure to hold the cost of the attack for the attacker and the damage done to the target
+// channels. Fees are in msat and values locked in msat multiplied by seconds.
+#[derive(Debug, Clone, Default)]
+pub struct JammingReport {
+    pub htlcs_sent: u64, // Number of jamming HTLCs sent
+    pub htlcs_at_target: u64, // Number of jamming HTLCs that were locked in a target channel
+    pub attacker_rejections: u64, // Number of jamming HTLCs rejected by a target channel
+    pub value_time_locked: f64, // Value locked by the attacker multiplied by the time it was locked
+    pub fees_paid: u64, // Upfront and hold fees paid by the attacker
+    pub target_value_time_locked: f64, // Value locked in the target channels by the attack
+    pub rejections_before: BTreeMap<EdgeIndex, u32>, // Rejected HTLCs of every target channel when the attack started
+}
+
+// Configuration of an attacker as written in the attackers file or in the [[attackers]] of a
+// scenario, with the nodes as ids of the snapshot and the amount in satoshis.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AttackerConfig {
+    pub strategy: JammingStrategy,
+    pub sender: usize,
+    pub recipient: usize,
+    pub target: (usize, usize),
+    pub amount: f64,
+    pub htlcs: usize,
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default = "default_endorsed")]
+    pub endorsed: bool,
+}
+
+fn default_interval_ms() -> u64 {
+    10
+}
+
+fn default_endorsed() -> bool {
+    true
+}
+
+impl From<AttackerConfig> for Attacker {
+    fn from(config: AttackerConfig) -> Self {
+        Attacker {
+            strategy: config.strategy,
+            sender: NodeIndex::new(config.sender),
+            recipient: NodeIndex::new(config.recipient),
+            target: (NodeIndex::new(config.target.0), NodeIndex::new(config.target.1)),
+            amount: sat_to_msat(config.amount),
+            htlcs: config.htlcs,
+            interval: Duration::from_millis(config.interval_ms),
+            endorsed: config.endorsed,
+        }
+    }
+}
+
+// Function to read the attackers from a JSON file. Every attacker is an object of the form
+// {"strategy": "slow", "sender": 1, "recipient": 2, "target": [3, 4], "amount": 1.0, "htlcs": 483,
+// "interval_ms": 10, "endorsed": true} where the nodes are ids of the snapshot and the amount is in
+// satoshis.
+pub fn load_attackers<P: AsRef<Path>>(file_path: P) -> Result<Vec<Attacker>, String> {
+    let file_content = fs::read_to_string(file_path).map_err(|e| e.to_string())?;
+    let attackers: Vec<AttackerConfig> = serde_json::from_str(&file_content).map_err(|e| e.to_string())?;
+    Ok(attackers.into_iter().map(Attacker::from).collect())
+}
+
+// Function to compute the path of a jamming HTLC. The attacker routes from its sender to the start
+// of the target channel, through the target channel, and from its end to the attacker's recipient.
+fn attack_route(network: &Network, attacker: &Attacker) -> (Vec<NodeIndex>,Vec<u32>,Vec<u64>) {
+    let segment = |from: NodeIndex, to: NodeIndex| {
+        if from == to {
+            vec![from]
+        } else {
+            find_path::dijkstra(network, to, from, attacker.amount).0
+        }
+    };
+    let mut path = segment(attacker.sender, attacker.target.0);
+    let tail = segment(attacker.target.1, attacker.recipient);
+    if path.is_empty() || tail.is_empty() {
+        return (Vec::new(), Vec::new(), Vec::new());
+    }
+    path.extend(tail);
+    find_path::route_through(network, path, attacker.amount)
+}
+
+// Function to keep one jamming HTLC in flight until the simulation stops. As soon as an HTLC is
+// failed back, the next one is sent.
+async fn jam_slot(network: Arc<Network>, attacker: Attacker, payment_id: u64, config: EndorsementConfig, ledger: Arc<Mutex<Ledger>>, report: Arc<Mutex<JammingReport>>, stopped: Arc<AtomicBool>) {
+    while !stopped.load(Ordering::Relaxed) {
+        let (path, timelocks, amounts) = attack_route(&network, &attacker);
+        let target_index = path.windows(2).position(|hop| hop[0] == attacker.target.0 && hop[1] == attacker.target.1);
+        let Some(target_index) = target_index else {
+            error!("Attacker found no path through the target channel {:?}", attacker.target);
+            sleep(attacker.interval).await;
+            continue;
+        };
+        let behaviour = match attacker.strategy {
+            JammingStrategy::Slow => {
+                let shortest = timelocks[..timelocks.len() - 1].iter().copied().min().unwrap_or(0);
+                RecipientBehaviour::Hold(network.chain().time_until(shortest).mul_f32(HOLD_FRACTION))
+            }
+            JammingStrategy::Fast => RecipientBehaviour::Reject,
+        };
+        let sent_amount = amounts[0];
+        let target_amount = amounts[target_index];
+        let mut payment = Payment::new(payment_id, path, timelocks, amounts);
+        payment.set_recipient_behaviour(behaviour);
+        payment.set_endorsed(attacker.endorsed);
+
+        let start = Instant::now();
+        let result = Payment::payment_manager(Arc::clone(&network), &mut payment, config).await;
+        let locked_for = start.elapsed().as_secs_f64();
+        if let Ok(mut ledger) = ledger.lock() {
+            ledger.record(&payment, result.is_ok());
+        }
+
+        // Update the cost of the attack and the damage done to the target channel.
+        if let Ok(mut report) = report.lock() {
+            report.htlcs_sent += 1;
+            report.value_time_locked += sent_amount as f64 * locked_for;
+            report.fees_paid += payment.upfront_and_hold_fees();
+            if payment.failed_at() == Some(target_index) {
+                report.attacker_rejections += 1;
+            } else if payment.failed_at().is_some_and(|index| index > target_index) {
+                report.htlcs_at_target += 1;
+                report.target_value_time_locked += target_amount as f64 * locked_for;
+            }
+        }
+
+        if attacker.strategy == JammingStrategy::Fast {
+            sleep(attacker.interval).await;
+        }
+    }
+}
+
+// Function to run the attackers. Every attacker keeps the configured number of jamming HTLCs in
+// flight through the normal payment router, so that they compete with the honest payments for the
+// HTLC slots and the liquidity of the target channels. Once the stop flag is set, the attackers send
+// no new HTLCs and the function returns after the HTLCs in flight have resolved.
+pub async fn run_attackers(network: Arc<Network>, attackers: Vec<Attacker>, config: EndorsementConfig, ledger: Arc<Mutex<Ledger>>, report: Arc<Mutex<JammingReport>>, stopped: Arc<AtomicBool>) {
+    if let Ok(mut report) = report.lock() {
+        // Attackers sharing a target channel share its count, so that its rejections count once.
+        report.rejections_before = attackers.iter()
+            .filter_map(|attacker| network.find_edge(attacker.target.0, attacker.target.1))
+            .map(|edge| (edge, network.channel(edge).rejected_htlcs))
+            .collect();
+    }
+    let mut handles = Vec::new();
+    for (attacker_id, attacker) in attackers.into_iter().enumerate() {
+        info!("Attacker {:?} started {:?} jamming of channel {:?}", attacker_id, attacker.strategy, attacker.target);
+        for slot in 0..attacker.htlcs {
+            let payment_id = ATTACK_PAYMENT_ID_OFFSET * (attacker_id as u64 + 1) + slot as u64;
+            handles.push(tokio::spawn(jam_slot(Arc::clone(&network), attacker, payment_id, config, Arc::clone(&ledger), Arc::clone(&report), Arc::clone(&stopped))));
+        }
+    }
+    for handle in handles {
+        let _ = handle.await;
+    }
+}
+
+// Function to log the cost of the attack against the damage done. The damage is measured by the
+// number of honest HTLCs the target channels rejected during the attack, by the value the attack
+// kept locked in them and by the channels force-closed when jamming HTLCs expired.
+pub fn log_report(network: &Network, report: Arc<Mutex<JammingReport>>) {
+    let Ok(report) = report.lock() else {
+        return;
+    };
+    let rejections_during_attack: u64 = report.rejections_before.iter()
+        .map(|(&edge, &before)| (network.channel(edge).rejected_htlcs - before) as u64)
+        .sum();
+    let honest_rejections = rejections_during_attack.saturating_sub(report.attacker_rejections);
+    // Channels closed on chain because a jamming HTLC expired in them.
+    let force_closes = network.onchain().force_closes.iter().filter(|close| close.payment_id >= ATTACK_PAYMENT_ID_OFFSET).count();
+    info!("Attack cost: {:?} HTLCs sent, {:?} msat*s locked by the attacker, {:?} msat of upfront and hold fees paid", report.htlcs_sent, report.value_time_locked, report.fees_paid);
+    info!("Attack damage: {:?} HTLCs jammed the targets for {:?} msat*s, {:?} honest HTLCs rejected by the targets, {:?} channels force-closed by expired jamming HTLCs", report.htlcs_at_target, report.target_value_time_locked, honest_rejections, force_closes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_graph::Graph;
+    use crate::topology::new_channel;
+
+    // Function to share a line of four nodes, 0 - 1 - 2 - 3, whose channel from 1 to 2 takes at most
+    // the given number of HTLCs.
+    fn line(target_slots: u16) -> Arc<Network> {
+        let mut graph = Graph::new();
+        let nodes: Vec<NodeIndex> = (0..4).map(|id| graph.add_node(id)).collect();
+        for pair in nodes.windows(2) {
+            graph.add_edge(pair[0], pair[1], new_channel(1_000_000_000, 0, 0, 40));
+            graph.add_edge(pair[1], pair[0], new_channel(1_000_000_000, 0, 0, 40));
+        }
+        let target = graph.find_edge(nodes[1], nodes[2]).unwrap();
+        graph[target].max_accepted_htlcs = target_slots;
+        Arc::new(Network::new(graph))
+    }
+
+    fn attacker(strategy: JammingStrategy, htlcs: usize) -> Attacker {
+        Attacker::from(AttackerConfig { strategy, sender: 0, recipient: 3, target: (1, 2), amount: 1000.0, htlcs, interval_ms: 1000, endorsed: true })
+    }
+
+    // Function to run the attackers for the given virtual time and return their report.
+    async fn attack(network: &Arc<Network>, attackers: Vec<Attacker>, duration: Duration) -> JammingReport {
+        let report = Arc::new(Mutex::new(JammingReport::default()));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let ledger = Arc::new(Mutex::new(Ledger::default()));
+        let run = tokio::spawn(run_attackers(Arc::clone(network), attackers, EndorsementConfig::default(), ledger, Arc::clone(&report), Arc::clone(&stopped)));
+        sleep(duration).await;
+        stopped.store(true, Ordering::Relaxed);
+        run.await.unwrap();
+        let report = report.lock().unwrap().clone();
+        report
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fast_jamming_htlcs_are_counted_at_the_target() {
+        let network = line(483);
+        let report = attack(&network, vec![attacker(JammingStrategy::Fast, 1)], Duration::from_millis(4500)).await;
+        // One HTLC every second, each failed by the recipient right after crossing the target.
+        assert_eq!(report.htlcs_sent, 5);
+        assert_eq!((report.htlcs_at_target, report.attacker_rejections), (5, 0));
+        assert_eq!(report.fees_paid, 0);
+        let target = network.find_edge(NodeIndex::new(1), NodeIndex::new(2)).unwrap();
+        let attrs = network.attributes(target);
+        assert_eq!((attrs.balance, attrs.pending_htlcs), (1_000_000_000, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn htlcs_beyond_the_slots_of_the_target_are_rejections() {
+        let network = line(1);
+        let report = attack(&network, vec![attacker(JammingStrategy::Slow, 2)], Duration::from_secs(1)).await;
+        // The first slow HTLC holds the only slot of the target until it is failed back, and every
+        // HTLC of the other slot is rejected by the target in the meantime.
+        assert_eq!(report.htlcs_at_target, 1);
+        assert_eq!(report.attacker_rejections, report.htlcs_sent - 1);
+        assert!(report.attacker_rejections > 0);
+        assert!(report.target_value_time_locked > 0.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn attackers_sharing_a_target_count_its_rejections_once() {
+        let network = line(483);
+        let report = attack(&network, vec![attacker(JammingStrategy::Fast, 1), attacker(JammingStrategy::Fast, 1)], Duration::ZERO).await;
+        let target = network.find_edge(NodeIndex::new(1), NodeIndex::new(2)).unwrap();
+        assert_eq!(report.rejections_before, BTreeMap::from([(target, 0)]));
+    }
+}
@@ -0,0 +1,88 @@
+use petgraph::graph::DiGraph;
+use rand::Rng;
+use serde::Deserialize;
+use std::time::Duration;
+use crate::create_graph::EdgeAttributes;
+use crate::workload::{exponential, standard_normal};
+
+// Model of the time an HTLC takes to cross a channel, covering the network delay and the processing
+// time of the forwarding node. Every channel is given its own latency drawn from the model.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum LatencyModel {
+    // HTLCs are forwarded without delay.
+    #[default]
+    None,
+    Constant { ms: f64 },
+    Uniform { min_ms: f64, max_ms: f64 },
+    Exponential { mean_ms: f64 },
+    // Latencies whose logarithm in milliseconds is normally distributed with mean mu and standard
+    // deviation sigma.
+    LogNormal { mu: f64, sigma: f64 },
+}
+
+impl LatencyModel {
+    // Function to check that the parameters of the model make sense.
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            LatencyModel::Constant { ms } if !ms.is_finite() || ms < 0.0 => Err("Constant latency must not be negative".to_string()),
+            LatencyModel::Uniform { min_ms, max_ms } if !min_ms.is_finite() || !max_ms.is_finite() || min_ms < 0.0 || max_ms < min_ms => Err("Uniform latency needs 0 <= min_ms <= max_ms".to_string()),
+            LatencyModel::Exponential { mean_ms } if !mean_ms.is_finite() || mean_ms <= 0.0 => Err("Exponential latency mean must be positive".to_string()),
+            LatencyModel::LogNormal { mu, sigma } if !mu.is_finite() || !sigma.is_finite() || sigma < 0.0 => Err("Log-normal latency needs a finite mu and sigma, and sigma must not be negative".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    // Function to draw the latency of a channel. Draws too large for a duration, such as those of a
+    // log-normal model with a large mu, are an error.
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Result<Duration, String> {
+        let ms = match *self {
+            LatencyModel::None => 0.0,
+            LatencyModel::Constant { ms } => ms,
+            LatencyModel::Uniform { min_ms, max_ms } => min_ms + (max_ms - min_ms) * rng.gen::<f64>(),
+            LatencyModel::Exponential { mean_ms } => exponential(rng, 1.0 / mean_ms),
+            LatencyModel::LogNormal { mu, sigma } => (mu + sigma * standard_normal(rng)).exp(),
+        };
+        Duration::try_from_secs_f64(ms / 1000.0).map_err(|_| format!("Latency of {} ms drawn from {:?} is out of range", ms, self))
+    }
+}
+
+// Function to draw the latency of every channel in the graph from the model.
+pub fn set_latencies<R: Rng + ?Sized>(graph: &mut DiGraph<usize, EdgeAttributes>, model: &LatencyModel, rng: &mut R) -> Result<(), String> {
+    for attrs in graph.edge_weights_mut() {
+        attrs.latency = model.sample(rng)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn non_finite_parameters_are_rejected() {
+        let models = [
+            LatencyModel::Constant { ms: f64::INFINITY },
+            LatencyModel::Uniform { min_ms: f64::NAN, max_ms: 10.0 },
+            LatencyModel::Uniform { min_ms: 0.0, max_ms: f64::INFINITY },
+            LatencyModel::Exponential { mean_ms: f64::NAN },
+            LatencyModel::LogNormal { mu: f64::INFINITY, sigma: 1.0 },
+            LatencyModel::LogNormal { mu: 1.0, sigma: f64::NAN },
+        ];
+        for model in models {
+            assert!(model.validate().is_err(), "{:?}", model);
+        }
+        assert!(LatencyModel::Uniform { min_ms: 0.0, max_ms: 10.0 }.validate().is_ok());
+    }
+
+    #[test]
+    fn latency_too_large_for_a_duration_is_an_error() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let model = LatencyModel::LogNormal { mu: 1000.0, sigma: 0.0 };
+        assert!(model.validate().is_ok());
+        assert!(model.sample(&mut rng).is_err());
+        assert_eq!(LatencyModel::Constant { ms: 1500.0 }.sample(&mut rng), Ok(Duration::from_millis(1500)));
+    }
+}
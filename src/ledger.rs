@@ -0,0 +1,185 @@
+use petgraph::graph::NodeIndex;
+use std::collections::BTreeMap;
+use log::{info, error};
+use crate::payment_router::Payment;
+
+// Number of nodes and channels listed in the revenue reports written to the log.
+const REPORT_SIZE: usize = 10;
+
+// Structure to hold the fees earned and paid by a node, and the value it had locked in HTLCs. Fees
+// are in msat and the value locked in msat multiplied by seconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NodeAccount {
+    pub forwarding_fees: u64, // Forwarding fees earned on settled payments
+    pub upfront_fees: u64, // Upfront fees earned as an intermediate hop or the recipient
+    pub hold_fees: u64, // Hold fees earned as an intermediate hop or the recipient
+    pub hold_fee_losses: u64, // Hold fees paid downstream beyond the ones received from upstream
+    pub hold_fee_shortfall: u64, // Hold fees owed downstream that the balance of the node did not cover
+    pub forwarded: u64, // Number of settled payments forwarded
+    pub fees_paid: u64, // Fees paid as the sender of payments
+    pub value_time_locked: f64, // Value locked in HTLCs multiplied by the time it was locked
+}
+
+// Structure to hold the fees earned over a channel by the node forwarding over it, in the same units
+// as the accounts of the nodes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChannelAccount {
+    pub forwarding_fees: u64, // Forwarding fees earned on settled payments
+    pub upfront_fees: u64, // Upfront fees earned on HTLCs forwarded over the channel
+    pub hold_fees: u64, // Hold fees earned on HTLCs forwarded over the channel
+    pub forwarded: u64, // Number of settled payments forwarded over the channel
+    pub value_time_locked: f64, // Value locked in the channel multiplied by the time it was locked
+}
+
+impl NodeAccount {
+    // Total revenue earned by the node, before the hold fee losses.
+    pub fn revenue(&self) -> u64 {
+        self.forwarding_fees + self.upfront_fees + self.hold_fees
+    }
+}
+
+impl ChannelAccount {
+    // Total revenue earned over the channel.
+    pub fn revenue(&self) -> u64 {
+        self.forwarding_fees + self.upfront_fees + self.hold_fees
+    }
+}
+
+// Ledger of the routing fee revenue of every node and channel. The revenue of a forwarding node is
+// credited to its outgoing channel in the path, as routing nodes account for it.
+#[derive(Debug, Clone, Default)]
+pub struct Ledger {
+    nodes: BTreeMap<NodeIndex, NodeAccount>,
+    channels: BTreeMap<(NodeIndex, NodeIndex), ChannelAccount>,
+}
+
+impl Ledger {
+    // Function to record a finished payment. Every intermediate hop is credited with the upfront and
+    // hold fees it kept, and on settlement with its forwarding fee, the fee of its outgoing channel.
+    // The recipient keeps the upfront and hold fees paid over the last channel. The sender is debited
+    // with the total fees it paid, and every node with the value it had locked.
+    pub fn record(&mut self, payment: &Payment, settled: bool) {
+        let path = payment.path();
+        if path.len() < 2 {
+            return;
+        }
+        let amounts = payment.amounts();
+        let upfront_fees = payment.upfront_fees();
+        let hold_fees = payment.hold_fees();
+        let hold_times = payment.hold_times();
+        let last = path.len() - 1;
+
+        for i in 0..last {
+            let value_time_locked = amounts[i] as f64 * hold_times[i].as_secs_f64();
+            self.nodes.entry(path[i]).or_default().value_time_locked += value_time_locked;
+            let channel = self.channels.entry((path[i], path[i + 1])).or_default();
+            channel.value_time_locked += value_time_locked;
+            if i == 0 {
+                continue;
+            }
+            // A hop whose upstream node could not pay all the hold fees it owed may have paid more
+            // downstream than it received. The difference is booked as a loss.
+            let forwarding_fee = if settled { amounts[i - 1] - amounts[i] } else { 0 };
+            let upfront_fee = upfront_fees[i - 1].saturating_sub(upfront_fees[i]);
+            let hold_fee = hold_fees[i - 1].saturating_sub(hold_fees[i]);
+            let hold_fee_loss = hold_fees[i].saturating_sub(hold_fees[i - 1]);
+            channel.forwarding_fees += forwarding_fee;
+            channel.upfront_fees += upfront_fee;
+            channel.hold_fees += hold_fee;
+            let node = self.nodes.entry(path[i]).or_default();
+            node.forwarding_fees += forwarding_fee;
+            node.upfront_fees += upfront_fee;
+            node.hold_fees += hold_fee;
+            node.hold_fee_losses += hold_fee_loss;
+            if settled {
+                channel.forwarded += 1;
+                node.forwarded += 1;
+            }
+        }
+
+        for (i, &shortfall) in payment.hold_fee_shortfalls().iter().enumerate().take(last) {
+            self.nodes.entry(path[i]).or_default().hold_fee_shortfall += shortfall;
+        }
+        let recipient = self.nodes.entry(path[last]).or_default();
+        recipient.upfront_fees += upfront_fees[last - 1];
+        recipient.hold_fees += hold_fees[last - 1];
+
+        let forwarding_fees = if settled { amounts[0] - amounts[last] } else { 0 };
+        self.nodes.entry(path[0]).or_default().fees_paid += forwarding_fees + payment.upfront_and_hold_fees();
+    }
+
+    // Account of a channel direction, empty if nothing was forwarded over it.
+    pub fn channel(&self, from: NodeIndex, to: NodeIndex) -> ChannelAccount {
+        self.channels.get(&(from, to)).copied().unwrap_or_default()
+    }
+
+    // Per-node report of the accounts, sorted by decreasing revenue.
+    pub fn node_report(&self) -> Vec<(NodeIndex, NodeAccount)> {
+        let mut report: Vec<_> = self.nodes.iter().map(|(&node, &account)| (node, account)).collect();
+        report.sort_by(|a, b| b.1.revenue().cmp(&a.1.revenue()).then(a.0.cmp(&b.0)));
+        report
+    }
+
+    // Per-channel report of the accounts, sorted by decreasing revenue.
+    pub fn channel_report(&self) -> Vec<((NodeIndex, NodeIndex), ChannelAccount)> {
+        let mut report: Vec<_> = self.channels.iter().map(|(&channel, &account)| (channel, account)).collect();
+        report.sort_by(|a, b| b.1.revenue().cmp(&a.1.revenue()).then(a.0.cmp(&b.0)));
+        report
+    }
+
+    // Total fees in msat paid by the senders and total revenue earned by the nodes net of their hold
+    // fee losses, which are equal as every fee paid goes to a node in the path.
+    pub fn totals(&self) -> (u64, u64) {
+        let paid = self.nodes.values().map(|account| account.fees_paid).sum();
+        let earned: u64 = self.nodes.values().map(NodeAccount::revenue).sum();
+        let losses: u64 = self.nodes.values().map(|account| account.hold_fee_losses).sum();
+        (paid, earned.saturating_sub(losses))
+    }
+
+    // Function to log the nodes and channels with the highest revenue.
+    pub fn log_report(&self) {
+        let (paid, total) = self.totals();
+        info!("Total routing revenue {:?} msat over {:?} nodes", total, self.nodes.len());
+        if paid != total {
+            error!("Ledger out of balance: senders paid {:?} msat of fees but nodes earned {:?} msat", paid, total);
+        }
+        let shortfall: u64 = self.nodes.values().map(|account| account.hold_fee_shortfall).sum();
+        if shortfall > 0 {
+            info!("Hold fees owed but not paid for lack of balance: {:?} msat", shortfall);
+        }
+        for (node, account) in self.node_report().into_iter().take(REPORT_SIZE) {
+            info!("Node {:?} revenue {:?} from {:?} payments, fees paid {:?}, value time locked {:?}", node, account.revenue(), account.forwarded, account.fees_paid, account.value_time_locked);
+        }
+        for (channel, account) in self.channel_report().into_iter().take(REPORT_SIZE) {
+            info!("Channel {:?} revenue {:?} from {:?} payments, value time locked {:?}", channel, account.revenue(), account.forwarded, account.value_time_locked);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::find_path;
+    use crate::network::Network;
+    use crate::create_graph::Graph;
+    use crate::topology::new_channel;
+
+    #[test]
+    fn settled_payment_credits_every_node_the_fee_of_its_outgoing_channel() {
+        let mut graph = Graph::new();
+        let nodes: Vec<NodeIndex> = (0..4).map(|id| graph.add_node(id)).collect();
+        for (i, basefee) in [1000, 2000, 3000].into_iter().enumerate() {
+            graph.add_edge(nodes[i], nodes[i + 1], new_channel(10_000_000_000, basefee, 0, 40));
+        }
+        let network = Network::new(graph);
+        let (path, timelocks, amounts) = find_path::route_through(&network, nodes.clone(), 1_000_000);
+        let mut ledger = Ledger::default();
+        ledger.record(&Payment::new(1, path, timelocks, amounts), true);
+
+        assert_eq!(ledger.channel(nodes[0], nodes[1]).forwarding_fees, 0);
+        assert_eq!(ledger.channel(nodes[1], nodes[2]).forwarding_fees, 2000);
+        assert_eq!(ledger.channel(nodes[2], nodes[3]).forwarding_fees, 3000);
+        assert_eq!(ledger.node_report().iter().find(|(node, _)| *node == nodes[0]).map(|(_, account)| account.fees_paid), Some(5000));
+        assert_eq!(ledger.totals(), (5000, 5000));
+    }
+}
@@ -0,0 +1,61 @@
+// Library of the Lightning Network simulator. Its API can be used to load a snapshot and find paths
+// on it, or to run the simulation of a scenario:
+//
+// let scenario = lnsimulator::Scenario::load("experiment.toml")?;
+// scenario.validate()?;
+// let summary = lnsimulator::simulate(scenario).await?;
+//
+// The simulation has to run on a tokio runtime with a paused clock, such as the one of
+// #[tokio::main(flavor = "current_thread", start_paused = true)] or
+// #[tokio::test(start_paused = true)], so that its results do not depend on the machine.
+#![warn(unnameable_types)]
+mod autopilot;
+mod chain;
+mod create_graph;
+mod fee_agents;
+mod find_path;
+mod gossip;
+mod jamming;
+mod latency;
+mod liquidity;
+mod ledger;
+mod network;
+mod payment_router;
+mod payment_scheduler;
+mod rebalancing;
+mod report;
+mod reputation;
+mod results;
+mod scenario;
+mod simulation;
+mod sweep;
+mod topology;
+mod trace;
+mod workload;
+
+// The modules are internal to the crate, and the types and functions programs need are re-exported
+// here, so that they do not depend on the module layout. Every type that appears in a public field or
+// signature is re-exported as well, which the lint below checks, so that programs can build and change
+// scenarios and drive payments in code.
+pub use autopilot::{Autopilot, AutopilotStrategy};
+pub use chain::{Chain, ChainConfig, ForceClose, OnChain, Output as ChainOutput};
+pub use create_graph::{load_graph, msat_to_sat, node_index, sat_to_msat, write_graph, BalanceStrategy, EdgeAttributes, Graph};
+pub use fee_agents::{FeeAgent, FeeStrategy};
+pub use find_path::{dijkstra, dijkstra_via, dijkstra_with_cost, CostModel};
+pub use gossip::{ChannelPolicy, Gossip, GossipConfig, GossipStats, View, ViewScope};
+pub use jamming::{load_attackers, Attacker, AttackerConfig, JammingStrategy};
+pub use latency::LatencyModel;
+pub use ledger::{ChannelAccount, Ledger, NodeAccount};
+pub use liquidity::{LiquidityAction, LiquidityAgent, LiquidityConfig};
+pub use network::Network;
+pub use payment_router::{Payment, RecipientBehaviour};
+pub use payment_scheduler::{Convergence, PaymentHook, Progress, StopConditions};
+pub use rebalancing::Rebalancer;
+pub use report::{AmountBucket, ChannelUse, Distribution, Summary};
+pub use reputation::{ChannelReputation, EndorsementConfig};
+pub use results::{Outcome, PaymentRecord, Request};
+pub use scenario::{GraphSource, Output, Scenario, TraceSource};
+pub use simulation::{simulate, simulate_with_hooks};
+pub use sweep::{run_sweep, Estimate, Point, PointResult, Sweep, SweepReport};
+pub use topology::{new_channel, ChurnModel, TopologyConfig};
+pub use workload::{AmountDistribution, ArrivalProcess, NodeClass, NodePicker, Workload};
@@ -0,0 +1,357 @@
+use log::{info, error};
+use petgraph::graph::{EdgeIndex, NodeIndex};
+use petgraph::visit::EdgeRef;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Instant};
+use crate::chain::Output;
+use crate::create_graph::{msat_to_sat, sat_to_msat, PPM};
+use crate::network::Network;
+use crate::payment_scheduler;
+
+// Operations that move liquidity between a channel and the chain. Swaps keep the capacity of the
+// channel and move funds between its sides against on-chain funds, while splices change its capacity.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum LiquidityAction {
+    // Submarine swap paying the peer off-chain for on-chain funds, which turns local balance into
+    // inbound liquidity.
+    LoopOut,
+    // Submarine swap paying on-chain funds for an off-chain payment from the peer, which turns inbound
+    // liquidity into local balance.
+    LoopIn,
+    // Splice adding on-chain funds to the local balance of the channel.
+    SpliceIn,
+    // Splice moving part of the local balance of the channel to the chain.
+    SpliceOut,
+}
+
+// Confirmation delay and costs of the liquidity operations. An operation takes effect once its
+// transaction has the given number of confirmations. Every operation pays the on-chain fee, and swaps
+// also pay the fee of the swap service.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LiquidityConfig {
+    pub confirmation_blocks: u32,
+    pub onchain_fee: f64, // On-chain fee in satoshis of an operation
+    pub swap_fee_ppm: u64, // Fee of the swap service in millionths of the amount swapped
+}
+
+impl Default for LiquidityConfig {
+    fn default() -> Self {
+        LiquidityConfig { confirmation_blocks: 3, onchain_fee: 2000.0, swap_fee_ppm: 1000 }
+    }
+}
+
+impl LiquidityConfig {
+    // Function to check that the costs make sense.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.onchain_fee.is_finite() || self.onchain_fee < 0.0 {
+            return Err("onchain_fee must not be negative".to_string());
+        }
+        Ok(())
+    }
+
+    // Fee in msat of the swap service for an operation moving the given amount in msat.
+    pub fn swap_fee(&self, action: LiquidityAction, amount: u64) -> u64 {
+        match action {
+            LiquidityAction::LoopOut | LiquidityAction::LoopIn => (amount as u128 * self.swap_fee_ppm as u128 / PPM as u128) as u64,
+            LiquidityAction::SpliceIn | LiquidityAction::SpliceOut => 0,
+        }
+    }
+
+    // Cost in msat of an operation moving the given amount in msat.
+    pub fn cost(&self, action: LiquidityAction, amount: u64) -> u64 {
+        sat_to_msat(self.onchain_fee) + self.swap_fee(action, amount)
+    }
+}
+
+// Policy of a node that triggers a liquidity operation on its channels. Every interval, loop_in and
+// splice_in refill the channels whose local share of the capacity fell below the threshold up to the
+// target share, while loop_out and splice_out drain the channels whose local share rose above the
+// threshold down to the target share. A channel with an operation pending is left alone until it
+// confirms.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LiquidityAgent {
+    pub node: usize, // Id of the node in the snapshot, its index in the graph once resolved
+    pub action: LiquidityAction,
+    pub threshold: f64, // Local share of the capacity that triggers the operation
+    pub target: f64, // Local share of the capacity the operation brings the channel to
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: f64,
+}
+
+fn default_interval_secs() -> f64 {
+    600.0
+}
+
+impl LiquidityAgent {
+    // Function to check that the shares and the interval make sense.
+    pub fn validate(&self) -> Result<(), String> {
+        let shares_in_order = match self.action {
+            LiquidityAction::LoopIn | LiquidityAction::SpliceIn => self.threshold <= self.target,
+            LiquidityAction::LoopOut | LiquidityAction::SpliceOut => self.threshold >= self.target,
+        };
+        if !(0.0..1.0).contains(&self.threshold) || !(0.0..1.0).contains(&self.target) || !shares_in_order {
+            return Err(format!("threshold and target must be shares below one, with the target on the side {:?} moves the channel to", self.action));
+        }
+        if !self.interval_secs.is_finite() || self.interval_secs <= 0.0 {
+            return Err("interval_secs must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    // Function to compute the amount in msat the operation of the agent moves for a channel with the
+    // given local balance and capacity, zero if the channel does not trigger it. Splices change the
+    // capacity along with the local balance.
+    pub fn amount(&self, local: u64, capacity: u64) -> u64 {
+        if capacity == 0 {
+            return 0;
+        }
+        let (local, capacity) = (local as f64, capacity as f64);
+        let share = local / capacity;
+        let amount = match self.action {
+            LiquidityAction::LoopIn if share < self.threshold => self.target * capacity - local,
+            LiquidityAction::SpliceIn if share < self.threshold => (self.target * capacity - local) / (1.0 - self.target),
+            LiquidityAction::LoopOut if share > self.threshold => local - self.target * capacity,
+            LiquidityAction::SpliceOut if share > self.threshold => (local - self.target * capacity) / (1.0 - self.target),
+            _ => 0.0,
+        };
+        amount.max(0.0) as u64
+    }
+}
+
+// Structure to hold the operations carried out and their costs. Amounts and costs are in msat.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LiquidityReport {
+    pub operations: BTreeMap<LiquidityAction, u64>, // Confirmed operations of every kind
+    pub amount: u64, // Amount moved by the confirmed operations
+    pub costs: u64, // On-chain and swap fees of the confirmed operations
+    pub failed: u64, // Operations that could not start or whose channel closed before they confirmed
+}
+
+// Function to carry out a liquidity operation of a node on its channel to a peer. Funds leaving the
+// channel are taken from it when the operation starts, and funds entering it are added once the
+// transaction confirms, after the confirmation delay on the simulated chain. The peer acts as the swap
+// server: it takes the off-chain side of a swap and pays or receives its on-chain side, keeping the
+// swap fee, while the on-chain fee goes to the miners. On-chain funds are spent once the transaction
+// confirms, and the outputs it creates are spendable at once. Returns the cost of the operation in
+// msat, or the reason it failed.
+pub async fn execute(network: Arc<Network>, node: NodeIndex, peer: NodeIndex, action: LiquidityAction, amount: u64, config: LiquidityConfig) -> Result<u64, String> {
+    let edge = network.find_edge(node, peer).ok_or("No channel to the peer")?;
+    let reverse = network.find_edge(peer, node);
+    let cost = config.cost(action, amount);
+    let swap_fee = config.swap_fee(action, amount);
+    let onchain_fee = cost - swap_fee;
+    // Funds leaving the channel are taken out right away, as they are committed to the transaction.
+    match action {
+        LiquidityAction::LoopOut | LiquidityAction::SpliceOut => {
+            let mut attrs = network.channel(edge);
+            if !attrs.is_open() {
+                return Err("channel closed".to_string());
+            }
+            if amount < cost {
+                return Err("amount below the cost".to_string());
+            }
+            if attrs.balance < amount {
+                return Err("insufficient balance".to_string());
+            }
+            attrs.balance -= amount;
+        }
+        LiquidityAction::LoopIn | LiquidityAction::SpliceIn => {
+            if !network.channel(edge).is_open() {
+                return Err("channel closed".to_string());
+            }
+        }
+    }
+    let confirmed_at = network.chain().height() + config.confirmation_blocks;
+    sleep(network.chain().time_until(confirmed_at)).await;
+    let height = network.chain().height();
+    match action {
+        // The peer gets the amount paid off-chain and pays it back on chain less the swap fee, out of
+        // which the node receives what is left after the on-chain fee.
+        LiquidityAction::LoopOut => {
+            if let Some(reverse) = reverse {
+                network.credit(reverse, amount);
+            }
+            let mut onchain = network.onchain();
+            onchain.spend(peer, amount - swap_fee, height);
+            onchain.outputs.push(Output { owner: node, amount: amount - cost, spendable_at: height });
+            onchain.operation_fees += onchain_fee;
+        }
+        // The peer pays the amount off-chain from its side of the channel, as far as it can, against
+        // the on-chain payment of the node, and refunds on chain the part it could not pay.
+        LiquidityAction::LoopIn => {
+            let Some(reverse) = reverse else {
+                return Err("No channel from the peer".to_string());
+            };
+            let paid = {
+                let mut reverse = network.channel(reverse);
+                if reverse.closed {
+                    return Err("channel closed".to_string());
+                }
+                let paid = reverse.balance.min(amount);
+                reverse.balance -= paid;
+                paid
+            };
+            network.credit(edge, paid);
+            let mut onchain = network.onchain();
+            onchain.spend(node, amount + cost, height);
+            onchain.outputs.push(Output { owner: peer, amount: paid + swap_fee, spendable_at: height });
+            if paid < amount {
+                onchain.outputs.push(Output { owner: node, amount: amount - paid, spendable_at: height });
+            }
+            onchain.operation_fees += onchain_fee;
+        }
+        // The splice only adds the funds if the channel is still open, and nothing is spent otherwise.
+        LiquidityAction::SpliceIn => {
+            {
+                let mut attrs = network.channel(edge);
+                if attrs.closed {
+                    return Err("channel closed before the splice confirmed".to_string());
+                }
+                attrs.balance += amount;
+            }
+            let mut onchain = network.onchain();
+            onchain.spend(node, amount + cost, height);
+            onchain.operation_fees += onchain_fee;
+        }
+        LiquidityAction::SpliceOut => {
+            let mut onchain = network.onchain();
+            onchain.outputs.push(Output { owner: node, amount: amount - cost, spendable_at: height });
+            onchain.operation_fees += onchain_fee;
+        }
+    }
+    Ok(cost)
+}
+
+// Function to check the channels of the node of an agent and start the operations they trigger,
+// skipping the channels that already have one pending.
+fn trigger(network: &Arc<Network>, agent: &LiquidityAgent, config: LiquidityConfig, pending: &mut BTreeMap<EdgeIndex, JoinHandle<()>>, report: &Arc<Mutex<LiquidityReport>>) {
+    pending.retain(|_, handle| !handle.is_finished());
+    let node = NodeIndex::new(agent.node);
+    let channels: Vec<(EdgeIndex, NodeIndex)> = network.topology().edges(node).map(|edge| (edge.id(), edge.target())).collect();
+    for (edge, peer) in channels {
+        let attrs = network.attributes(edge);
+        if !attrs.is_open() || pending.contains_key(&edge) {
+            continue;
+        }
+        let local = attrs.balance + attrs.value_in_flight;
+        let remote = network.find_edge(peer, node).map_or(0, |reverse| {
+            let reverse = network.attributes(reverse);
+            reverse.balance + reverse.value_in_flight
+        });
+        // Operations moving no more than they cost are not worth it.
+        let amount = agent.amount(local, local + remote);
+        if amount <= config.cost(agent.action, amount) {
+            continue;
+        }
+        info!("Node {:?} started {:?} of {:?} msat on channel {:?}", node, agent.action, amount, (node, peer));
+        let (network, report, action) = (Arc::clone(network), Arc::clone(report), agent.action);
+        pending.insert(edge, tokio::spawn(async move {
+            let result = execute(network, node, peer, action, amount, config).await;
+            let mut report = report.lock().unwrap_or_else(PoisonError::into_inner);
+            match result {
+                Ok(cost) => {
+                    info!("{:?} of {:?} msat on channel {:?} confirmed for {:?} msat", action, amount, (node, peer), cost);
+                    *report.operations.entry(action).or_default() += 1;
+                    report.amount += amount;
+                    report.costs += cost;
+                }
+                Err(e) => {
+                    error!("{:?} of {:?} msat on channel {:?} failed: {}", action, amount, (node, peer), e);
+                    report.failed += 1;
+                }
+            }
+        }));
+    }
+}
+
+// Function to run the liquidity agents next to the payments. Every agent checks the channels of its
+// node at the start and then every interval. Once the stop flag is set, no new operation starts, and
+// the function returns after the pending ones have confirmed.
+pub async fn run_liquidity_agents(network: Arc<Network>, agents: Vec<LiquidityAgent>, config: LiquidityConfig, stopped: Arc<AtomicBool>) -> LiquidityReport {
+    let report = Arc::new(Mutex::new(LiquidityReport::default()));
+    let handles: Vec<_> = agents.into_iter()
+        .map(|agent| {
+            let (network, report, stopped) = (Arc::clone(&network), Arc::clone(&report), Arc::clone(&stopped));
+            tokio::spawn(async move {
+                let mut pending = BTreeMap::new();
+                let interval = Duration::from_secs_f64(agent.interval_secs);
+                let mut next = Instant::now();
+                while payment_scheduler::wait_until(next, &stopped).await {
+                    trigger(&network, &agent, config, &mut pending, &report);
+                    next += interval;
+                }
+                for (_, handle) in pending {
+                    let _ = handle.await;
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        let _ = handle.await;
+    }
+    let report = report.lock().unwrap_or_else(PoisonError::into_inner).clone();
+    if report.amount > 0 || report.failed > 0 {
+        info!(
+            "Liquidity operations: {:?} confirmed, moving {} sat for {} sat of costs, {} failed",
+            report.operations, msat_to_sat(report.amount), msat_to_sat(report.costs), report.failed
+        );
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_graph::Graph;
+    use crate::topology::new_channel;
+
+    // Channel between nodes 0 and 1 with the given balance on each side.
+    fn pair(balance: u64) -> Arc<Network> {
+        let mut graph = Graph::new();
+        let (a, b) = (graph.add_node(0), graph.add_node(1));
+        graph.add_edge(a, b, new_channel(balance, 1000, 1, 40));
+        graph.add_edge(b, a, new_channel(balance, 1000, 1, 40));
+        Arc::new(Network::new(graph))
+    }
+
+    // Funds in the channels and on chain, with the on-chain fees paid and less the funds deposited
+    // from outside the network, which stay the same through every operation.
+    fn total_funds(network: &Network) -> u64 {
+        let channels: u64 = network.topology().edge_indices().map(|edge| network.attributes(edge).balance).sum();
+        let onchain = network.onchain();
+        channels + onchain.outputs.iter().map(|output| output.amount).sum::<u64>() + onchain.operation_fees - onchain.deposits
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn operations_conserve_funds() {
+        let config = LiquidityConfig::default();
+        for action in [LiquidityAction::LoopOut, LiquidityAction::LoopIn, LiquidityAction::SpliceIn, LiquidityAction::SpliceOut] {
+            let network = pair(1_000_000_000);
+            let before = total_funds(&network);
+            let cost = execute(Arc::clone(&network), NodeIndex::new(0), NodeIndex::new(1), action, 100_000_000, config).await;
+            assert_eq!(cost, Ok(config.cost(action, 100_000_000)));
+            assert_eq!(total_funds(&network), before, "{:?} changed the funds", action);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn splice_in_fails_if_the_channel_closes_before_it_confirms() {
+        let network = pair(1_000_000_000);
+        let before = total_funds(&network);
+        let splice = tokio::spawn(execute(Arc::clone(&network), NodeIndex::new(0), NodeIndex::new(1), LiquidityAction::SpliceIn, 100_000_000, LiquidityConfig::default()));
+        tokio::task::yield_now().await;
+        let edge = network.find_edge(NodeIndex::new(1), NodeIndex::new(0)).unwrap();
+        assert!(network.force_close(edge, 0).is_some());
+        assert!(splice.await.unwrap().is_err());
+        assert_eq!(network.onchain().deposits, 0);
+        assert_eq!(total_funds(&network) + network.onchain().fees(), before);
+    }
+}
@@ -1,34 +1,263 @@
-mod create_graph;
-mod find_path;
-mod payment_router;
-mod payment_scheduler;
-
-use std::sync::{Arc, Mutex};
-use tokio;
-use tokio::time::{self, Duration};
+use lnsimulator::{AmountDistribution, EdgeAttributes, Network, Scenario, TraceSource, Workload};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tokio::time::Duration;
 use simplelog::*;
-use std::fs::File;
-use log::{info, error};
-
-#[tokio::main]
-async fn main() {
-    // Initialize the logger
-    WriteLogger::init(LevelFilter::Info, Config::default(), File::create("Path_to_log_file/sim.log").unwrap()).unwrap();
-    info!("Starting the program");
-    // Obtain the graph structure from the json file.
-    let graph = create_graph::convert_networkx_to_petgraph("Path_to_json_file/json_graph1.json");
-    // Convert the graph so that it can be shared across concurrent payments.
-    let graph_arc = Arc::new(Mutex::new(graph));
-    let graph_clone = Arc::clone(&graph_arc);
-    // Required simulation duration.
-    let simulation_duration = Duration::from_secs(10000);
-    // Use `timeout` to limit the scheduler's execution time to the simulation duration.
-    let result = time::timeout(simulation_duration, async {
-        payment_scheduler::schedule_payments(graph_clone).await;
-    }).await;
+use std::fs::{self, File};
+use clap::{Args, Parser, Subcommand};
+use petgraph::algo::connected_components;
+use petgraph::Direction;
 
+// Command-line interface of the simulator.
+#[derive(Debug, Parser)]
+#[command(name = "LNsimulator", version, about = "Simulator of payments in the Lightning Network")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    #[command(about = "Run a simulation on a graph snapshot")]
+    Run(Box<RunArgs>),
+    #[command(about = "Run a scenario over a grid of parameters with seeded replications")]
+    Sweep(SweepArgs),
+    #[command(about = "Print statistics of a graph snapshot")]
+    InspectGraph {
+        #[arg(long, help = "Graph snapshot in the networkx JSON or the CSV channel list format")]
+        graph: PathBuf,
+    },
+    #[command(about = "Find the path of a single payment between two nodes")]
+    Route(RouteArgs),
+    #[command(about = "Convert a graph snapshot between the networkx JSON and the CSV channel list formats")]
+    Convert {
+        #[arg(long, help = "Snapshot to read, a .csv file or a networkx JSON file")]
+        input: PathBuf,
+        #[arg(long, help = "Snapshot to write, a .csv file or a networkx JSON file")]
+        output: PathBuf,
+    },
+}
+
+// Settings of a run. Without a scenario file the default scenario is run, and every setting given
+// on the command line overrides the one of the scenario.
+#[derive(Debug, Args)]
+struct RunArgs {
+    #[arg(long, help = "TOML scenario file describing the experiment")]
+    scenario: Option<PathBuf>,
+    #[arg(long, help = "Graph snapshot in the networkx JSON or the CSV channel list format")]
+    graph: Option<PathBuf>,
+    #[arg(long, help = "Virtual time in seconds after which no new payment is started")]
+    duration: Option<u64>,
+    #[arg(long, help = "Number of payments after which no new payment is started")]
+    max_payments: Option<u64>,
+    #[arg(long, help = "Seed of every random decision of the simulation")]
+    seed: Option<u64>,
+    #[arg(long, help = "Fixed amount of every payment in satoshis, overriding the workload amounts")]
+    amount: Option<f64>,
+    #[arg(long, help = "Directory the log of the simulation is written to")]
+    output_dir: Option<PathBuf>,
+    #[arg(long, help = "JSON workload file (see workload.rs for the format)")]
+    workload: Option<PathBuf>,
+    #[arg(long, help = "CSV or JSON payment trace to replay instead of the workload")]
+    trace: Option<PathBuf>,
+    #[arg(long, default_value_t = 1.0, help = "Factor applied to the recorded times of the trace")]
+    trace_time_scale: f64,
+    #[arg(long, help = "JSON file of jamming attackers (see jamming.rs for the format)")]
+    attackers: Option<PathBuf>,
+    #[arg(long, help = "Enable HTLC endorsement and local reputation against jamming")]
+    endorsement: bool,
+    #[arg(long, help = "Virtual seconds between two blocks of the simulated chain")]
+    block_interval: Option<f64>,
+    #[arg(long, help = "JSON script of channel opens and closes during the run (see topology.rs for the format)")]
+    topology_script: Option<PathBuf>,
+    #[arg(long, help = "Let senders find paths on gossip views that learn of channel updates after this mean delay in seconds")]
+    gossip_delay: Option<f64>,
+}
+
+impl RunArgs {
+    // Function to build the scenario of the run from the scenario file and the command line.
+    fn scenario(self) -> Result<Scenario, String> {
+        let mut scenario = match &self.scenario {
+            Some(path) => Scenario::load(path)?,
+            None => Scenario::default(),
+        };
+        if let Some(graph) = self.graph {
+            scenario.graph.path = graph;
+        }
+        if let Some(duration) = self.duration {
+            scenario.stop.max_duration = Some(Duration::from_secs(duration));
+        }
+        if let Some(max_payments) = self.max_payments {
+            scenario.stop.max_payments = Some(max_payments);
+        }
+        if let Some(seed) = self.seed {
+            scenario.seed = seed;
+        }
+        if let Some(output_dir) = self.output_dir {
+            scenario.output.dir = output_dir;
+        }
+        if let Some(path) = self.workload {
+            scenario.workload = Workload::load(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        if let Some(amount) = self.amount {
+            scenario.workload.amounts = AmountDistribution::Fixed { amount };
+        }
+        if let Some(path) = self.trace {
+            scenario.trace = Some(TraceSource { path, time_scale: self.trace_time_scale });
+        }
+        if let Some(path) = self.attackers {
+            scenario.attackers = lnsimulator::load_attackers(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        if self.endorsement {
+            scenario.endorsement.enabled = true;
+        }
+        if let Some(secs) = self.block_interval {
+            scenario.chain.block_interval = Duration::try_from_secs_f64(secs).map_err(|_| "--block-interval must be a non-negative number of seconds".to_string())?;
+        }
+        if let Some(path) = self.topology_script {
+            scenario.topology.script = Some(path);
+        }
+        if let Some(secs) = self.gossip_delay {
+            scenario.gossip.enabled = true;
+            scenario.gossip.delay_secs = secs;
+        }
+        scenario.validate()?;
+        Ok(scenario)
+    }
+}
+
+// Settings of a sweep. The grid is read from the [sweep] section of the scenario, and a scenario
+// without one runs the replications of the scenario alone.
+#[derive(Debug, Args)]
+struct SweepArgs {
+    #[arg(long, help = "TOML scenario file describing the experiment and its sweep")]
+    scenario: PathBuf,
+    #[arg(long, help = "Number of seeded runs of every combination of parameters")]
+    replications: Option<u32>,
+    #[arg(long, help = "Number of runs in parallel, by default one per available core")]
+    threads: Option<usize>,
+    #[arg(long, help = "Directory the results of the sweep are written to")]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct RouteArgs {
+    #[arg(long, help = "Graph snapshot in the networkx JSON or the CSV channel list format")]
+    graph: PathBuf,
+    #[arg(long, help = "Id of the sender in the snapshot")]
+    from: usize,
+    #[arg(long, help = "Id of the recipient in the snapshot")]
+    to: usize,
+    #[arg(long, default_value_t = 1000.0, help = "Amount to deliver in satoshis")]
+    amount: f64,
+}
+
+// The simulation runs on a single thread with a paused clock that only advances when every payment
+// is waiting, so that the order of events does not depend on the machine. Together with the seed,
+// this makes runs reproducible. A run therefore uses a single core, and only the runs of a sweep
+// spread over several.
+#[tokio::main(flavor = "current_thread", start_paused = true)]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Run(args) => match args.scenario() {
+            Ok(scenario) => run(scenario).await,
+            Err(e) => Err(e),
+        },
+        Command::Sweep(args) => sweep(args),
+        Command::InspectGraph { graph } => inspect_graph(graph),
+        Command::Route(args) => route(args),
+        Command::Convert { input, output } => convert(input, output),
+    };
     match result {
-        Ok(_) => info!("Scheduler completed successfully"),
-        Err(_) => info!("Scheduler timed out"),
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Function to run the simulation of a scenario and log its progress and final statistics to the
+// output directory.
+async fn run(scenario: Scenario) -> Result<(), String> {
+    // Initialize the logger. The wall-clock time is left out of the log so that runs with the same
+    // seed produce identical logs.
+    let output_dir = &scenario.output.dir;
+    fs::create_dir_all(output_dir).map_err(|e| format!("{}: {}", output_dir.display(), e))?;
+    let log_config = ConfigBuilder::new().set_time_level(LevelFilter::Off).build();
+    let log_file = File::create(output_dir.join("sim.log")).map_err(|e| e.to_string())?;
+    WriteLogger::init(LevelFilter::Info, log_config, log_file).map_err(|e| e.to_string())?;
+    lnsimulator::simulate(scenario).await?;
+    Ok(())
+}
+
+// Function to run the sweep of a scenario, print the table of results and write it to the output
+// directory. The runs of the sweep are not logged.
+fn sweep(args: SweepArgs) -> Result<(), String> {
+    let mut scenario = Scenario::load(&args.scenario)?;
+    let mut sweep = scenario.sweep.take().unwrap_or_default();
+    if let Some(replications) = args.replications {
+        sweep.replications = replications;
+    }
+    if let Some(threads) = args.threads {
+        sweep.threads = Some(threads);
+    }
+    if let Some(output_dir) = args.output_dir {
+        scenario.output.dir = output_dir;
+    }
+    scenario.validate()?;
+    let report = lnsimulator::run_sweep(&scenario, &sweep).map_err(|e| format!("sweep: {}", e))?;
+    print!("{}", report);
+    report.write(&scenario.output.dir)
+}
+
+// Function to print the size, connectivity, liquidity and fee policies of a snapshot.
+fn inspect_graph(path: PathBuf) -> Result<(), String> {
+    let graph = lnsimulator::load_graph(&path)?;
+    let channels = graph.edge_count();
+    println!("Nodes: {}", graph.node_count());
+    println!("Channel directions: {}", channels);
+    println!("Connected components: {}", connected_components(&graph));
+    let isolated = graph.node_indices().filter(|&node| graph.neighbors_undirected(node).next().is_none()).count();
+    println!("Isolated nodes: {}", isolated);
+    let max_degree = graph.node_indices().map(|node| graph.neighbors_directed(node, Direction::Outgoing).count()).max().unwrap_or(0);
+    println!("Maximum out-degree: {}", max_degree);
+    if channels == 0 {
+        return Ok(());
     }
+    let mean = |value: fn(&EdgeAttributes) -> f64| graph.edge_weights().map(value).sum::<f64>() / channels as f64;
+    let total: u64 = graph.edge_weights().map(|attrs| attrs.balance).sum();
+    println!("Total balance: {} sat", lnsimulator::msat_to_sat(total));
+    println!("Mean balance: {} sat", mean(|attrs| lnsimulator::msat_to_sat(attrs.balance)));
+    println!("Mean base fee: {} msat", mean(|attrs| attrs.basefee as f64));
+    println!("Mean fee rate: {} ppm", mean(|attrs| attrs.feerate as f64));
+    println!("Mean delay: {}", mean(|attrs| attrs.delay as f64));
+    Ok(())
+}
+
+// Function to print the path a payment between two nodes would take, with its fees and timelocks.
+fn route(args: RouteArgs) -> Result<(), String> {
+    let graph = lnsimulator::load_graph(&args.graph)?;
+    let sender = lnsimulator::node_index(&graph, args.from).ok_or(format!("Node {} is not in the graph", args.from))?;
+    let recipient = lnsimulator::node_index(&graph, args.to).ok_or(format!("Node {} is not in the graph", args.to))?;
+    let ids: Vec<usize> = graph.node_weights().cloned().collect();
+    let (path, timelocks, amounts) = lnsimulator::dijkstra(&Network::new(graph), recipient, sender, lnsimulator::sat_to_msat(args.amount));
+    if path.is_empty() {
+        return Err(format!("No path found from {} to {} for {} sat", args.from, args.to, args.amount));
+    }
+    let hops: Vec<usize> = path.iter().map(|node| ids[node.index()]).collect();
+    println!("Path: {:?}", hops);
+    println!("Amounts (msat): {:?}", amounts);
+    println!("CLTV expiries (blocks): {:?}", timelocks);
+    println!("Fee: {} msat", amounts[0] - amounts[amounts.len() - 1]);
+    Ok(())
+}
+
+// Function to convert a snapshot from one format to the other, as decided by the file extensions.
+fn convert(input: PathBuf, output: PathBuf) -> Result<(), String> {
+    let graph = lnsimulator::load_graph(&input)?;
+    lnsimulator::write_graph(&graph, &output)?;
+    println!("Wrote {} nodes and {} channel directions to {}", graph.node_count(), graph.edge_count(), output.display());
+    Ok(())
 }
@@ -0,0 +1,311 @@
+use petgraph::graph::{DiGraph, EdgeIndex, NodeIndex};
+use std::collections::BTreeSet;
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard};
+use crate::chain::{Chain, ChainConfig, ForceClose, OnChain, Output};
+use crate::gossip::{ChannelPolicy, Gossip, View};
+use crate::create_graph::{EdgeAttributes, Graph};
+
+// Network shared by the concurrent payments. The topology only grows during a run, as nodes join
+// and channels open, so it sits behind a read-write lock that path finding holds for a search and
+// the topology changes take briefly. The state of every channel direction sits behind its own lock
+// in an append-only list, so that path finding only holds the lock of the channel it is looking at,
+// and HTLC updates on different channels never wait for each other. The network also carries the
+// clock of the chain, against which the timelocks of the HTLCs are set and expire, and the funds sent
+// on chain by the channels closed during the run. Closed channels stay in the topology, marked as
+// closed, so that the edge indices of the payments in flight remain valid, and a channel reopened
+// between the same nodes takes the place of the closed one. Nodes that leave stay in the topology as
+// well, and are marked as departed so that no payment is sent from or to them. With gossip, the opens,
+// closes and policy changes of the channels are broadcast to the views the senders find paths on.
+pub struct Network {
+    topology: RwLock<DiGraph<usize, ()>>, // Id of every node in the snapshot and the channel directions
+    channels: boxcar::Vec<Mutex<EdgeAttributes>>, // State of every channel direction, by edge index
+    chain: Chain,
+    onchain: Mutex<OnChain>,
+    departed: Mutex<BTreeSet<NodeIndex>>, // Nodes that left the network
+    gossip: Option<Gossip>,
+}
+
+impl Network {
+    // Function to share a graph between concurrent payments, with a chain of the default settings.
+    pub fn new(graph: Graph) -> Self {
+        Network::with_chain(graph, ChainConfig::default())
+    }
+
+    // Function to share a graph between concurrent payments, starting the chain clock now.
+    pub fn with_chain(graph: Graph, chain: ChainConfig) -> Self {
+        let topology = RwLock::new(graph.map(|_, &id| id, |_, _| ()));
+        let channels = graph.edge_weights().map(|&attrs| Mutex::new(attrs)).collect();
+        Network { topology, channels, chain: Chain::new(chain), onchain: Mutex::new(OnChain::default()), departed: Mutex::new(BTreeSet::new()), gossip: None }
+    }
+
+    // Function to let the senders find paths on their gossip views instead of the live network.
+    pub fn with_gossip(mut self, gossip: Gossip) -> Self {
+        self.gossip = Some(gossip);
+        self
+    }
+
+    // Clock of the chain the channels are anchored in.
+    pub fn chain(&self) -> &Chain {
+        &self.chain
+    }
+
+    // Function to read the topology of the network, with the id of every node in the snapshot. The
+    // topology cannot change while it is read, so the guard must not be held across an await.
+    pub fn topology(&self) -> RwLockReadGuard<'_, DiGraph<usize, ()>> {
+        self.topology.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Number of nodes and of channel directions in the network.
+    pub fn node_count(&self) -> usize {
+        self.topology().node_count()
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.topology().edge_count()
+    }
+
+    // Function to find the channel direction from one node to another.
+    pub fn find_edge(&self, from: NodeIndex, to: NodeIndex) -> Option<EdgeIndex> {
+        self.topology().find_edge(from, to)
+    }
+
+    // Function to lock the gossip view of a node, if the senders find paths on their views.
+    pub fn gossip_view(&self, node: NodeIndex) -> Option<MutexGuard<'_, View>> {
+        self.gossip.as_ref().map(|gossip| gossip.view(node))
+    }
+
+    // Gossip of the network, if enabled.
+    pub fn gossip(&self) -> Option<&Gossip> {
+        self.gossip.as_ref()
+    }
+
+    // Function to broadcast the current policy of a channel direction to the gossip views, as a
+    // channel_announcement or channel_update. It must not be called with a channel locked.
+    pub fn announce(&self, edge: EdgeIndex) {
+        if let Some(gossip) = &self.gossip {
+            let policy = ChannelPolicy::of(&self.channel(edge));
+            gossip.broadcast(edge, policy);
+        }
+    }
+
+    // Function to change the forwarding policy of a channel direction and announce it in a
+    // channel_update if it changed. Returns whether it changed.
+    pub fn set_policy(&self, edge: EdgeIndex, basefee: u64, feerate: u64) -> bool {
+        {
+            let mut attrs = self.channel(edge);
+            if attrs.basefee == basefee && attrs.feerate == feerate {
+                return false;
+            }
+            attrs.basefee = basefee;
+            attrs.feerate = feerate;
+        }
+        self.announce(edge);
+        true
+    }
+
+    // Function to find the node with the given id in the snapshot.
+    pub fn node_index(&self, id: usize) -> Option<NodeIndex> {
+        let topology = self.topology();
+        topology.node_indices().find(|&node| topology[node] == id)
+    }
+
+    // Function to mark a node as having left the network. Returns whether it had not left before.
+    pub fn depart(&self, node: NodeIndex) -> bool {
+        self.departed.lock().unwrap_or_else(PoisonError::into_inner).insert(node)
+    }
+
+    // Nodes that left the network.
+    pub fn departed(&self) -> BTreeSet<NodeIndex> {
+        self.departed.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    // Function to add a node with a new id to the network, without any channel.
+    pub fn add_node(&self, id: usize) -> Result<NodeIndex, String> {
+        let mut topology = self.topology.write().unwrap_or_else(PoisonError::into_inner);
+        if topology.node_weights().any(|&node| node == id) {
+            return Err(format!("Node {} is already in the network", id));
+        }
+        Ok(topology.add_node(id))
+    }
+
+    // Function to open a channel between two nodes, with the state of both of its directions. A
+    // channel that was closed between the same nodes is replaced once none of its HTLCs is left to
+    // resolve on chain, so that there is a single edge in every direction.
+    pub fn open_channel(&self, from: NodeIndex, to: NodeIndex, forward: EdgeAttributes, backward: EdgeAttributes) -> Result<(), String> {
+        let mut topology = self.topology.write().unwrap_or_else(PoisonError::into_inner);
+        if from == to || from.index() >= topology.node_count() || to.index() >= topology.node_count() {
+            return Err(format!("Cannot open a channel from {:?} to {:?}", from, to));
+        }
+        let directions = [(from, to, forward), (to, from, backward)];
+        for &(start, end, _) in &directions {
+            if let Some(edge) = topology.find_edge(start, end) {
+                let attrs = self.channel(edge);
+                if !attrs.closed || attrs.pending_htlcs > 0 {
+                    return Err(format!("Channel from {:?} to {:?} is still open or resolving HTLCs", start, end));
+                }
+            }
+        }
+        for (start, end, attrs) in directions {
+            let edge = match topology.find_edge(start, end) {
+                Some(edge) => {
+                    *self.channel(edge) = attrs;
+                    edge
+                }
+                None => {
+                    // The edge and its state are added under the write lock of the topology, so
+                    // that their indices stay the same.
+                    self.channels.push(Mutex::new(attrs));
+                    topology.add_edge(start, end, ())
+                }
+            };
+            self.announce(edge);
+        }
+        Ok(())
+    }
+
+    // Function to start the cooperative close of a channel. Both directions stop taking new HTLCs
+    // right away, and the channel closes once the HTLCs in flight have resolved.
+    pub fn start_close(&self, from: NodeIndex, to: NodeIndex) -> Result<(), String> {
+        let edges = [self.find_edge(from, to), self.find_edge(to, from)];
+        if edges.iter().flatten().all(|&edge| !self.channel(edge).is_open()) {
+            return Err(format!("No open channel between {:?} and {:?}", from, to));
+        }
+        for edge in edges.into_iter().flatten() {
+            self.channel(edge).closing = true;
+        }
+        Ok(())
+    }
+
+    // Function to finish the cooperative close of a channel once no HTLC is in flight in either
+    // direction. The balances of both nodes become outputs on chain that they can spend at once.
+    // Returns whether the channel is closed.
+    pub fn finish_close(&self, from: NodeIndex, to: NodeIndex) -> bool {
+        let height = self.chain.height();
+        let balances = match (self.find_edge(from, to), self.find_edge(to, from)) {
+            (Some(forward), Some(backward)) => {
+                let (mut forward, mut backward) = self.channel_pair(forward, backward);
+                if forward.pending_htlcs > 0 || backward.pending_htlcs > 0 {
+                    return false;
+                }
+                forward.closed = true;
+                backward.closed = true;
+                [(from, std::mem::take(&mut forward.balance)), (to, std::mem::take(&mut backward.balance))]
+            }
+            (Some(edge), None) | (None, Some(edge)) => {
+                let mut attrs = self.channel(edge);
+                if attrs.pending_htlcs > 0 {
+                    return false;
+                }
+                attrs.closed = true;
+                let owner = if self.find_edge(from, to) == Some(edge) { from } else { to };
+                [(owner, std::mem::take(&mut attrs.balance)), (owner, 0)]
+            }
+            (None, None) => return true,
+        };
+        let mut onchain = self.onchain();
+        for (owner, amount) in balances.into_iter().filter(|&(_, amount)| amount > 0) {
+            onchain.outputs.push(Output { owner, amount, spendable_at: height });
+        }
+        drop(onchain);
+        for edge in [self.find_edge(from, to), self.find_edge(to, from)].into_iter().flatten() {
+            self.announce(edge);
+        }
+        true
+    }
+
+    // Function to lock the state of a channel direction. A lock poisoned by a panicking payment
+    // still holds a consistent state, as every update completes before the lock is released.
+    pub fn channel(&self, edge: EdgeIndex) -> MutexGuard<'_, EdgeAttributes> {
+        self.channels[edge.index()].lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Function to lock the states of two different channel directions together. The locks are always
+    // taken in the order of the edge indices, so that two payments locking the same pair of channels
+    // cannot deadlock.
+    pub fn channel_pair(&self, first: EdgeIndex, second: EdgeIndex) -> (MutexGuard<'_, EdgeAttributes>, MutexGuard<'_, EdgeAttributes>) {
+        if first < second {
+            let first = self.channel(first);
+            (first, self.channel(second))
+        } else {
+            let second = self.channel(second);
+            (self.channel(first), second)
+        }
+    }
+
+    // Function to copy the current state of a channel direction.
+    pub fn attributes(&self, edge: EdgeIndex) -> EdgeAttributes {
+        *self.channel(edge)
+    }
+
+    // Function to lock the funds sent on chain by the closed channels.
+    pub fn onchain(&self) -> MutexGuard<'_, OnChain> {
+        self.onchain.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Function to credit funds to the node at the start of a channel direction. While the channel is
+    // open they are added to its balance. Once it is closed they are resolved on chain, as an output
+    // the node can spend after the to_self_delay.
+    pub fn credit(&self, edge: EdgeIndex, amount: u64) {
+        let closed = {
+            let mut attrs = self.channel(edge);
+            if !attrs.closed {
+                attrs.balance += amount;
+            }
+            attrs.closed
+        };
+        if closed && amount > 0 {
+            if let Some((owner, _)) = self.topology().edge_endpoints(edge) {
+                let spendable_at = self.chain.height() + self.chain.config().to_self_delay;
+                self.onchain().outputs.push(Output { owner, amount, spendable_at });
+            }
+        }
+    }
+
+    // Function to force-close the channel of a channel direction on chain, on behalf of the node at
+    // its start. Both directions are marked as closed and their balances become outputs on chain: the
+    // closer pays the on-chain fee and waits for the to_self_delay before spending its funds, while
+    // its peer can spend its own at once. HTLCs still in flight over the channel are resolved on chain
+    // as they settle or fail. Nothing happens if the channel is already closed.
+    pub fn force_close(&self, edge: EdgeIndex, payment_id: u64) -> Option<ForceClose> {
+        let (closer, peer) = self.topology().edge_endpoints(edge)?;
+        let config = self.chain.config();
+        let height = self.chain.height();
+        let (closer_funds, peer_funds) = match self.find_edge(peer, closer) {
+            Some(reverse) => {
+                let (mut attrs, mut reverse) = self.channel_pair(edge, reverse);
+                if attrs.closed {
+                    return None;
+                }
+                attrs.closed = true;
+                reverse.closed = true;
+                (std::mem::take(&mut attrs.balance), std::mem::take(&mut reverse.balance))
+            }
+            None => {
+                let mut attrs = self.channel(edge);
+                if attrs.closed {
+                    return None;
+                }
+                attrs.closed = true;
+                (std::mem::take(&mut attrs.balance), 0)
+            }
+        };
+        let fee = config.force_close_fee.min(closer_funds);
+        let close = ForceClose { closer, peer, payment_id, height, fee };
+        let mut onchain = self.onchain();
+        onchain.outputs.push(Output { owner: closer, amount: closer_funds - fee, spendable_at: height + config.to_self_delay });
+        onchain.outputs.push(Output { owner: peer, amount: peer_funds, spendable_at: height });
+        onchain.force_closes.push(close);
+        drop(onchain);
+        for edge in [Some(edge), self.find_edge(peer, closer)].into_iter().flatten() {
+            self.announce(edge);
+        }
+        Some(close)
+    }
+
+    // Function to copy the open channels of the network back into a graph, with the current state of
+    // every channel. The channels are locked one at a time, so the copy is only consistent once no
+    // payment is in flight.
+    pub fn snapshot(&self) -> Graph {
+        self.topology().filter_map(|_, &id| Some(id), |edge, _| Some(self.attributes(edge)).filter(|attrs| !attrs.closed))
+    }
+}
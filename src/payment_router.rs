@@ -1,30 +1,117 @@
-use petgraph::graph::{NodeIndex,DiGraph};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use petgraph::graph::{NodeIndex,EdgeIndex};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 use log::{error, info};
-use crate::create_graph;
-use create_graph::EdgeAttributes;
+use crate::chain::MIN_FINAL_CLTV_EXPIRY_DELTA;
+use crate::create_graph::EdgeAttributes;
+use crate::network::Network;
+use crate::reputation::{self, EndorsementConfig};
 use tokio::time::{sleep};
 
+// Behaviour of the recipient once the HTLC reaches it. An honest recipient settles the payment by
+// sharing the secret key, while a jamming recipient either holds the HTLC for some time before
+// failing it or fails it as soon as it arrives.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecipientBehaviour {
+    Settle,
+    Hold(Duration),
+    Reject,
+}
+
 // Define the payment structure.
 pub struct Payment {
     payment_id: u64, // Payment ID
     path: Vec<NodeIndex>, // Payment path
-    timelocks: Vec<f32>, // Timelocks of every node in the path
-    amounts: Vec<f32>, // Amounts at every node in the path
+    timelocks: Vec<u32>, // CLTV expiries in blocks of the HTLC offered by every node in the path
+    amounts: Vec<u64>, // Amounts in msat at every node in the path
     lock_status: Vec<bool>, // Tracker of which nodes in the path have locked
     lock_time: Vec<Option<Instant>>, // Tracker of the time of locking for every node in the path
     secret_key_status: Vec<bool>, // Tracker of whether a node in the path has the secret key
     secret_key_received_time: Vec<Option<Instant>>, // Tracker of the time of receiving the secret
                                                     // key for every node in the path
     unlock_status: Vec<bool>, // Tracker of whether a node has unlocked its locked funds
-    timelock_expired: Vec<bool>, // Tracker of whether the timelock of a node has expired
+    timelock_expired: Vec<bool>, // Tracker of whether the HTLC offered by a node has expired
+    failed_status: Vec<bool>, // Tracker of whether the locked funds of a node were failed back
+    failed_at: Option<usize>, // Index of the node in the path that failed the HTLC, if any
+    failure: Option<String>, // Reason the HTLC was failed, if it was
+    recipient: RecipientBehaviour, // Behaviour of the recipient on receiving the HTLC
+    sender_endorsed: bool, // Whether the sender endorses the HTLC
+    endorsed: Vec<bool>, // Tracker of whether the HTLC offered by a node is endorsed
+    general_bucket: Vec<bool>, // Tracker of whether the HTLC offered by a node occupies the
+                               // general bucket of the channel
+    upfront_fees: Vec<u64>, // Upfront fees in msat paid by every node in the path to the next node
+    hold_fees: Vec<u64>, // Hold fees in msat paid by every node in the path to the next node
+    hold_fee_shortfalls: Vec<u64>, // Hold fees in msat every node owed but could not pay from its balance
+    hold_times: Vec<Duration>, // Time for which every node in the path had its funds locked
+}
+
+// Function to add an HTLC to a channel if the balance and the HTLC limits of the channel allow it.
+// Otherwise the reason for rejecting the HTLC is returned. The balance also has to cover the upfront
+// fee, which leaves the balance for good once the HTLC is added. A channel that is closed or being
+// closed takes no HTLC.
+fn add_htlc(attrs: &mut EdgeAttributes, amount: u64, upfront_fee: u64) -> Result<(), String> {
+    if !attrs.is_open() {
+        return Err("channel closed".to_string());
+    }
+    let reason = if attrs.balance < amount + upfront_fee {
+        Some("insufficient balance")
+    } else if attrs.pending_htlcs >= attrs.max_accepted_htlcs {
+        Some("max_accepted_htlcs reached")
+    } else if attrs.value_in_flight.saturating_add(amount) > attrs.max_htlc_value_in_flight {
+        Some("max_htlc_value_in_flight reached")
+    } else {
+        None
+    };
+    if let Some(reason) = reason {
+        attrs.rejected_htlcs += 1;
+        return Err(reason.to_string());
+    }
+    attrs.balance -= amount + upfront_fee;
+    attrs.pending_htlcs += 1;
+    attrs.value_in_flight += amount;
+    Ok(())
+}
+
+// Function to check the CLTV expiry of an HTLC a node is asked to forward, as in BOLT 4. The
+// incoming HTLC has to expire at least the cltv_expiry_delta of the outgoing channel after the
+// outgoing one, and the outgoing HTLC must not have expired already.
+fn check_cltv_expiry(attrs: &EdgeAttributes, incoming_expiry: u32, outgoing_expiry: u32, height: u32) -> Result<(), String> {
+    if incoming_expiry < outgoing_expiry.saturating_add(attrs.delay) {
+        Err("incorrect cltv expiry".to_string())
+    } else if outgoing_expiry <= height {
+        Err("expiry too soon".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+// Function to check that the HTLC a node received covers the amount it is asked to offer over its
+// outgoing channel plus the fee of that channel, as in BOLT 4. A sender that found its path on an
+// outdated view of the policy of the channel may offer too little.
+fn check_fee(attrs: &EdgeAttributes, incoming_amount: u64, outgoing_amount: u64) -> Result<(), String> {
+    if incoming_amount < outgoing_amount.saturating_add(attrs.fee(outgoing_amount)) {
+        Err("fee insufficient".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+// Function to remove a settled or failed HTLC from a channel, freeing its slot and its share of the
+// value in flight, including its share of the general bucket if it occupied it.
+fn remove_htlc(attrs: &mut EdgeAttributes, amount: u64, general: bool) {
+    attrs.pending_htlcs = attrs.pending_htlcs.saturating_sub(1);
+    attrs.value_in_flight = attrs.value_in_flight.saturating_sub(amount);
+    if general {
+        attrs.reputation.general_htlcs = attrs.reputation.general_htlcs.saturating_sub(1);
+        attrs.reputation.general_in_flight = attrs.reputation.general_in_flight.saturating_sub(amount);
+    }
 }
 
 // Functions related to a payment
 impl Payment {
     // Initialize a new Payment with the values returned by the path finding algorithm.
-    pub fn new(payment_id: u64, path: Vec<NodeIndex>, timelocks: Vec<f32>, amounts: Vec<f32>) -> Self {
+    pub fn new(payment_id: u64, path: Vec<NodeIndex>, timelocks: Vec<u32>, amounts: Vec<u64>) -> Self {
         Payment {
             payment_id,
             path: path.clone(),
@@ -35,80 +122,339 @@ impl Payment {
             secret_key_status: vec![false; path.len()],
             secret_key_received_time: vec![None; path.len()],
             unlock_status:vec![false; path.len()],
-            timelock_expired: vec![false; path.len()]
+            timelock_expired: vec![false; path.len()],
+            failed_status: vec![false; path.len()],
+            failed_at: None,
+            failure: None,
+            recipient: RecipientBehaviour::Settle,
+            sender_endorsed: true,
+            endorsed: vec![false; path.len()],
+            general_bucket: vec![false; path.len()],
+            upfront_fees: vec![0; path.len()],
+            hold_fees: vec![0; path.len()],
+            hold_fee_shortfalls: vec![0; path.len()],
+            hold_times: vec![Duration::ZERO; path.len()]
+        }
+    }
+
+    // Set whether the sender endorses the HTLC. Senders endorse their payments by default.
+    pub fn set_endorsed(&mut self, endorsed: bool) {
+        self.sender_endorsed = endorsed;
+    }
+
+    // Set how the recipient treats the HTLC once it arrives. Payments settle by default.
+    pub fn set_recipient_behaviour(&mut self, behaviour: RecipientBehaviour) {
+        self.recipient = behaviour;
+    }
+
+    // Index of the node in the path that failed the HTLC, if the payment was failed back.
+    pub fn failed_at(&self) -> Option<usize> {
+        self.failed_at
+    }
+
+    // Reason the HTLC was failed, if it was. Payments whose timelocks expired have no reason.
+    pub fn failure(&self) -> Option<&str> {
+        self.failure.as_deref()
+    }
+
+    // Path of the payment, from the sender to the recipient.
+    pub fn path(&self) -> &[NodeIndex] {
+        &self.path
+    }
+
+    // CLTV expiries in blocks of the HTLC offered by every node in the path. The last entry is the
+    // expiry the recipient receives.
+    pub fn timelocks(&self) -> &[u32] {
+        &self.timelocks
+    }
+
+    // Amounts in msat of the HTLC every node in the path offers to the next one, including the fees
+    // of the forwarding nodes after it. The last entry is the amount the recipient receives.
+    pub fn amounts(&self) -> &[u64] {
+        &self.amounts
+    }
+
+    // Upfront fees in msat paid by every node in the path to the next node.
+    pub fn upfront_fees(&self) -> &[u64] {
+        &self.upfront_fees
+    }
+
+    // Hold fees in msat paid by every node in the path to the next node.
+    pub fn hold_fees(&self) -> &[u64] {
+        &self.hold_fees
+    }
+
+    // Hold fees in msat every node in the path owed to the next node but could not pay, as its
+    // balance in the channel did not cover them.
+    pub fn hold_fee_shortfalls(&self) -> &[u64] {
+        &self.hold_fee_shortfalls
+    }
+
+    // Time for which every node in the path had its funds locked.
+    pub fn hold_times(&self) -> &[Duration] {
+        &self.hold_times
+    }
+
+    // Upfront and hold fees in msat paid by the sender. Unlike the forwarding fees included in the
+    // amounts, these are paid whether or not the payment succeeds.
+    pub fn upfront_and_hold_fees(&self) -> u64 {
+        self.upfront_fees.first().unwrap_or(&0) + self.hold_fees.first().unwrap_or(&0)
+    }
+
+    // Function to compute the upfront fee the node at node_index pays to the next node, which covers
+    // the upfront fees of every channel from there on to the recipient.
+    fn upfront_fee(network: &Network, payment: &Payment, node_index: usize) -> u64 {
+        (node_index..payment.path.len() - 1)
+            .filter_map(|i| network.find_edge(payment.path[i], payment.path[i + 1]))
+            .map(|edge| network.channel(edge).upfrontfee)
+            .sum()
+    }
+
+    // Function to move a fee paid by the node at node_index to the next node across their channel.
+    fn pay_fee(network: &Network, payment: &Payment, node_index: usize, fee: u64) {
+        if let Some(edge) = network.find_edge(payment.path[node_index + 1], payment.path[node_index]) {
+            network.credit(edge, fee);
         }
     }
 
-    // Function to check if a node can lock funds.
-    pub async fn lock_funds(graph: Arc<Mutex<DiGraph<usize, EdgeAttributes>>>, payment: &mut Payment, node_index: usize, amount: f32) -> Result<(), String> {
-        // Obtain lock on the shared graph to do the locking process
-        let guard = graph.lock().map_err(|e| e.to_string())?;
+    // Function to check if a node can lock funds. Only the channels the HTLC crosses are locked,
+    // one at a time, so that payments over other channels go on meanwhile.
+    pub async fn lock_funds(network: Arc<Network>, payment: &mut Payment, node_index: usize, amount: u64, config: EndorsementConfig) -> Result<(), String> {
         if node_index + 1 >= payment.path.len() {
             return Err("Invalid node index".to_string());
         }
-        // Check if the node has sufficient balance to lock. If yes, then lock and set the lock
-        // status.
-        if let Some(edge) = guard.find_edge(payment.path[node_index], payment.path[node_index + 1]){
-            let mut attrs = guard[edge];
-
-            if attrs.balance < amount {
-                info!("Insufficient balance at node {:?} for payment id {:?}", payment.path[node_index],payment.payment_id);
-            }else{
-                attrs.balance -= amount;
-                payment.lock_status[node_index] = true;
-                payment.lock_time[node_index] = Some(Instant::now());
-                info!("Locked amount by node {:?} for payment id {:?}", payment.path[node_index], payment.payment_id);
-                // If this is the penultimate node, then by locking it automatically notifies
-                // the recipient of the payment and the recipient shares the secret key with this
-                // node and the secret key status is updated accordingly.
-                if node_index == payment.path.len() - 2 {
-                    // Set secret key received for the last node
-                    payment.secret_key_status[payment.path.len() - 1] = true;
-                    payment.secret_key_received_time[payment.path.len() - 1] = Some(Instant::now());
-                    payment.secret_key_status[payment.path.len() - 2] = true;
-                    payment.secret_key_received_time[payment.path.len() - 2] = Some(Instant::now());
-                    info!("Secret shared by recipient {:?} for payment id {:?}", payment.path[payment.path.len() - 1], payment.payment_id);
-                    info!("Secret received by node {:?} for payment id {:?}", payment.path[payment.path.len() - 2], payment.payment_id);
+        // The HTLC only reaches the node once it has crossed the incoming channel. Until then, the
+        // payment manager keeps retrying.
+        if node_index > 0 {
+            if let (Some(incoming), Some(locked)) = (network.find_edge(payment.path[node_index - 1], payment.path[node_index]), payment.lock_time[node_index - 1]) {
+                if locked.elapsed() < network.channel(incoming).latency {
+                    return Ok(());
+                }
+            }
+        }
+        // Check if the node has sufficient balance and free HTLC slots to lock. If yes, then lock
+        // and set the lock status. Otherwise, the HTLC is failed back to the sender. With the
+        // mitigation enabled, HTLCs that are not endorsed by a peer with good reputation only get
+        // the resources of the general bucket. The checks and the update of the outgoing channel
+        // happen under its lock, so that no other HTLC can take the resources in between. An HTLC
+        // whose fee or expiry does not match the policy of the outgoing channel is failed back as
+        // well.
+        if let Some(edge) = network.find_edge(payment.path[node_index], payment.path[node_index + 1]){
+            let (endorsed, general) = Payment::endorsement(&network, payment, node_index, edge, config);
+            let upfront_fee = Payment::upfront_fee(&network, payment, node_index);
+            let height = network.chain().height();
+            let result = {
+                let mut attrs = network.channel(edge);
+                let policy = check_fee(&attrs, payment.amounts[node_index - 1], amount)
+                    .and_then(|()| check_cltv_expiry(&attrs, payment.timelocks[node_index - 1], payment.timelocks[node_index], height));
+                if !attrs.is_open() {
+                    Err("channel closed".to_string())
+                } else if policy.is_err() {
+                    policy
+                } else if general && !reputation::general_bucket_has_room(&attrs, amount, config) {
+                    attrs.rejected_htlcs += 1;
+                    Err("general bucket full".to_string())
+                } else {
+                    add_htlc(&mut attrs, amount, upfront_fee).map(|()| {
+                        if general {
+                            attrs.reputation.general_htlcs += 1;
+                            attrs.reputation.general_in_flight += amount;
+                        }
+                    })
+                }
+            };
+            match result {
+                Err(reason) => {
+                    info!("Lock failed at node {:?} for payment id {:?}: {}", payment.path[node_index], payment.payment_id, reason);
+                    Payment::fail_back(&network, payment, node_index, &reason, config);
+                }
+                Ok(()) => {
+                    payment.endorsed[node_index] = endorsed;
+                    payment.general_bucket[node_index] = general;
+                    payment.upfront_fees[node_index] = upfront_fee;
+                    Payment::pay_fee(&network, payment, node_index, upfront_fee);
+                    payment.lock_status[node_index] = true;
+                    payment.lock_time[node_index] = Some(Instant::now());
+                    info!("Locked amount by node {:?} for payment id {:?}", payment.path[node_index], payment.payment_id);
+                    // If this is the penultimate node, then by locking it automatically notifies
+                    // the recipient of the payment.
+                    if node_index == payment.path.len() - 2 {
+                        Payment::notify_recipient(&network, payment, config);
+                    }
                 }
             }
-
-
         }
-        // Drop the lock on the shared graph
-        drop(guard);
-
-
         Ok(())
     }
 
     // Function to lock the funds in the first channel. The sender inherently has the sufficient
-    // balance due to the nature of the path finding algorithm. Accordingly update the lock status
-    // of the sender.
-    pub async fn lock_funds_sender(graph: Arc<Mutex<DiGraph<usize, EdgeAttributes>>>, payment: &mut Payment) -> Result<(), String> {
-        let guard = graph.lock().map_err(|e| e.to_string())?;
-        if let Some(edge) = guard.find_edge(payment.path[0], payment.path[1]){
-            let mut attrs = guard[edge];
-            attrs.balance -= payment.amounts[0];
+    // balance due to the nature of the path finding algorithm, but the channel may have run out of
+    // HTLC slots. Accordingly update the lock status of the sender. If the recipient is the next
+    // node, it is notified right away.
+    pub async fn lock_funds_sender(network: Arc<Network>, payment: &mut Payment, config: EndorsementConfig) -> Result<(), String> {
+        if let Some(edge) = network.find_edge(payment.path[0], payment.path[1]){
+            let upfront_fee = Payment::upfront_fee(&network, payment, 0);
+            if let Err(reason) = add_htlc(&mut network.channel(edge), payment.amounts[0], upfront_fee) {
+                payment.failed_at = Some(0);
+                payment.failure = Some(reason.clone());
+                return Err(reason);
+            }
+            payment.upfront_fees[0] = upfront_fee;
+            Payment::pay_fee(&network, payment, 0, upfront_fee);
+            payment.endorsed[0] = payment.sender_endorsed;
             payment.lock_status[0] = true;
             payment.lock_time[0] = Some(Instant::now());
             info!("Locked amount by sender {:?} for payment id {:?}", payment.path[0], payment.payment_id);
-
+            if payment.path.len() == 2 {
+                Payment::notify_recipient(&network, payment, config);
+            }
         }
-        drop(guard);
         Ok(())
     }
 
+    // Function to notify the recipient once the penultimate node has locked its funds. An honest
+    // recipient shares the secret key with the penultimate node and the secret key status is updated
+    // accordingly, unless the HTLC expires within the final delta it requires, in which case it fails
+    // the HTLC back as in BOLT 4. A rejecting recipient fails the HTLC back right away, and a holding recipient
+    // does so once the hold time has passed.
+    fn notify_recipient(network: &Network, payment: &mut Payment, config: EndorsementConfig) {
+        let recipient_index = payment.path.len() - 1;
+        let final_expiry = payment.timelocks[recipient_index - 1];
+        match payment.recipient {
+            RecipientBehaviour::Settle if final_expiry < network.chain().height().saturating_add(MIN_FINAL_CLTV_EXPIRY_DELTA) => {
+                info!("HTLC expiring too soon rejected by recipient {:?} for payment id {:?}", payment.path[recipient_index], payment.payment_id);
+                Payment::fail_back(network, payment, recipient_index, "final expiry too soon", config);
+            }
+            RecipientBehaviour::Settle => {
+                // Set secret key received for the last node
+                payment.secret_key_status[payment.path.len() - 1] = true;
+                payment.secret_key_received_time[payment.path.len() - 1] = Some(Instant::now());
+                payment.secret_key_status[payment.path.len() - 2] = true;
+                payment.secret_key_received_time[payment.path.len() - 2] = Some(Instant::now());
+                info!("Secret shared by recipient {:?} for payment id {:?}", payment.path[payment.path.len() - 1], payment.payment_id);
+                info!("Secret received by node {:?} for payment id {:?}", payment.path[payment.path.len() - 2], payment.payment_id);
+            }
+            RecipientBehaviour::Hold(_) => {
+                info!("HTLC held by recipient {:?} for payment id {:?}", payment.path[payment.path.len() - 1], payment.payment_id);
+            }
+            RecipientBehaviour::Reject => {
+                info!("HTLC rejected by recipient {:?} for payment id {:?}", payment.path[payment.path.len() - 1], payment.payment_id);
+                Payment::fail_back(network, payment, recipient_index, "rejected by the recipient", config);
+            }
+        }
+    }
+
+    // Function to decide whether the HTLC a forwarding node offers to the next node is endorsed, and
+    // whether it has to fit in the general bucket of the outgoing channel. Only endorsed HTLCs from
+    // incoming peers with good reputation stay endorsed and may use the protected resources.
+    fn endorsement(network: &Network, payment: &Payment, node_index: usize, outgoing: EdgeIndex, config: EndorsementConfig) -> (bool, bool) {
+        if !config.enabled {
+            return (false, false);
+        }
+        let fee = (payment.amounts[node_index - 1] - payment.amounts[node_index]) as f32;
+        let max_hold = network.chain().time_until(payment.timelocks[node_index]);
+        let good = payment.endorsed[node_index - 1] && network.find_edge(payment.path[node_index - 1], payment.path[node_index])
+            .is_some_and(|incoming| {
+                let (mut incoming, mut outgoing) = network.channel_pair(incoming, outgoing);
+                reputation::has_good_reputation(&mut incoming.reputation, &mut outgoing.reputation, fee, max_hold, config)
+            });
+        (good, !good)
+    }
+
+    // Function to remove the HTLC offered by the node at node_index from its channel once the HTLC
+    // resolves. A settled HTLC moves the funds to the opposite direction of the channel, while a
+    // failed or expired HTLC returns them to the node. Either way, the node pays the hold fee for the
+    // time the HTLC was locked along with the hold fees the next node has paid downstream, as far as
+    // its balance in the channel allows. The part it cannot pay is recorded as a shortfall. With the
+    // mitigation enabled, a forwarding node also updates the reputation of its incoming peer. An HTLC
+    // over a channel closed in the meantime is resolved on chain.
+    fn release_htlc(network: &Network, payment: &mut Payment, node_index: usize, settled: bool, config: EndorsementConfig) {
+        let amount = payment.amounts[node_index];
+        let (from, to) = (payment.path[node_index], payment.path[node_index + 1]);
+        let hold_time = payment.lock_time[node_index].map_or(Duration::ZERO, |time| time.elapsed());
+        if let Some(edge) = network.find_edge(from, to) {
+            remove_htlc(&mut network.channel(edge), amount, payment.general_bucket[node_index]);
+            if !settled {
+                network.credit(edge, amount);
+            }
+            let (hold_fee, shortfall) = {
+                let mut attrs = network.channel(edge);
+                let downstream_hold_fees = payment.hold_fees.get(node_index + 1).copied().unwrap_or(0);
+                let owed = attrs.hold_fee(amount, hold_time) + downstream_hold_fees;
+                let hold_fee = owed.min(attrs.balance);
+                attrs.balance -= hold_fee;
+                (hold_fee, owed - hold_fee)
+            };
+            if shortfall > 0 {
+                error!("Node {:?} could not pay {:?} msat of the hold fees it owes for payment id {:?}", from, shortfall, payment.payment_id);
+            }
+            payment.hold_fees[node_index] = hold_fee;
+            payment.hold_fee_shortfalls[node_index] = shortfall;
+            payment.hold_times[node_index] = hold_time;
+            Payment::pay_fee(network, payment, node_index, hold_fee);
+            if config.enabled && node_index > 0 {
+                if let Some(incoming) = network.find_edge(payment.path[node_index - 1], from) {
+                    let resolution = reputation::Resolution {
+                        fee: (payment.amounts[node_index - 1] - amount) as f32,
+                        hold_time,
+                        settled,
+                        endorsed: payment.endorsed[node_index - 1],
+                    };
+                    let (mut incoming, mut outgoing) = network.channel_pair(incoming, edge);
+                    reputation::resolve(&mut incoming.reputation, &mut outgoing.reputation, resolution, config);
+                }
+            }
+        }
+        if settled {
+            if let Some(edge) = network.find_edge(to, from) {
+                network.credit(edge, amount);
+            }
+        }
+    }
+
+    // Function to time out the HTLC offered by the node at node_index once it has expired. The HTLC
+    // is released as a failed one, so that the node pays the hold fee for the whole time it was
+    // locked and, with the mitigation enabled, resolves the reputation of its incoming peer. As in
+    // BOLT 5, the node then force-closes the channel to claim the HTLC back on chain, paying the
+    // on-chain fee and waiting for the to_self_delay before it can spend its funds. It then fails
+    // the HTLC back towards the sender, so that the upstream channels stay open.
+    fn expire(network: &Network, payment: &mut Payment, node_index: usize, config: EndorsementConfig) {
+        Payment::release_htlc(network, payment, node_index, false, config);
+        if let Some(edge) = network.find_edge(payment.path[node_index], payment.path[node_index + 1]) {
+            if let Some(close) = network.force_close(edge, payment.payment_id) {
+                error!("Channel ({:?}, {:?}) force-closed at height {} for payment id {:?}", close.closer, close.peer, close.height, payment.payment_id);
+            }
+        }
+        payment.timelock_expired[node_index] = true;
+        Payment::fail_back(network, payment, node_index, "htlc expired", config);
+    }
+
+    // Function to fail an HTLC back towards the sender when the node at node_index cannot forward
+    // it. Every upstream node that has locked funds for the payment releases them and marks its lock
+    // as failed.
+    fn fail_back(network: &Network, payment: &mut Payment, node_index: usize, reason: &str, config: EndorsementConfig) {
+        payment.failed_at = Some(node_index);
+        payment.failure = Some(reason.to_string());
+        for i in (0..node_index).rev() {
+            if !payment.lock_status[i] || payment.unlock_status[i] || payment.timelock_expired[i] || payment.failed_status[i] {
+                continue;
+            }
+            Payment::release_htlc(network, payment, i, false, config);
+            payment.failed_status[i] = true;
+            info!("Payment failed back to node {:?} for payment id {:?}", payment.path[i], payment.payment_id);
+        }
+    }
+
     // Function to check whether a node has been updated with the secret key. If yes, then it
     // unlocks the locked funds with the next node and sets the secret key status of the
-    // predecessor. Otherwise, it checks whether the node's timelock has expired by checking the
-    // time elapsed since the node locked. If the timelock has expired it updates the relevant field
+    // predecessor. Otherwise, it checks whether the HTLC the node offered has expired, which happens
+    // once the chain reaches its CLTV expiry. If the HTLC has expired it updates the relevant field
     // in the payment structure and reverts the locked funds.
-    pub async fn check_secret_key(graph: Arc<Mutex<DiGraph<usize, EdgeAttributes>>>, payment: &mut Payment, node_index: usize) -> Result<(), String> {
+    pub async fn check_secret_key(network: Arc<Network>, payment: &mut Payment, node_index: usize, config: EndorsementConfig) -> Result<(), String> {
 
         if node_index >= payment.path.len() {
             return Err("Invalid node index".to_string());
         }
-        let amount = payment.amounts[node_index];
 
         if payment.secret_key_status[node_index] {
             // Secret key has been received for the current node
@@ -116,29 +462,26 @@ impl Payment {
                 // Set secret key for the previous node
                 Payment::set_secret_key(payment, node_index).await?;
             }
-            let guard = graph.lock().map_err(|e| e.to_string())?;
-            // The unlocked funds have to be added to the balance in the opposite direction of the
-            // channel.
-            if let Some(edge) = guard.find_edge(payment.path[node_index+1], payment.path[node_index]) {
-                let mut attrs = guard[edge];
-                attrs.balance += amount;
-                payment.unlock_status[node_index] = true;
-                info!("Payment unlocked by node {:?} for payment id {:?}", payment.path[node_index], payment.payment_id);
-            }
-            drop(guard);
+            // The settled HTLC no longer occupies a slot in the channel, and the unlocked funds
+            // have to be added to the balance in the opposite direction of the channel.
+            Payment::release_htlc(&network, payment, node_index, true, config);
+            payment.unlock_status[node_index] = true;
+            info!("Payment unlocked by node {:?} for payment id {:?}", payment.path[node_index], payment.payment_id);
         } else {
             let lock_time = payment.lock_time[node_index].ok_or("Lock time not set")?;
-            // Check if the timelock is expired. The simulated time can be adjustedaccording to
-            // simulation needs. Here, the timelock value is divided by 100.
-            if lock_time.elapsed().as_secs() as f32 > payment.timelocks[node_index]/100.0 {
-                let guard = graph.lock().map_err(|e| e.to_string())?;
-                if let Some(edge) = guard.find_edge(payment.path[node_index], payment.path[node_index+1]) {
-                    let mut attrs = guard[edge];
-                    attrs.balance += amount;
-                    error!("Timelock reached for node {:?} for payment id {:?}", payment.path[node_index], payment.payment_id);
+            // A holding recipient fails the HTLC back once it has held it for the requested time.
+            if let RecipientBehaviour::Hold(hold_time) = payment.recipient {
+                if node_index == payment.path.len() - 2 && lock_time.elapsed() >= hold_time {
+                    let recipient_index = payment.path.len() - 1;
+                    info!("HTLC released by recipient {:?} for payment id {:?}", payment.path[recipient_index], payment.payment_id);
+                    Payment::fail_back(&network, payment, recipient_index, "held and failed by the recipient", config);
+                    return Ok(());
                 }
-                drop(guard);
-                payment.timelock_expired[node_index] = true; // Set timelock_expired to true
+            }
+            // Check if the HTLC has expired at the current height of the chain.
+            if network.chain().height() >= payment.timelocks[node_index] {
+                error!("Timelock reached for node {:?} for payment id {:?}", payment.path[node_index], payment.payment_id);
+                Payment::expire(&network, payment, node_index, config);
             }
         }
 
@@ -160,38 +503,44 @@ impl Payment {
     }
 
     // Function to manage the payment.
-    pub async fn payment_manager(graph: Arc<Mutex<DiGraph<usize, EdgeAttributes>>>, payment: &mut Payment) -> Result<(), String> {
-        info!("Payment path {:?}, amounts {:?}, delays {:?} for payment id {:?}",payment.path, payment.timelocks, payment.amounts, payment.payment_id);
+    pub async fn payment_manager(network: Arc<Network>, payment: &mut Payment, config: EndorsementConfig) -> Result<(), String> {
+        info!("Payment path {:?}, amounts {:?}, expiries {:?} for payment id {:?}",payment.path, payment.amounts, payment.timelocks, payment.payment_id);
         // First check if the path is valid. Otherwise fail the payment immediately.
         if payment.path.len()<2{
             error!("Payment {:?} failed due to no path!", payment.payment_id);
             return Err("Payment failed due to no path found".to_string());
-        }else{
-            Payment::lock_funds_sender(Arc::clone(&graph), payment).await?;
+        }else if let Err(reason) = Payment::lock_funds_sender(Arc::clone(&network), payment, config).await {
+            error!("Payment {:?} failed at the sender: {}", payment.payment_id, reason);
+            return Err(format!("Payment failed at the sender: {}", reason));
         }
 
         // If the payment path is valid have a loop to continuously check the values in the payment
         // structure and call relevant functions.
         loop {
             // 1) Check if payment has succeeded or failed
-            if payment.secret_key_status.iter().all(|&status| status) {
-                // All secret keys received, payment succeeded
+            if payment.secret_key_status.iter().all(|&status| status) && payment.unlock_status[..payment.path.len() - 1].iter().all(|&status| status) {
+                // All secret keys received and all locked funds unlocked, payment succeeded
                 info!("Payment {:?} success!", payment.payment_id);
                 return Ok(());
-            } else if payment.lock_status.iter().zip(payment.timelock_expired.iter()).all(|(&lock, &expired)| !lock || expired) {
-                // All locked nodes have timelocks expired, payment failed
-                error!("Payment {:?} failed due to timelock expiry!", payment.payment_id);
-                return Err("Payment failed due to expired timelocks".to_string());
+            } else if (0..payment.path.len()).all(|i| !payment.lock_status[i] || payment.timelock_expired[i] || payment.failed_status[i]) {
+                // All locked nodes have either had their timelocks expired or had the HTLC failed
+                // back to them, payment failed
+                if payment.timelock_expired.iter().any(|&expired| expired) {
+                    error!("Payment {:?} failed due to timelock expiry!", payment.payment_id);
+                    return Err("Payment failed due to expired timelocks".to_string());
+                }
+                error!("Payment {:?} failed due to a rejected HTLC!", payment.payment_id);
+                return Err("Payment failed due to a rejected HTLC".to_string());
             }
 
             // 2) Iterate over nodes in the path
             for i in 0..payment.path.len()-1 {
-                if i > 0 && payment.lock_status[i - 1] && !payment.lock_status[i] {
+                if i > 0 && payment.lock_status[i - 1] && !payment.failed_status[i - 1] && !payment.lock_status[i] {
                     // Previous node locked, current node not locked
-                    Payment::lock_funds(Arc::clone(&graph), payment, i, payment.amounts[i]).await?;
-                } else if payment.lock_status[i] && !payment.unlock_status[i] &&!payment.timelock_expired[i] {
+                    Payment::lock_funds(Arc::clone(&network), payment, i, payment.amounts[i], config).await?;
+                } else if payment.lock_status[i] && !payment.unlock_status[i] && !payment.timelock_expired[i] && !payment.failed_status[i] {
                     // Current node is already locked, check secret key
-                    Payment::check_secret_key(Arc::clone(&graph), payment, i).await?;
+                    Payment::check_secret_key(Arc::clone(&network), payment, i, config).await?;
                 }
             }
 
@@ -1,47 +1,263 @@
-use rand::Rng; // For generating random numbers
-use tokio::time::{sleep, Duration};
-use petgraph::graph::{NodeIndex,DiGraph};
+use tokio::time::Instant;
+use rand::rngs::StdRng;
+use tokio::time::sleep;
+use tokio::task::JoinHandle;
 use std::sync::{Arc, Mutex};
-use log::info;
-use crate::create_graph;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use serde::Deserialize;
+use log::{info, error};
 use crate::find_path;
+use crate::network::Network;
 use crate::payment_router;
-use create_graph::EdgeAttributes;
+use crate::reputation::EndorsementConfig;
+use crate::ledger::Ledger;
+use crate::results::{PaymentRecord, Request, Results};
+use crate::workload::Workload;
+
+// Longest time an agent running next to the payments sleeps before checking whether they have
+// stopped, so that a distant wake-up does not keep the simulation running.
+const STOP_POLL: Duration = Duration::from_secs(1);
+
+// The success rate has converged once it changes by less than the tolerance over a window of
+// finished payments.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Convergence {
+    pub window: u64, // Number of finished payments between two checks of the success rate
+    pub tolerance: f64, // Largest change of the success rate over a window that counts as converged
+}
+
+// Conditions under which the scheduler stops starting new payments. The scheduler stops as soon as
+// any of the set conditions is met, and without any condition it runs until the workload or the
+// trace is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct StopConditions {
+    pub max_payments: Option<u64>, // Number of payments to start
+    pub max_duration: Option<Duration>, // Virtual time after which no payment is started
+    pub convergence: Option<Convergence>, // Stop once the success rate has converged
+}
+
+// Structure to hold the progress of a simulation, shared between the scheduler and the payments it
+// started.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    pub started: u64, // Number of payments started
+    pub succeeded: u64, // Number of payments that succeeded
+    pub failed: u64, // Number of payments that failed
+    convergence: Option<Convergence>,
+    window_rate: Option<f64>, // Success rate at the end of the last window
+    converged: bool,
+}
+
+impl Progress {
+    pub fn new(convergence: Option<Convergence>) -> Self {
+        Progress { convergence, ..Default::default() }
+    }
+
+    // Number of payments that have finished, successfully or not.
+    pub fn finished(&self) -> u64 {
+        self.succeeded + self.failed
+    }
+
+    // Number of payments started but not finished yet.
+    pub fn in_flight(&self) -> u64 {
+        self.started - self.finished()
+    }
+
+    // Share of the finished payments that succeeded.
+    pub fn success_rate(&self) -> f64 {
+        if self.finished() == 0 {
+            return 0.0;
+        }
+        self.succeeded as f64 / self.finished() as f64
+    }
+
+    // Function to record a finished payment. At the end of every window the success rate is compared
+    // with the one at the end of the previous window to check whether it has converged.
+    pub fn record(&mut self, succeeded: bool) {
+        if succeeded {
+            self.succeeded += 1;
+        } else {
+            self.failed += 1;
+        }
+        let Some(convergence) = self.convergence else {
+            return;
+        };
+        if !self.finished().is_multiple_of(convergence.window.max(1)) {
+            return;
+        }
+        let rate = self.success_rate();
+        if let Some(window_rate) = self.window_rate {
+            self.converged = (rate - window_rate).abs() < convergence.tolerance;
+        }
+        self.window_rate = Some(rate);
+    }
+
+    // Function to log the final statistics of the simulation.
+    pub fn log_report(&self) {
+        info!("Payments started {:?}, succeeded {:?}, failed {:?}, success rate {:?}", self.started, self.succeeded, self.failed, self.success_rate());
+    }
+}
+
+// Function called with the record of every finished payment, for example to collect statistics of
+// an embedded simulation without reading its output files.
+pub type PaymentHook = Arc<dyn Fn(&PaymentRecord) + Send + Sync>;
+
+// Records of the finished payments shared between the scheduler and the payments it started: the
+// fee ledger, the progress of the simulation and the per-payment results.
+#[derive(Clone)]
+pub struct Recorder {
+    pub ledger: Arc<Mutex<Ledger>>,
+    pub progress: Arc<Mutex<Progress>>,
+    pub results: Arc<Mutex<Results>>,
+    hooks: Vec<PaymentHook>,
+}
+
+impl Recorder {
+    pub fn new(ledger: Ledger, progress: Progress, results: Results) -> Self {
+        Recorder {
+            ledger: Arc::new(Mutex::new(ledger)),
+            progress: Arc::new(Mutex::new(progress)),
+            results: Arc::new(Mutex::new(results)),
+            hooks: Vec::new(),
+        }
+    }
+
+    // Function to add a hook called with the record of every payment that finishes from now on.
+    pub fn add_hook(&mut self, hook: PaymentHook) {
+        self.hooks.push(hook);
+    }
+
+    // Function to record a finished payment that was started at the given time.
+    pub fn record(&self, request: &Request, payment: &payment_router::Payment, settled: bool, started: Instant) {
+        if let Ok(mut ledger) = self.ledger.lock() {
+            ledger.record(payment, settled);
+        }
+        if let Ok(mut progress) = self.progress.lock() {
+            progress.record(settled);
+        }
+        // The hooks are called once the results are unlocked, so that a hook can read them and a
+        // slow hook does not hold up the other payments.
+        let record = self.results.lock().ok().and_then(|mut results| {
+            if let Err(e) = results.record(request, payment, settled, started) {
+                error!("Failed to write the result of payment {:?}: {}", request.payment_id, e);
+            }
+            results.records().last().filter(|_| !self.hooks.is_empty()).cloned()
+        });
+        if let Some(record) = record {
+            for hook in &self.hooks {
+                hook(&record);
+            }
+        }
+    }
+}
+
+impl StopConditions {
+    // Function to check whether the scheduler should stop, given the progress and the virtual time
+    // elapsed since the start of the simulation.
+    pub fn reached(&self, progress: &Progress, elapsed: Duration) -> Option<String> {
+        if self.max_payments.is_some_and(|max| progress.started >= max) {
+            return Some(format!("{:?} payments started", progress.started));
+        }
+        if self.max_duration.is_some_and(|max| elapsed >= max) {
+            return Some(format!("{:?} elapsed", elapsed));
+        }
+        if self.convergence.is_some() && progress.converged {
+            return Some(format!("success rate converged to {:?}", progress.success_rate()));
+        }
+        None
+    }
+
+    // Function to check the stop conditions against the shared progress, logging the reason to stop.
+    pub fn check(&self, progress: &Arc<Mutex<Progress>>, elapsed: Duration) -> Result<bool, String> {
+        let guard = progress.lock().map_err(|e| e.to_string())?;
+        match self.reached(&guard, elapsed) {
+            Some(reason) => {
+                info!("Stopping the scheduler: {}, {:?} payments in flight", reason, guard.in_flight());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
 
 // Scheduler function to schedule payments asynchronously so that concurrent payments are possible.
-pub async fn schedule_payments(graph: Arc<Mutex<DiGraph<usize, EdgeAttributes>>>) -> Result<(), String>{
-    let mut rng = rand::thread_rng();
+// The workload decides the senders, recipients and amounts of the payments and the delays between
+// them, drawn from the given seeded generator. The endorsement config decides whether the forwarding
+// nodes apply the jamming mitigation, and every finished payment is recorded by the recorder. Once a
+// stop condition is met, no new payment is started and the scheduler returns after the payments in
+// flight have finished.
+pub async fn schedule_payments(network: Arc<Network>, workload: Workload, mut rng: StdRng, config: EndorsementConfig, recorder: Recorder, stop: StopConditions) -> Result<(), String>{
     let mut payment_id:u64 = 0;
+    let mut picker = workload.node_picker(network.node_count())?;
+    let mut departed = 0;
+    let start = Instant::now();
+    let mut payments = Vec::new();
     loop {
+        // Nodes that left the network neither send nor receive payments.
+        let left = network.departed();
+        if left.len() > departed {
+            picker.exclude(&left)?;
+            departed = left.len();
+        }
         // Generate random sender and recipient
         payment_id += 1;
-        let guard = graph.lock().map_err(|e| e.to_string())?;
-        let sender = NodeIndex::new(rng.gen_range(0..guard.node_count()));
-        let recipient = NodeIndex::new(rng.gen_range(0..guard.node_count()));
-        drop(guard);
+        let sender = picker.sender(&mut rng);
+        let recipient = picker.recipient(&mut rng);
         // Ensure sender and recipient are not the same
         if sender != recipient {
-            // Use the path finding algorithm to get path, timelocks, and amounts. The payment
-            // amount is assumed to be 10000 satoshis here. This can be set according to simulation
-            // needs. Higher amounts can lead to more payment failures both due to balance
-            // availability and no paths found.
-            let (path,timelocks,amounts) = find_path::dijkstra(Arc::clone(&graph), sender, recipient,1000.0);
+            // Schedule the payment with a delay drawn from the arrival process. Smaller delays
+            // will mean more concurrent payments.
+            sleep(workload.next_delay(&mut rng, start.elapsed())).await;
+            if stop.check(&recorder.progress, start.elapsed())? {
+                break;
+            }
+            // Use the path finding algorithm with the cost model of the sender to get path,
+            // timelocks, and amounts, once the payment starts, so that its expiries are counted from
+            // the current height and its path is found on the current balances. Higher amounts can
+            // lead to more payment failures both due to balance availability and no paths found.
+            let amount = workload.next_amount(&mut rng);
+            let (path,timelocks,amounts) = find_path::dijkstra_with_cost(&network, recipient, sender, amount, picker.cost_model(sender));
 
             // Create a Payment instance
-            let mut payment = payment_router::Payment::new(payment_id,path,timelocks,amounts);
-
-            // Schedule the payment with a random delay
-            let delay = rng.gen_range(0..10); // Random delay in milliseconds. This can
-            // be adjusted according to simulation needs. Smaller delays will mean more concurrent
-            // payments.
-            sleep(Duration::from_millis(delay)).await;
-            let graph_clone = Arc::clone(&graph);
+            let payment = payment_router::Payment::new(payment_id,path,timelocks,amounts);
             info!("Payment no. {:?} started from {:?} to {:?}",payment_id,sender,recipient);
-            // Process the payment asynchronously.
-            tokio::spawn(async move {
-                payment_router::Payment::payment_manager(graph_clone, &mut payment).await;
-            });
-
+            payments.retain(|handle: &JoinHandle<()>| !handle.is_finished());
+            let request = Request { payment_id, sender, recipient, amount };
+            payments.push(start_payment(Arc::clone(&network), payment, request, config, recorder.clone()));
         }
     }
+    drain(payments).await;
+    Ok(())
+}
+
+// Function to process a payment asynchronously and record it once it has finished.
+pub fn start_payment(network: Arc<Network>, mut payment: payment_router::Payment, request: Request, config: EndorsementConfig, recorder: Recorder) -> JoinHandle<()> {
+    if let Ok(mut progress) = recorder.progress.lock() {
+        progress.started += 1;
+    }
+    tokio::spawn(async move {
+        let started = Instant::now();
+        // The outcome of the payment is logged by the payment manager itself.
+        let result = payment_router::Payment::payment_manager(network, &mut payment, config).await;
+        recorder.record(&request, &payment, result.is_ok(), started);
+    })
+}
+
+// Function to sleep until the given time, or until the payments have stopped. Returns whether the
+// payments are still running.
+pub async fn wait_until(deadline: Instant, stopped: &AtomicBool) -> bool {
+    while !stopped.load(Ordering::Relaxed) && Instant::now() < deadline {
+        sleep((deadline - Instant::now()).min(STOP_POLL)).await;
+    }
+    !stopped.load(Ordering::Relaxed)
+}
+
+// Function to wait for the payments in flight to settle, fail or expire once the scheduler has
+// stopped, so that the final statistics cover every payment that was started.
+pub async fn drain(payments: Vec<JoinHandle<()>>) {
+    for handle in payments {
+        let _ = handle.await;
+    }
 }
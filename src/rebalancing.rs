@@ -0,0 +1,269 @@
+use log::{info, error};
+use petgraph::graph::{EdgeIndex, NodeIndex};
+use petgraph::visit::EdgeRef;
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::create_graph::{msat_to_sat, PPM};
+use crate::find_path::{self, CostModel};
+use crate::ledger::Ledger;
+use crate::network::Network;
+use crate::payment_router::Payment;
+use crate::payment_scheduler;
+use crate::reputation::EndorsementConfig;
+
+// Payment ids of the rebalancers start at this offset so that they never collide with the ids given
+// out by the scheduler and the attackers.
+const REBALANCE_PAYMENT_ID_OFFSET: u64 = 1 << 48;
+
+// Routing node that rebalances its depleted channels by paying itself around a cycle. Every interval,
+// each channel whose local share of the capacity fell below the threshold is refilled up to the
+// target share, from the channel with the largest local share above the target, as long as the fees
+// of the circular route stay within the budget.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rebalancer {
+    pub node: usize, // Id of the node in the snapshot, its index in the graph once resolved
+    #[serde(default = "default_threshold")]
+    pub threshold: f64, // Local share of the capacity below which a channel is rebalanced
+    #[serde(default = "default_target")]
+    pub target: f64, // Local share of the capacity a rebalanced channel is refilled to
+    #[serde(default = "default_max_fee_ppm")]
+    pub max_fee_ppm: u64, // Fee budget in millionths of the amount moved
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: f64, // Time between two checks of the channels
+}
+
+fn default_threshold() -> f64 {
+    0.2
+}
+
+fn default_target() -> f64 {
+    0.5
+}
+
+fn default_max_fee_ppm() -> u64 {
+    1000
+}
+
+fn default_interval_secs() -> f64 {
+    60.0
+}
+
+impl Rebalancer {
+    // Function to check that the parameters of the rebalancer make sense.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.threshold) || !(0.0..=1.0).contains(&self.target) || self.threshold > self.target {
+            return Err("threshold and target must be shares with threshold <= target".to_string());
+        }
+        if !self.interval_secs.is_finite() || self.interval_secs <= 0.0 {
+            return Err("interval_secs must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Structure to hold the outcome of the rebalancing attempts and their cost. Amounts and fees are in
+// msat.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RebalancingReport {
+    pub attempts: u64, // Circular payments sent
+    pub succeeded: u64,
+    pub no_route: u64, // Depleted channels for which no circular route was found
+    pub over_budget: u64, // Depleted channels whose circular route cost more than the budget
+    pub amount_moved: u64, // Amount moved into the depleted channels by the successful payments
+    pub fees_paid: u64, // Fees paid by the rebalancers for the successful payments
+}
+
+// Local balance of a channel direction and the capacity of the channel, counting the HTLCs in flight
+// on both sides.
+fn liquidity(network: &Network, edge: EdgeIndex, from: NodeIndex, to: NodeIndex) -> (u64, u64) {
+    let attrs = network.attributes(edge);
+    let local = attrs.balance + attrs.value_in_flight;
+    let remote = network.find_edge(to, from).map_or(0, |reverse| {
+        let reverse = network.attributes(reverse);
+        reverse.balance + reverse.value_in_flight
+    });
+    (local, local + remote)
+}
+
+// Function to compute the circular route that moves the amount out of the node over the channel to
+// the source peer and back in over the channel from the depleted peer. The route is found by the node
+// itself, on its view of the network and with its cost model, as the sender of a payment whose first
+// hop is the channel to the source peer. Empty vectors are returned if there is none.
+pub fn circular_route(network: &Network, node: NodeIndex, source: NodeIndex, depleted: NodeIndex, amount: u64, model: CostModel) -> (Vec<NodeIndex>, Vec<u32>, Vec<u64>) {
+    // The depleted peer charges the fee of its channel to the node on top of the amount.
+    let Some(last_hop) = network.find_edge(depleted, node) else {
+        return (Vec::new(), Vec::new(), Vec::new());
+    };
+    let received = amount + network.attributes(last_hop).fee(amount);
+    let (mut path, _, _) = find_path::dijkstra_via(network, depleted, node, Some(source), received, model);
+    if path.is_empty() {
+        return (Vec::new(), Vec::new(), Vec::new());
+    }
+    path.push(node);
+    find_path::route_through(network, path, amount)
+}
+
+// Function to find the depleted channels of the rebalancer and refill them one at a time through the
+// payment router.
+async fn rebalance(network: &Arc<Network>, rebalancer: &Rebalancer, model: CostModel, next_id: &mut u64, config: EndorsementConfig, ledger: &Mutex<Ledger>, report: &Mutex<RebalancingReport>) {
+    let node = NodeIndex::new(rebalancer.node);
+    let channels: Vec<(EdgeIndex, NodeIndex)> = network.topology().edges(node).map(|edge| (edge.id(), edge.target())).collect();
+    let channels: Vec<(EdgeIndex, NodeIndex, u64, u64)> = channels.into_iter()
+        .filter(|&(edge, _)| network.channel(edge).is_open())
+        .map(|(edge, peer)| {
+            let (local, capacity) = liquidity(network, edge, node, peer);
+            (edge, peer, local, capacity)
+        })
+        .filter(|&(_, _, _, capacity)| capacity > 0)
+        .collect();
+    let share = |local: u64, capacity: u64| local as f64 / capacity as f64;
+    for &(_, depleted, local, capacity) in channels.iter().filter(|&&(_, _, local, capacity)| share(local, capacity) < rebalancer.threshold) {
+        // The source is the channel with the largest local share above the target, which can give
+        // away the amount without falling below the target itself.
+        let Some(&(_, source, source_local, source_capacity)) = channels.iter()
+            .filter(|&&(_, peer, local, capacity)| peer != depleted && share(local, capacity) > rebalancer.target)
+            .max_by(|a, b| share(a.2, a.3).total_cmp(&share(b.2, b.3)).then(b.1.cmp(&a.1)))
+        else {
+            continue;
+        };
+        let needed = ((capacity as f64 * rebalancer.target) as u64).saturating_sub(local);
+        let spare = source_local.saturating_sub((source_capacity as f64 * rebalancer.target) as u64);
+        let amount = needed.min(spare);
+        if amount == 0 {
+            continue;
+        }
+        let (path, timelocks, amounts) = circular_route(network, node, source, depleted, amount, model);
+        if path.is_empty() {
+            if let Ok(mut report) = report.lock() {
+                report.no_route += 1;
+            }
+            continue;
+        }
+        let fee = amounts[0] - amount;
+        if fee as u128 * PPM as u128 > amount as u128 * rebalancer.max_fee_ppm as u128 {
+            info!("Rebalancing of channel {:?} by node {:?} skipped as its fee {:?} msat is over the budget", (node, depleted), node, fee);
+            if let Ok(mut report) = report.lock() {
+                report.over_budget += 1;
+            }
+            continue;
+        }
+        *next_id += 1;
+        let mut payment = Payment::new(*next_id, path, timelocks, amounts);
+        let result = Payment::payment_manager(Arc::clone(network), &mut payment, config).await;
+        if let Ok(mut ledger) = ledger.lock() {
+            ledger.record(&payment, result.is_ok());
+        }
+        if let Ok(mut report) = report.lock() {
+            report.attempts += 1;
+            if result.is_ok() {
+                report.succeeded += 1;
+                report.amount_moved += amount;
+                report.fees_paid += fee + payment.upfront_and_hold_fees();
+            }
+        }
+        match result {
+            Ok(()) => info!("Node {:?} moved {:?} msat into channel {:?} from {:?} for {:?} msat of fees", node, amount, (node, depleted), (node, source), fee),
+            Err(e) => error!("Rebalancing of channel {:?} by node {:?} failed: {}", (node, depleted), node, payment.failure().unwrap_or(&e)),
+        }
+    }
+}
+
+// Function to run the rebalancers next to the payments. Every rebalancer checks its channels at the
+// start and then every interval, and sends its circular payments through the normal payment router,
+// so that they compete with the other payments for liquidity and pay the usual fees. Every rebalancer
+// comes with the cost model its node finds paths with. Once the stop flag is set, no new rebalancing
+// starts.
+pub async fn run_rebalancers(network: Arc<Network>, rebalancers: Vec<(Rebalancer, CostModel)>, config: EndorsementConfig, ledger: Arc<Mutex<Ledger>>, stopped: Arc<AtomicBool>) -> RebalancingReport {
+    let report = Arc::new(Mutex::new(RebalancingReport::default()));
+    let handles: Vec<_> = rebalancers.into_iter().enumerate()
+        .map(|(i, (rebalancer, model))| {
+            let (network, ledger, report, stopped) = (Arc::clone(&network), Arc::clone(&ledger), Arc::clone(&report), Arc::clone(&stopped));
+            tokio::spawn(async move {
+                let mut next_id = REBALANCE_PAYMENT_ID_OFFSET + ((i as u64) << 32);
+                let interval = Duration::from_secs_f64(rebalancer.interval_secs);
+                let mut next = Instant::now();
+                while payment_scheduler::wait_until(next, &stopped).await {
+                    rebalance(&network, &rebalancer, model, &mut next_id, config, &ledger, &report).await;
+                    next += interval;
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        let _ = handle.await;
+    }
+    let report = report.lock().map_or_else(|e| *e.into_inner(), |report| *report);
+    if report.attempts + report.no_route + report.over_budget > 0 {
+        info!(
+            "Rebalancing: {} of {} circular payments succeeded and moved {} sat for {} sat of fees, {} depleted channels without a route, {} over the fee budget",
+            report.succeeded, report.attempts, msat_to_sat(report.amount_moved), msat_to_sat(report.fees_paid), report.no_route, report.over_budget
+        );
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_graph::Graph;
+    use crate::topology::new_channel;
+
+    // Function to build a triangle of channels of 1000 sats with a base fee of one sat, in which node
+    // 0 holds 90% of its channel with node 1 and 10% of its channel with node 2.
+    fn triangle() -> Arc<Network> {
+        let mut graph = Graph::new();
+        let nodes: Vec<NodeIndex> = (0..3).map(|id| graph.add_node(id)).collect();
+        for (from, to, balance) in [(0, 1, 900_000), (1, 0, 100_000), (0, 2, 100_000), (2, 0, 900_000), (1, 2, 500_000), (2, 1, 500_000)] {
+            graph.add_edge(nodes[from], nodes[to], new_channel(balance, 1000, 0, 40));
+        }
+        Arc::new(Network::new(graph))
+    }
+
+    fn balance(network: &Network, from: usize, to: usize) -> u64 {
+        network.attributes(network.find_edge(NodeIndex::new(from), NodeIndex::new(to)).unwrap()).balance
+    }
+
+    fn rebalancer(max_fee_ppm: u64) -> Rebalancer {
+        Rebalancer { node: 0, threshold: default_threshold(), target: default_target(), max_fee_ppm, interval_secs: default_interval_secs() }
+    }
+
+    #[test]
+    fn circular_route_leaves_over_the_source_and_returns_over_the_depleted_channel() {
+        let network = triangle();
+        let (path, _, amounts) = circular_route(&network, NodeIndex::new(0), NodeIndex::new(1), NodeIndex::new(2), 400_000, CostModel::default());
+        assert_eq!(path, [0, 1, 2, 0].map(NodeIndex::new));
+        // Nodes 1 and 2 each charge the base fee of their outgoing channel.
+        assert_eq!(amounts, vec![402_000, 401_000, 400_000, 400_000]);
+        // Without a channel from the depleted peer back to the node there is no route.
+        let (path, _, _) = circular_route(&network, NodeIndex::new(1), NodeIndex::new(0), NodeIndex::new(3), 400_000, CostModel::default());
+        assert!(path.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn depleted_channel_is_refilled_to_the_target_within_the_budget() {
+        // Half of the capacity is 500 sats: node 0 moves 400 sats from the channel with node 1 into the
+        // one with node 2, for 2 sats of fees, 5000 ppm of the amount.
+        let network = triangle();
+        let (ledger, report) = (Mutex::new(Ledger::default()), Mutex::new(RebalancingReport::default()));
+        let mut next_id = 0;
+        rebalance(&network, &rebalancer(4999), CostModel::default(), &mut next_id, EndorsementConfig::default(), &ledger, &report).await;
+        assert_eq!(*report.lock().unwrap(), RebalancingReport { over_budget: 1, ..RebalancingReport::default() });
+        assert_eq!(balance(&network, 0, 2), 100_000);
+
+        rebalance(&network, &rebalancer(5000), CostModel::default(), &mut next_id, EndorsementConfig::default(), &ledger, &report).await;
+        let report = *report.lock().unwrap();
+        assert_eq!((report.attempts, report.succeeded, report.amount_moved, report.fees_paid), (1, 1, 400_000, 2000));
+        assert_eq!(balance(&network, 0, 1), 900_000 - 402_000);
+        assert_eq!(balance(&network, 0, 2), 100_000 + 400_000);
+        assert_eq!(ledger.lock().unwrap().channel(NodeIndex::new(1), NodeIndex::new(2)).forwarding_fees, 1000);
+
+        // Both channels now sit at their target and nothing more is moved.
+        let report_after = Mutex::new(RebalancingReport::default());
+        rebalance(&network, &rebalancer(5000), CostModel::default(), &mut next_id, EndorsementConfig::default(), &ledger, &report_after).await;
+        assert_eq!(*report_after.lock().unwrap(), RebalancingReport::default());
+    }
+}
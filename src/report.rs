@@ -0,0 +1,293 @@
+use petgraph::graph::DiGraph;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use crate::chain::OnChain;
+use crate::create_graph::{msat_to_sat, EdgeAttributes};
+use crate::ledger::Ledger;
+use crate::results::{Outcome, PaymentRecord};
+
+// Upper bounds in satoshis of the amount buckets over which the success rate is reported. The last
+// bucket holds every larger amount.
+const AMOUNT_BUCKETS: [f32; 4] = [1_000.0, 10_000.0, 100_000.0, 1_000_000.0];
+// Share of the capacity of a channel below which the balance of one direction counts as depleted.
+const DEPLETION_THRESHOLD: f64 = 0.01;
+// Number of channels listed as the most used.
+const MOST_USED_CHANNELS: usize = 10;
+
+// Success rate of the payments whose amount falls in a bucket.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct AmountBucket {
+    pub min: f32,
+    pub max: Option<f32>, // Upper bound of the bucket, none for the last one
+    pub payments: u64,
+    pub succeeded: u64,
+    pub success_rate: f64,
+}
+
+// Mean and percentiles of a metric over the payments.
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct Distribution {
+    pub count: usize,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+// Number of settled payments that crossed a channel.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ChannelUse {
+    pub channel: (usize, usize),
+    pub payments: u64,
+}
+
+// Summary of a run with the standard Lightning Network metrics. The fee, path length and latency
+// distributions are over the settled payments.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Summary {
+    pub payments: u64,
+    pub succeeded: u64,
+    pub success_rate: f64,
+    pub success_by_amount: Vec<AmountBucket>,
+    pub fees: Distribution, // Fees paid by the sender in satoshis
+    pub path_length: Distribution, // Number of channels in the path
+    pub latency: Distribution, // Virtual seconds from the start of the payment to its completion
+    pub failure_reasons: BTreeMap<String, u64>,
+    pub channels: usize, // Number of channel directions in the graph
+    pub depleted_channels_at_start: usize,
+    pub depleted_channels: usize, // Channel directions depleted at the end of the run
+    pub force_closes: usize, // Channels force-closed on chain after an HTLC expired
+    pub force_close_fees: f64, // On-chain fees in satoshis paid for the force closes
+    pub revenue_gini: f64, // Gini coefficient of the routing revenue over every node in the graph
+    pub most_used_channels: Vec<ChannelUse>,
+}
+
+impl Distribution {
+    // Function to compute the distribution of the given values, with nearest-rank percentiles.
+    pub fn new(mut values: Vec<f64>) -> Self {
+        if values.is_empty() {
+            return Distribution::default();
+        }
+        values.sort_by(|a, b| a.total_cmp(b));
+        let percentile = |p: f64| values[((p * values.len() as f64).ceil() as usize).clamp(1, values.len()) - 1];
+        Distribution {
+            count: values.len(),
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: values[values.len() - 1],
+        }
+    }
+}
+
+// Function to count the channel directions whose balance is below the depletion threshold of the
+// capacity of their channel.
+pub fn depleted_channels(graph: &DiGraph<usize, EdgeAttributes>) -> usize {
+    graph.edge_indices().filter(|&edge| {
+        let (start, end) = graph.edge_endpoints(edge).unwrap();
+        let reverse = graph.find_edge(end, start).map_or(0, |reverse| graph[reverse].balance + graph[reverse].value_in_flight);
+        let capacity = graph[edge].balance + graph[edge].value_in_flight + reverse;
+        (graph[edge].balance as f64) < capacity as f64 * DEPLETION_THRESHOLD
+    }).count()
+}
+
+// Function to compute the Gini coefficient of the given values, zero meaning a perfectly even split.
+pub fn gini(mut values: Vec<f64>) -> f64 {
+    let total: f64 = values.iter().sum();
+    if values.is_empty() || total <= 0.0 {
+        return 0.0;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let n = values.len() as f64;
+    let weighted: f64 = values.iter().enumerate().map(|(i, value)| (i + 1) as f64 * value).sum();
+    2.0 * weighted / (n * total) - (n + 1.0) / n
+}
+
+impl Summary {
+    // Function to summarise a run from the records of its payments, its ledger, the open channels of
+    // the graph at the end of the run and the channels it closed on chain.
+    pub fn new(records: &[PaymentRecord], ledger: &Ledger, graph: &DiGraph<usize, EdgeAttributes>, onchain: &OnChain, depleted_channels_at_start: usize) -> Self {
+        let settled: Vec<&PaymentRecord> = records.iter().filter(|record| record.outcome == Outcome::Success).collect();
+
+        let mut success_by_amount = Vec::new();
+        let mut min = 0.0;
+        for max in AMOUNT_BUCKETS.iter().map(|&max| Some(max)).chain([None]) {
+            let in_bucket = |record: &PaymentRecord| {
+                let amount = msat_to_sat(record.amount_msat) as f32;
+                amount >= min && max.is_none_or(|max| amount < max)
+            };
+            let payments = records.iter().filter(|record| in_bucket(record)).count() as u64;
+            let succeeded = settled.iter().filter(|record| in_bucket(record)).count() as u64;
+            success_by_amount.push(AmountBucket { min, max, payments, succeeded, success_rate: rate(succeeded, payments) });
+            min = max.unwrap_or(f32::INFINITY);
+        }
+
+        let mut failure_reasons = BTreeMap::new();
+        for record in records {
+            if let Some(reason) = &record.failure_reason {
+                *failure_reasons.entry(reason.clone()).or_insert(0) += 1;
+            }
+        }
+
+        let mut channel_use: BTreeMap<(usize, usize), u64> = BTreeMap::new();
+        for record in &settled {
+            for hop in record.path.windows(2) {
+                *channel_use.entry((hop[0], hop[1])).or_insert(0) += 1;
+            }
+        }
+        let mut most_used_channels: Vec<ChannelUse> = channel_use.into_iter().map(|(channel, payments)| ChannelUse { channel, payments }).collect();
+        most_used_channels.sort_by(|a, b| b.payments.cmp(&a.payments).then(a.channel.cmp(&b.channel)));
+        most_used_channels.truncate(MOST_USED_CHANNELS);
+
+        // Nodes without an account in the ledger earned nothing, and count towards the inequality.
+        let mut revenues: Vec<f64> = ledger.node_report().iter().map(|(_, account)| account.revenue() as f64).collect();
+        revenues.resize(graph.node_count().max(revenues.len()), 0.0);
+
+        Summary {
+            payments: records.len() as u64,
+            succeeded: settled.len() as u64,
+            success_rate: rate(settled.len() as u64, records.len() as u64),
+            success_by_amount,
+            fees: Distribution::new(settled.iter().map(|record| msat_to_sat(record.fees_msat)).collect()),
+            path_length: Distribution::new(settled.iter().map(|record| (record.path.len() - 1) as f64).collect()),
+            latency: Distribution::new(settled.iter().map(|record| record.end_time - record.start_time).collect()),
+            failure_reasons,
+            channels: graph.edge_count(),
+            depleted_channels_at_start,
+            depleted_channels: depleted_channels(graph),
+            force_closes: onchain.force_closes.len(),
+            force_close_fees: msat_to_sat(onchain.fees()),
+            revenue_gini: gini(revenues),
+            most_used_channels,
+        }
+    }
+
+    // Function to write the summary to summary.json and summary.txt in the output directory.
+    pub fn write<P: AsRef<Path>>(&self, dir: P) -> Result<(), String> {
+        let dir = dir.as_ref();
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(dir.join("summary.json"), json).map_err(|e| e.to_string())?;
+        fs::write(dir.join("summary.txt"), self.to_string()).map_err(|e| e.to_string())
+    }
+}
+
+// Function to compute the share of the payments that succeeded.
+fn rate(succeeded: u64, payments: u64) -> f64 {
+    if payments == 0 {
+        return 0.0;
+    }
+    succeeded as f64 / payments as f64
+}
+
+impl fmt::Display for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "mean {:.3}, p50 {:.3}, p90 {:.3}, p99 {:.3}, max {:.3}", self.mean, self.p50, self.p90, self.p99, self.max)
+    }
+}
+
+// Human-readable form of the summary.
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Payments: {} finished, {} succeeded ({:.2}%)", self.payments, self.succeeded, 100.0 * self.success_rate)?;
+        writeln!(f, "Success rate by amount:")?;
+        for bucket in &self.success_by_amount {
+            let max = bucket.max.map_or("inf".to_string(), |max| max.to_string());
+            writeln!(f, "  [{}, {}) sat: {} payments, {:.2}%", bucket.min, max, bucket.payments, 100.0 * bucket.success_rate)?;
+        }
+        writeln!(f, "Fees (sat): {}", self.fees)?;
+        writeln!(f, "Path length (channels): {}", self.path_length)?;
+        writeln!(f, "Completion latency (s): {}", self.latency)?;
+        writeln!(f, "Failure reasons:")?;
+        for (reason, count) in &self.failure_reasons {
+            writeln!(f, "  {}: {}", reason, count)?;
+        }
+        writeln!(f, "Depleted channel directions: {} of {} ({} at the start)", self.depleted_channels, self.channels, self.depleted_channels_at_start)?;
+        writeln!(f, "Force-closed channels: {} ({} sat of on-chain fees)", self.force_closes, self.force_close_fees)?;
+        writeln!(f, "Gini coefficient of node revenue: {:.4}", self.revenue_gini)?;
+        writeln!(f, "Most-used channels:")?;
+        for channel in &self.most_used_channels {
+            writeln!(f, "  {:?}: {} payments", channel.channel, channel.payments)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use petgraph::graph::NodeIndex;
+    use crate::payment_router::Payment;
+    use crate::topology::new_channel;
+
+    fn record(id: u64, amount_sat: u64, path: Vec<usize>, fees_msat: u64, times: (f64, f64), failure_reason: Option<&str>) -> PaymentRecord {
+        PaymentRecord {
+            id,
+            sender: path[0],
+            recipient: path[path.len() - 1],
+            amount_msat: amount_sat * 1000,
+            path,
+            fees_msat,
+            attempts: 1,
+            outcome: if failure_reason.is_none() { Outcome::Success } else { Outcome::Failure },
+            failure_reason: failure_reason.map(str::to_string),
+            failure_hop: None,
+            start_time: times.0,
+            end_time: times.1,
+            lock_time: 0.0,
+        }
+    }
+
+    #[test]
+    fn percentiles_are_nearest_ranks() {
+        let distribution = Distribution::new((1..=100).rev().map(f64::from).collect());
+        assert_eq!(distribution, Distribution { count: 100, mean: 50.5, p50: 50.0, p90: 90.0, p99: 99.0, max: 100.0 });
+        assert_eq!(Distribution::new(vec![7.0]).p50, 7.0);
+    }
+
+    #[test]
+    fn summary_of_a_known_run() {
+        let mut graph = DiGraph::new();
+        let nodes: Vec<NodeIndex> = (0..4).map(|id| graph.add_node(id)).collect();
+        for pair in nodes.windows(2) {
+            graph.add_edge(pair[0], pair[1], new_channel(1_000_000, 1000, 0, 40));
+            graph.add_edge(pair[1], pair[0], new_channel(1_000_000, 1000, 0, 40));
+        }
+        // The channel from node 2 to node 3 is depleted, below 1% of the capacity of the channel.
+        let depleted = graph.find_edge(nodes[2], nodes[3]).unwrap();
+        graph[depleted].balance = 5_000;
+
+        // Only node 1 earns a revenue, 1000 msat on each of two payments, out of four nodes.
+        let mut ledger = Ledger::default();
+        for id in 0..2 {
+            let payment = Payment::new(id, nodes[..3].to_vec(), vec![80, 40, 40], vec![101_000, 100_000, 100_000]);
+            ledger.record(&payment, true);
+        }
+
+        let records = [
+            record(1, 500, vec![0, 1, 2], 1000, (0.0, 1.0), None),
+            record(2, 5_000, vec![0, 1], 2000, (0.0, 2.0), None),
+            record(3, 50_000, vec![0, 1, 2, 3], 3000, (1.0, 4.0), None),
+            record(4, 2_000_000, vec![0, 1, 2, 3], 0, (1.0, 2.0), Some("insufficient balance")),
+        ];
+        let summary = Summary::new(&records, &ledger, &graph, &OnChain::default(), 0);
+
+        assert_eq!((summary.payments, summary.succeeded, summary.success_rate), (4, 3, 0.75));
+        let buckets: Vec<(u64, u64)> = summary.success_by_amount.iter().map(|bucket| (bucket.payments, bucket.succeeded)).collect();
+        assert_eq!(buckets, [(1, 1), (1, 1), (1, 1), (0, 0), (1, 0)]);
+        assert_eq!(summary.success_by_amount[4].max, None);
+        assert_eq!(summary.fees, Distribution { count: 3, mean: 2.0, p50: 2.0, p90: 3.0, p99: 3.0, max: 3.0 });
+        assert_eq!(summary.path_length, Distribution { count: 3, mean: 2.0, p50: 2.0, p90: 3.0, p99: 3.0, max: 3.0 });
+        assert_eq!(summary.latency, Distribution { count: 3, mean: 2.0, p50: 2.0, p90: 3.0, p99: 3.0, max: 3.0 });
+        assert_eq!(summary.failure_reasons, BTreeMap::from([("insufficient balance".to_string(), 1)]));
+        assert_eq!((summary.channels, summary.depleted_channels), (6, 1));
+        // Gini of (0, 0, 0, 2000): 2 * 4 * 2000 / (4 * 2000) - 5 / 4.
+        assert_eq!(summary.revenue_gini, 0.75);
+        let most_used: Vec<((usize, usize), u64)> = summary.most_used_channels.iter().map(|channel| (channel.channel, channel.payments)).collect();
+        assert_eq!(most_used, [((0, 1), 3), ((1, 2), 2), ((2, 3), 1)]);
+    }
+}
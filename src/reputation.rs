@@ -0,0 +1,154 @@
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::create_graph::EdgeAttributes;
+
+// Settings of the HTLC endorsement and local reputation mitigation against jamming, following the
+// local resource conservation proposal. When the mitigation is disabled, the router forwards every
+// HTLC as long as the channel limits allow it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EndorsementConfig {
+    pub enabled: bool, // Whether forwarding nodes apply the mitigation
+    pub general_slot_share: f32, // Share of the HTLC slots of a channel available to general traffic
+    pub general_liquidity_share: f32, // Share of the liquidity of a channel available to general traffic
+    pub revenue_window: Duration, // Window over which the revenue of an outgoing channel is tracked
+    pub reputation_multiplier: u32, // Length of the reputation window in revenue windows
+    pub resolution_period: Duration, // Time within which an honest HTLC is expected to resolve
+}
+
+// The defaults follow the proposal, with the time windows scaled down in the same way as the
+// default block interval of the simulated chain in chain.rs.
+impl Default for EndorsementConfig {
+    fn default() -> Self {
+        EndorsementConfig {
+            enabled: false,
+            general_slot_share: 0.5,
+            general_liquidity_share: 0.5,
+            revenue_window: Duration::from_secs(60),
+            reputation_multiplier: 10,
+            resolution_period: Duration::from_millis(500),
+        }
+    }
+}
+
+// Structure to hold the reputation state of every channel. The reputation is the one the node at the
+// end of the channel gives to its incoming peer at the start of the channel, while the revenue and
+// the general bucket belong to the channel as an outgoing channel of the node at its start.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Default)]
+pub struct ChannelReputation {
+    pub reputation: f32, // Reputation of the incoming peer, decaying over the reputation window
+    pub revenue: f32, // Fees in msat earned by forwarding over the channel, decaying over the revenue window
+    pub general_htlcs: u16, // Number of HTLCs in flight in the general bucket
+    pub general_in_flight: u64, // Value in msat of the HTLCs in flight in the general bucket
+    pub updated: Option<Instant>, // Time of the last decay of the reputation and the revenue
+}
+
+// Structure describing how an HTLC forwarded by a node resolved.
+#[derive(Debug, Clone, Copy)]
+pub struct Resolution {
+    pub fee: f32, // Fee in msat the forwarding node charged for the HTLC
+    pub hold_time: Duration, // Time between locking and resolving the HTLC
+    pub settled: bool, // Whether the HTLC was settled rather than failed or expired
+    pub endorsed: bool, // Whether the incoming HTLC was endorsed
+}
+
+// Function to decay the reputation and the revenue of a channel to the current time. Both are
+// exponentially decaying sums, so that old behaviour is gradually forgotten.
+fn decay(state: &mut ChannelReputation, config: EndorsementConfig) {
+    let now = Instant::now();
+    if let Some(updated) = state.updated {
+        let elapsed = now.duration_since(updated).as_secs_f32();
+        let reputation_window = config.revenue_window.as_secs_f32() * config.reputation_multiplier as f32;
+        state.reputation *= (-elapsed / reputation_window).exp();
+        state.revenue *= (-elapsed / config.revenue_window.as_secs_f32()).exp();
+    }
+    state.updated = Some(now);
+}
+
+// Function to check whether the incoming peer of a forwarding node has a good reputation for an HTLC
+// to be forwarded over the outgoing channel. The peer's reputation has to cover the revenue of the
+// outgoing channel plus the fee the HTLC would lose if it was held for its whole timelock.
+pub fn has_good_reputation(incoming: &mut ChannelReputation, outgoing: &mut ChannelReputation, fee: f32, max_hold: Duration, config: EndorsementConfig) -> bool {
+    decay(incoming, config);
+    decay(outgoing, config);
+    let risk = fee * max_hold.as_secs_f32() / config.resolution_period.as_secs_f32();
+    incoming.reputation >= outgoing.revenue + risk
+}
+
+// Function to check whether the general bucket of a channel has room for another HTLC.
+pub fn general_bucket_has_room(attrs: &EdgeAttributes, amount: u64, config: EndorsementConfig) -> bool {
+    let slots = (attrs.max_accepted_htlcs as f32 * config.general_slot_share) as u16;
+    let liquidity = ((attrs.balance + attrs.value_in_flight) as f64 * config.general_liquidity_share as f64) as u64;
+    attrs.reputation.general_htlcs < slots && attrs.reputation.general_in_flight + amount <= liquidity
+}
+
+// Function to update the reputation of the incoming peer and the revenue of the outgoing channel
+// once an HTLC forwarded between them has resolved. A settled HTLC earns its fee, while an endorsed
+// HTLC that was held for longer than the resolution period is charged for the time it was held.
+pub fn resolve(incoming: &mut ChannelReputation, outgoing: &mut ChannelReputation, resolution: Resolution, config: EndorsementConfig) {
+    decay(incoming, config);
+    decay(outgoing, config);
+    let mut effective_fee = if resolution.settled { resolution.fee } else { 0.0 };
+    if resolution.endorsed && resolution.hold_time > config.resolution_period {
+        effective_fee -= resolution.fee * (resolution.hold_time - config.resolution_period).as_secs_f32() / config.resolution_period.as_secs_f32();
+    }
+    incoming.reputation += effective_fee;
+    if resolution.settled {
+        outgoing.revenue += resolution.fee;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topology::new_channel;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() <= 1e-3 * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn general_bucket_holds_its_share_of_slots_and_liquidity() {
+        let config = EndorsementConfig::default();
+        let mut attrs = new_channel(1_000_000, 0, 0, 40);
+        attrs.max_accepted_htlcs = 10;
+        // Half of the 10 slots and of the 1_000_000 msat of the channel.
+        assert!(general_bucket_has_room(&attrs, 500_000, config));
+        assert!(!general_bucket_has_room(&attrs, 500_001, config));
+        attrs.reputation.general_htlcs = 4;
+        attrs.reputation.general_in_flight = 400_000;
+        assert!(general_bucket_has_room(&attrs, 100_000, config));
+        attrs.reputation.general_htlcs = 5;
+        assert!(!general_bucket_has_room(&attrs, 1, config));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resolved_htlcs_earn_their_fee_or_are_charged_for_their_hold_time() {
+        let config = EndorsementConfig::default();
+        let (mut incoming, mut outgoing) = (ChannelReputation::default(), ChannelReputation::default());
+        resolve(&mut incoming, &mut outgoing, Resolution { fee: 100.0, hold_time: Duration::from_millis(100), settled: true, endorsed: true }, config);
+        assert_eq!((incoming.reputation, outgoing.revenue), (100.0, 100.0));
+        // A failed endorsed HTLC held for one second past the resolution period of half a second is
+        // charged twice its fee, and earns the channel nothing.
+        resolve(&mut incoming, &mut outgoing, Resolution { fee: 100.0, hold_time: Duration::from_millis(1500), settled: false, endorsed: true }, config);
+        assert_eq!((incoming.reputation, outgoing.revenue), (-100.0, 100.0));
+        // An unendorsed HTLC is not charged for its hold time.
+        resolve(&mut incoming, &mut outgoing, Resolution { fee: 100.0, hold_time: Duration::from_secs(60), settled: false, endorsed: false }, config);
+        assert_eq!(incoming.reputation, -100.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reputation_covers_the_revenue_and_the_risk_of_the_htlc() {
+        let config = EndorsementConfig::default();
+        let mut incoming = ChannelReputation { reputation: 1000.0, ..ChannelReputation::default() };
+        let mut outgoing = ChannelReputation { revenue: 100.0, ..ChannelReputation::default() };
+        // A fee of 10 msat held for 10 seconds risks 10 * 10 / 0.5 = 200 msat.
+        assert!(has_good_reputation(&mut incoming, &mut outgoing, 10.0, Duration::from_secs(10), config));
+        assert!(!has_good_reputation(&mut incoming, &mut outgoing, 50.0, Duration::from_secs(10), config));
+        // After one revenue window, the revenue has decayed by e and the reputation, whose window is
+        // ten times longer, by e^0.1.
+        tokio::time::advance(config.revenue_window).await;
+        assert!(has_good_reputation(&mut incoming, &mut outgoing, 10.0, Duration::from_secs(10), config));
+        assert!(close(incoming.reputation, 1000.0 * (-0.1f32).exp()), "{}", incoming.reputation);
+        assert!(close(outgoing.revenue, 100.0 * (-1.0f32).exp()), "{}", outgoing.revenue);
+    }
+}
@@ -0,0 +1,209 @@
+use petgraph::graph::NodeIndex;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tokio::time::Instant;
+use crate::payment_router::Payment;
+
+// Header of the CSV file of the payment results, in the order of the fields of PaymentRecord.
+const CSV_HEADER: [&str; 13] = ["id", "sender", "recipient", "amount_msat", "path", "fees_msat", "attempts", "outcome", "failure_reason", "failure_hop", "start_time", "end_time", "lock_time"];
+
+// Sender, recipient and amount in msat of a payment as requested by the workload or the trace. They
+// are kept apart from the payment itself as no path may have been found for it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Request {
+    pub payment_id: u64,
+    pub sender: NodeIndex,
+    pub recipient: NodeIndex,
+    pub amount: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+// Structure to hold the outcome of a finished payment. The nodes are indices of the graph and the
+// times are virtual seconds since the start of the simulation.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct PaymentRecord {
+    pub id: u64,
+    pub sender: usize,
+    pub recipient: usize,
+    pub amount_msat: u64, // Amount delivered to the recipient
+    pub path: Vec<usize>,
+    pub fees_msat: u64, // Forwarding fees of a settled payment plus the upfront and hold fees paid
+    pub attempts: u32, // Number of paths tried
+    pub outcome: Outcome,
+    pub failure_reason: Option<String>,
+    pub failure_hop: Option<usize>, // Node that failed the HTLC
+    pub start_time: f64,
+    pub end_time: f64,
+    pub lock_time: f64, // Time every node in the path had its funds locked, summed over the path
+}
+
+impl PaymentRecord {
+    // Function to build the record of a finished payment.
+    pub fn new(request: &Request, payment: &Payment, settled: bool, start_time: f64, end_time: f64) -> Self {
+        let path: Vec<usize> = payment.path().iter().map(|node| node.index()).collect();
+        let amounts = payment.amounts();
+        let forwarding_fees = if settled && !amounts.is_empty() { amounts[0] - amounts[amounts.len() - 1] } else { 0 };
+        let failure_reason = if settled {
+            None
+        } else if path.len() < 2 {
+            Some("no path found".to_string())
+        } else {
+            Some(payment.failure().unwrap_or("timelock expired").to_string())
+        };
+        PaymentRecord {
+            id: request.payment_id,
+            sender: request.sender.index(),
+            recipient: request.recipient.index(),
+            amount_msat: request.amount,
+            fees_msat: forwarding_fees + payment.upfront_and_hold_fees(),
+            attempts: if path.len() < 2 { 0 } else { 1 },
+            outcome: if settled { Outcome::Success } else { Outcome::Failure },
+            failure_reason,
+            failure_hop: payment.failed_at().filter(|_| !settled).map(|i| path[i]),
+            start_time,
+            end_time,
+            // Folded from zero, as the sum of no floats is -0.0 and would be written as such.
+            lock_time: payment.hold_times().iter().fold(0.0, |total, time| total + time.as_secs_f64()),
+            path,
+        }
+    }
+
+    // Fields of the record as a row of the CSV file. The path is written as the nodes separated by
+    // dashes, and missing values as empty fields.
+    fn csv_row(&self) -> [String; 13] {
+        let path: Vec<String> = self.path.iter().map(|node| node.to_string()).collect();
+        [
+            self.id.to_string(),
+            self.sender.to_string(),
+            self.recipient.to_string(),
+            self.amount_msat.to_string(),
+            path.join("-"),
+            self.fees_msat.to_string(),
+            self.attempts.to_string(),
+            if self.outcome == Outcome::Success { "success" } else { "failure" }.to_string(),
+            self.failure_reason.clone().unwrap_or_default(),
+            self.failure_hop.map_or(String::new(), |hop| hop.to_string()),
+            self.start_time.to_string(),
+            self.end_time.to_string(),
+            self.lock_time.to_string(),
+        ]
+    }
+}
+
+// Writer of the per-payment results, as CSV and as JSON Lines with one record per line. The records
+// are kept as well for the summary report at the end of the run.
+pub struct Results {
+    start: Instant, // Start of the simulation
+    records: Vec<PaymentRecord>,
+    csv: Option<csv::Writer<File>>,
+    jsonl: Option<BufWriter<File>>,
+}
+
+// By default the results are not written anywhere.
+impl Default for Results {
+    fn default() -> Self {
+        Results { start: Instant::now(), records: Vec::new(), csv: None, jsonl: None }
+    }
+}
+
+impl Results {
+    // Function to create the result files payments.csv and payments.jsonl in the output directory,
+    // as requested.
+    pub fn create<P: AsRef<Path>>(dir: P, csv: bool, jsonl: bool) -> Result<Self, String> {
+        let dir = dir.as_ref();
+        let mut results = Results::default();
+        if csv {
+            let mut writer = csv::Writer::from_path(dir.join("payments.csv")).map_err(|e| e.to_string())?;
+            writer.write_record(CSV_HEADER).map_err(|e| e.to_string())?;
+            results.csv = Some(writer);
+        }
+        if jsonl {
+            let file = File::create(dir.join("payments.jsonl")).map_err(|e| e.to_string())?;
+            results.jsonl = Some(BufWriter::new(file));
+        }
+        Ok(results)
+    }
+
+    // Function to write the record of a finished payment that was started at the given time.
+    pub fn record(&mut self, request: &Request, payment: &Payment, settled: bool, started: Instant) -> Result<(), String> {
+        let start_time = started.duration_since(self.start).as_secs_f64();
+        let end_time = self.start.elapsed().as_secs_f64();
+        let record = PaymentRecord::new(request, payment, settled, start_time, end_time);
+        if let Some(writer) = &mut self.csv {
+            writer.write_record(record.csv_row()).map_err(|e| e.to_string())?;
+        }
+        if let Some(writer) = &mut self.jsonl {
+            serde_json::to_writer(&mut *writer, &record).map_err(|e| e.to_string())?;
+            writer.write_all(b"\n").map_err(|e| e.to_string())?;
+        }
+        self.records.push(record);
+        Ok(())
+    }
+
+    // Records of the payments finished so far, in the order they finished.
+    pub fn records(&self) -> &[PaymentRecord] {
+        &self.records
+    }
+
+    // Function to write out the records still buffered.
+    pub fn flush(&mut self) -> Result<(), String> {
+        if let Some(writer) = &mut self.csv {
+            writer.flush().map_err(|e| e.to_string())?;
+        }
+        if let Some(writer) = &mut self.jsonl {
+            writer.flush().map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+
+    fn request(payment_id: u64) -> Request {
+        Request { payment_id, sender: NodeIndex::new(0), recipient: NodeIndex::new(2), amount: 100_000 }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn records_are_written_as_csv_and_json_lines() {
+        let dir = std::env::temp_dir().join(format!("lnsimulator-results-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut results = Results::create(&dir, true, true).unwrap();
+        let started = Instant::now();
+        tokio::time::advance(Duration::from_millis(1500)).await;
+        let path = vec![NodeIndex::new(0), NodeIndex::new(1), NodeIndex::new(2)];
+        let settled = Payment::new(1, path, vec![200, 160, 120], vec![101_000, 100_000, 100_000]);
+        results.record(&request(1), &settled, true, started).unwrap();
+        let unroutable = Payment::new(2, Vec::new(), Vec::new(), Vec::new());
+        results.record(&request(2), &unroutable, false, started).unwrap();
+        results.flush().unwrap();
+
+        let csv = fs::read_to_string(dir.join("payments.csv")).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines, [
+            "id,sender,recipient,amount_msat,path,fees_msat,attempts,outcome,failure_reason,failure_hop,start_time,end_time,lock_time",
+            "1,0,2,100000,0-1-2,1000,1,success,,,0,1.5,0",
+            "2,0,2,100000,,0,0,failure,no path found,,0,1.5,0",
+        ]);
+        let jsonl = fs::read_to_string(dir.join("payments.jsonl")).unwrap();
+        let records: Vec<serde_json::Value> = jsonl.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records[0], serde_json::json!({
+            "id": 1, "sender": 0, "recipient": 2, "amount_msat": 100000, "path": [0, 1, 2], "fees_msat": 1000, "attempts": 1,
+            "outcome": "success", "failure_reason": null, "failure_hop": null, "start_time": 0.0, "end_time": 1.5, "lock_time": 0.0,
+        }));
+        assert_eq!((records[1]["outcome"].as_str(), records[1]["failure_reason"].as_str()), (Some("failure"), Some("no path found")));
+        assert_eq!(results.records().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,538 @@
+use petgraph::graph::{DiGraph, NodeIndex};
+use rand::Rng;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::chain::ChainConfig;
+use crate::create_graph::{self, BalanceStrategy, EdgeAttributes};
+use crate::fee_agents::FeeAgent;
+use crate::find_path::CostModel;
+use crate::gossip::GossipConfig;
+use crate::jamming::{Attacker, AttackerConfig};
+use crate::latency::{self, LatencyModel};
+use crate::autopilot::Autopilot;
+use crate::liquidity::{LiquidityAgent, LiquidityConfig};
+use crate::payment_scheduler::{Convergence, StopConditions};
+use crate::rebalancing::Rebalancer;
+use crate::reputation::EndorsementConfig;
+use crate::sweep::Sweep;
+use crate::topology::TopologyConfig;
+use crate::workload::Workload;
+
+// Source of the graph of a scenario and how its channels are initialised.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GraphSource {
+    pub path: PathBuf, // Snapshot in the networkx JSON or the CSV channel list format
+    #[serde(default)]
+    pub balances: BalanceStrategy, // Split of the capacity of every channel between its directions
+    pub max_accepted_htlcs: Option<u16>, // HTLC slot limit applied to every channel
+    pub max_htlc_value_in_flight: Option<f64>, // Value in flight limit in satoshis applied to every channel
+}
+
+// Payment trace replayed instead of the workload.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TraceSource {
+    pub path: PathBuf,
+    #[serde(default = "default_time_scale")]
+    pub time_scale: f64, // Factor applied to the recorded times of the trace
+}
+
+fn default_time_scale() -> f64 {
+    1.0
+}
+
+// Settings of the endorsement mitigation as written in a scenario file, with the time windows in
+// seconds and milliseconds.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct EndorsementSection {
+    enabled: bool,
+    general_slot_share: f32,
+    general_liquidity_share: f32,
+    revenue_window_secs: f64,
+    reputation_multiplier: u32,
+    resolution_period_ms: f64,
+}
+
+impl Default for EndorsementSection {
+    fn default() -> Self {
+        let config = EndorsementConfig::default();
+        EndorsementSection {
+            enabled: config.enabled,
+            general_slot_share: config.general_slot_share,
+            general_liquidity_share: config.general_liquidity_share,
+            revenue_window_secs: config.revenue_window.as_secs_f64(),
+            reputation_multiplier: config.reputation_multiplier,
+            resolution_period_ms: config.resolution_period.as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+// Settings of the simulated chain as written in a scenario file, with the block interval in seconds
+// and the force close fee in satoshis.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ChainSection {
+    block_interval_secs: f64,
+    start_height: u32,
+    force_close_fee: f64,
+    to_self_delay: u32,
+}
+
+impl Default for ChainSection {
+    fn default() -> Self {
+        let config = ChainConfig::default();
+        ChainSection {
+            block_interval_secs: config.block_interval.as_secs_f64(),
+            start_height: config.start_height,
+            force_close_fee: create_graph::msat_to_sat(config.force_close_fee),
+            to_self_delay: config.to_self_delay,
+        }
+    }
+}
+
+// Stop conditions as written in a scenario file, with the duration in virtual seconds.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StopSection {
+    max_payments: Option<u64>,
+    duration_secs: Option<f64>,
+    convergence: Option<Convergence>,
+}
+
+// Outputs of a scenario. The log is always written to the output directory, and the per-payment
+// results and the summary report as requested.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Output {
+    pub dir: PathBuf,
+    pub payments_csv: bool, // Whether to write the result of every payment to payments.csv
+    pub payments_jsonl: bool, // Whether to write the result of every payment to payments.jsonl
+    pub summary: bool, // Whether to write the summary report to summary.json and summary.txt
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output { dir: PathBuf::from("Path_to_log_file"), payments_csv: true, payments_jsonl: true, summary: true }
+    }
+}
+
+// Layout of a scenario file. Sections left out take their default values.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioFile {
+    seed: Option<u64>,
+    graph: GraphSource,
+    routing: Option<CostModel>,
+    #[serde(default)]
+    workload: Option<Workload>,
+    trace: Option<TraceSource>,
+    #[serde(default)]
+    attackers: Vec<AttackerConfig>,
+    #[serde(default)]
+    latency: LatencyModel,
+    #[serde(default)]
+    endorsement: EndorsementSection,
+    #[serde(default)]
+    chain: ChainSection,
+    #[serde(default)]
+    topology: TopologyConfig,
+    #[serde(default)]
+    gossip: GossipConfig,
+    #[serde(default)]
+    fee_agents: Vec<FeeAgent>,
+    #[serde(default)]
+    rebalancers: Vec<Rebalancer>,
+    #[serde(default)]
+    liquidity: LiquidityConfig,
+    #[serde(default)]
+    liquidity_agents: Vec<LiquidityAgent>,
+    #[serde(default)]
+    autopilots: Vec<Autopilot>,
+    #[serde(default)]
+    stop: StopSection,
+    #[serde(default)]
+    output: Output,
+    sweep: Option<Sweep>,
+}
+
+// Structure to hold a full experiment: the graph, the payments, the attackers, the network and
+// mitigation settings, when to stop and where to write the results.
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    pub seed: u64,
+    pub graph: GraphSource,
+    pub workload: Workload,
+    pub trace: Option<TraceSource>,
+    pub attackers: Vec<Attacker>,
+    pub latency: LatencyModel,
+    pub endorsement: EndorsementConfig,
+    pub chain: ChainConfig,
+    pub topology: TopologyConfig, // Channel opens and closes and nodes joining and leaving during the run
+    pub gossip: GossipConfig, // Views of the network the senders find paths on
+    pub fee_agents: Vec<FeeAgent>, // Strategies of the routing nodes that change their fees
+    pub rebalancers: Vec<Rebalancer>, // Routing nodes that refill their depleted channels with circular payments
+    pub liquidity: LiquidityConfig, // Confirmation delay and costs of swaps and splices
+    pub liquidity_agents: Vec<LiquidityAgent>, // Policies of the nodes that swap or splice liquidity
+    pub autopilots: Vec<Autopilot>, // Nodes that open channels to the peers of their strategy
+    pub stop: StopConditions,
+    pub output: Output,
+    pub sweep: Option<Sweep>, // Parameters varied by the sweep subcommand
+}
+
+// The default scenario runs the default workload on the July 2022 snapshot for 10000 seconds.
+impl Default for Scenario {
+    fn default() -> Self {
+        Scenario {
+            seed: 1,
+            graph: GraphSource {
+                path: PathBuf::from("Path_to_json_file/json_graph1.json"),
+                balances: BalanceStrategy::Snapshot,
+                max_accepted_htlcs: None,
+                max_htlc_value_in_flight: None,
+            },
+            workload: Workload::default(),
+            trace: None,
+            attackers: Vec::new(),
+            latency: LatencyModel::None,
+            endorsement: EndorsementConfig::default(),
+            chain: ChainConfig::default(),
+            topology: TopologyConfig::default(),
+            gossip: GossipConfig::default(),
+            fee_agents: Vec::new(),
+            rebalancers: Vec::new(),
+            liquidity: LiquidityConfig::default(),
+            liquidity_agents: Vec::new(),
+            autopilots: Vec::new(),
+            stop: StopConditions { max_duration: Some(Duration::from_secs(10000)), ..Default::default() },
+            output: Output::default(),
+            sweep: None,
+        }
+    }
+}
+
+impl Scenario {
+    // Function to read a scenario from a TOML file, for example
+    //
+    // seed = 7
+    // [graph]
+    // path = "Path_to_json_file/json_graph1.json"
+    // balances = "random"
+    // [routing]
+    // risk_factor = 0.0000000015
+    // [workload]
+    // arrivals = { type = "poisson", rate = 50.0 }
+    // amounts = { type = "log_normal", mu = 8.0, sigma = 1.5 }
+    // [[workload.node_classes]]
+    // name = "exchanges"
+    // nodes = [1, 2]
+    // sender_weight = 20.0
+    // recipient_weight = 20.0
+    // cost_model = { risk_factor = 0.000000015 }
+    // [[attackers]]
+    // strategy = "slow"
+    // sender = 3
+    // recipient = 5
+    // target = [4, 6]
+    // amount = 1.0
+    // htlcs = 483
+    // [latency]
+    // type = "uniform"
+    // min_ms = 10.0
+    // max_ms = 100.0
+    // [chain]
+    // block_interval_secs = 600.0
+    // force_close_fee = 5000.0
+    // [topology]
+    // script = "topology.json"
+    // churn = { open_rate = 0.01, close_rate = 0.01, join_rate = 0.001, leave_rate = 0.001 }
+    // [gossip]
+    // enabled = true
+    // views = "per_class"
+    // delay_secs = 60.0
+    // [[fee_agents]]
+    // strategy = "proportional"
+    // min_ppm = 10
+    // max_ppm = 2000
+    // nodes = [1, 2]
+    // interval_secs = 600.0
+    // [[rebalancers]]
+    // node = 4
+    // threshold = 0.2
+    // max_fee_ppm = 500
+    // [liquidity]
+    // confirmation_blocks = 6
+    // onchain_fee = 2000.0
+    // [[liquidity_agents]]
+    // node = 4
+    // action = "loop_out"
+    // threshold = 0.9
+    // target = 0.5
+    // [[autopilots]]
+    // strategy = "preferential_attachment"
+    // budget = 10000000.0
+    // channel_capacity = 2000000.0
+    // interval_secs = 3600.0
+    // [stop]
+    // max_payments = 100000
+    // convergence = { window = 1000, tolerance = 0.001 }
+    // [output]
+    // dir = "results"
+    // payments_jsonl = false
+    // [sweep]
+    // amounts = [1000.0, 100000.0]
+    // balances = ["even", "random"]
+    // replications = 20
+    //
+    // The scenario is only read here, as settings such as the paths and the stop conditions may still
+    // be overridden, and validate has to be called once they are final. Errors name the offending
+    // setting.
+    pub fn load<P: AsRef<Path>>(file_path: P) -> Result<Scenario, String> {
+        let file_path = file_path.as_ref();
+        let file_content = fs::read_to_string(file_path).map_err(|e| format!("{}: {}", file_path.display(), e))?;
+        Scenario::parse(&file_content).map_err(|e| format!("{}: {}", file_path.display(), e))
+    }
+
+    // Function to read a scenario from the contents of a TOML file.
+    pub fn parse(file_content: &str) -> Result<Scenario, String> {
+        let file: ScenarioFile = toml::from_str(file_content).map_err(|e| e.to_string())?;
+        let mut workload = file.workload.unwrap_or_default();
+        if let Some(cost_model) = file.routing {
+            workload.cost_model = cost_model;
+        }
+        workload.load_samples().map_err(|e| format!("workload: {}", e))?;
+        let endorsement = EndorsementConfig {
+            enabled: file.endorsement.enabled,
+            general_slot_share: file.endorsement.general_slot_share,
+            general_liquidity_share: file.endorsement.general_liquidity_share,
+            revenue_window: seconds(file.endorsement.revenue_window_secs, "endorsement.revenue_window_secs")?,
+            reputation_multiplier: file.endorsement.reputation_multiplier,
+            resolution_period: seconds(file.endorsement.resolution_period_ms / 1000.0, "endorsement.resolution_period_ms")?,
+        };
+        if file.chain.force_close_fee < 0.0 {
+            return Err("chain: force_close_fee must not be negative".to_string());
+        }
+        let chain = ChainConfig {
+            block_interval: seconds(file.chain.block_interval_secs, "chain.block_interval_secs")?,
+            start_height: file.chain.start_height,
+            force_close_fee: create_graph::sat_to_msat(file.chain.force_close_fee),
+            to_self_delay: file.chain.to_self_delay,
+        };
+        let stop = StopConditions {
+            max_payments: file.stop.max_payments,
+            max_duration: file.stop.duration_secs.map(|secs| seconds(secs, "stop.duration_secs")).transpose()?,
+            convergence: file.stop.convergence,
+        };
+        let scenario = Scenario {
+            seed: file.seed.unwrap_or(1),
+            graph: file.graph,
+            workload,
+            trace: file.trace,
+            attackers: file.attackers.into_iter().map(Attacker::from).collect(),
+            latency: file.latency,
+            endorsement,
+            chain,
+            topology: file.topology,
+            gossip: file.gossip,
+            fee_agents: file.fee_agents,
+            rebalancers: file.rebalancers,
+            liquidity: file.liquidity,
+            liquidity_agents: file.liquidity_agents,
+            autopilots: file.autopilots,
+            stop,
+            output: file.output,
+            sweep: file.sweep,
+        };
+        Ok(scenario)
+    }
+
+    // Function to check that the settings of the scenario make sense and that its files exist.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.graph.path.is_file() {
+            return Err(format!("graph: snapshot {} not found", self.graph.path.display()));
+        }
+        if self.graph.max_accepted_htlcs == Some(0) {
+            return Err("graph: max_accepted_htlcs must be positive".to_string());
+        }
+        if self.graph.max_htlc_value_in_flight.is_some_and(|value| value <= 0.0) {
+            return Err("graph: max_htlc_value_in_flight must be positive".to_string());
+        }
+        if let Some(trace) = &self.trace {
+            if !trace.path.is_file() {
+                return Err(format!("trace: {} not found", trace.path.display()));
+            }
+            if trace.time_scale <= 0.0 {
+                return Err("trace: time_scale must be positive".to_string());
+            }
+        }
+        self.workload.validate().map_err(|e| format!("workload: {}", e))?;
+        self.latency.validate().map_err(|e| format!("latency: {}", e))?;
+        for (i, attacker) in self.attackers.iter().enumerate() {
+            if attacker.htlcs == 0 || attacker.amount == 0 {
+                return Err(format!("attackers: attacker {} needs a positive amount and number of HTLCs", i));
+            }
+        }
+        let shares = [self.endorsement.general_slot_share, self.endorsement.general_liquidity_share];
+        if shares.iter().any(|share| !(0.0..=1.0).contains(share)) {
+            return Err("endorsement: general shares must be between 0 and 1".to_string());
+        }
+        if self.endorsement.revenue_window.is_zero() || self.endorsement.resolution_period.is_zero() || self.endorsement.reputation_multiplier == 0 {
+            return Err("endorsement: windows and the reputation multiplier must be positive".to_string());
+        }
+        if self.chain.block_interval.is_zero() {
+            return Err("chain: block_interval_secs must be positive".to_string());
+        }
+        self.topology.validate().map_err(|e| format!("topology: {}", e))?;
+        self.gossip.validate().map_err(|e| format!("gossip: {}", e))?;
+        for (i, agent) in self.fee_agents.iter().enumerate() {
+            agent.validate().map_err(|e| format!("fee_agents: agent {}: {}", i, e))?;
+        }
+        for (i, rebalancer) in self.rebalancers.iter().enumerate() {
+            rebalancer.validate().map_err(|e| format!("rebalancers: rebalancer {}: {}", i, e))?;
+        }
+        self.liquidity.validate().map_err(|e| format!("liquidity: {}", e))?;
+        for (i, agent) in self.liquidity_agents.iter().enumerate() {
+            agent.validate().map_err(|e| format!("liquidity_agents: agent {}: {}", i, e))?;
+        }
+        for (i, autopilot) in self.autopilots.iter().enumerate() {
+            autopilot.validate().map_err(|e| format!("autopilots: autopilot {}: {}", i, e))?;
+        }
+        if let Some(convergence) = self.stop.convergence {
+            if convergence.window == 0 || convergence.tolerance <= 0.0 {
+                return Err("stop: convergence needs a positive window and tolerance".to_string());
+            }
+        }
+        if let Some(sweep) = &self.sweep {
+            sweep.validate().map_err(|e| format!("sweep: {}", e))?;
+        }
+        if self.trace.is_none() && self.stop == StopConditions::default() {
+            return Err("stop: a stop condition is needed as the workload never ends".to_string());
+        }
+        Ok(())
+    }
+
+    // Function to build the graph of the scenario: the snapshot with its balances initialised, the
+    // HTLC limits applied and the latency of every channel drawn from the latency model.
+    pub fn build_graph<R: Rng + ?Sized>(&self, rng: &mut R) -> Result<DiGraph<usize, EdgeAttributes>, String> {
+        let mut graph = create_graph::load_graph(&self.graph.path)?;
+        create_graph::init_balances(&mut graph, self.graph.balances, rng);
+        create_graph::set_htlc_limits(&mut graph, self.graph.max_accepted_htlcs, self.graph.max_htlc_value_in_flight.map(create_graph::sat_to_msat));
+        latency::set_latencies(&mut graph, &self.latency, rng).map_err(|e| format!("latency: {}", e))?;
+        Ok(graph)
+    }
+
+    // Function to convert the nodes the scenario names by their ids in the snapshot, as the trace, the
+    // topology script and the route subcommand do, into their indices in the graph, which the
+    // workload and the agents work with during the run.
+    pub fn resolve_nodes(&mut self, graph: &DiGraph<usize, EdgeAttributes>) -> Result<(), String> {
+        let indices: HashMap<usize, usize> = graph.node_indices().map(|index| (graph[index], index.index())).collect();
+        let index = |section: &str, id: usize| indices.get(&id).copied().ok_or(format!("{}: node {} is not in the snapshot", section, id));
+        for class in &mut self.workload.node_classes {
+            for node in &mut class.nodes {
+                *node = index("workload", *node)?;
+            }
+        }
+        for attacker in &mut self.attackers {
+            for node in [&mut attacker.sender, &mut attacker.recipient, &mut attacker.target.0, &mut attacker.target.1] {
+                *node = NodeIndex::new(index("attackers", node.index())?);
+            }
+        }
+        for agent in &mut self.fee_agents {
+            for node in agent.nodes.iter_mut().flatten() {
+                *node = index("fee_agents", *node)?;
+            }
+        }
+        for rebalancer in &mut self.rebalancers {
+            rebalancer.node = index("rebalancers", rebalancer.node)?;
+        }
+        for agent in &mut self.liquidity_agents {
+            agent.node = index("liquidity_agents", agent.node)?;
+        }
+        for node in self.autopilots.iter_mut().filter_map(|autopilot| autopilot.node.as_mut()) {
+            *node = index("autopilots", *node)?;
+        }
+        Ok(())
+    }
+}
+
+// Function to reject the keys of a table that are neither among the fields of a structure nor among
+// those of its strategy, for the structures that flatten their strategy into their own fields.
+pub fn deny_unknown_fields<E: serde::de::Error>(table: &toml::Table, fields: &[&str], strategy_fields: &[&str]) -> Result<(), E> {
+    match table.keys().find(|key| !fields.contains(&key.as_str()) && !strategy_fields.contains(&key.as_str())) {
+        Some(key) => Err(E::custom(format!("unknown field `{}`, expected one of {:?}", key, [fields, strategy_fields].concat()))),
+        None => Ok(()),
+    }
+}
+
+// Function to convert a number of seconds from a scenario file into a duration.
+fn seconds(secs: f64, setting: &str) -> Result<Duration, String> {
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("{} must be a non-negative number of seconds", setting))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use petgraph::graph::NodeIndex;
+
+    const SCENARIO: &str = "[graph]\npath = \"missing.json\"\n[stop]\nmax_payments = 10\n[[attackers]]\nstrategy = \"slow\"\nsender = 0\nrecipient = 1\ntarget = [2, 3]\namount = 1.0\nhtlcs = 483\n";
+
+    #[test]
+    fn parse_leaves_validation_to_the_caller() {
+        let mut scenario = Scenario::parse(SCENARIO).unwrap();
+        assert_eq!(scenario.attackers[0].target, (NodeIndex::new(2), NodeIndex::new(3)));
+        assert!(scenario.validate().is_err());
+        scenario.graph.path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+        assert!(scenario.validate().is_ok());
+    }
+
+    #[test]
+    fn unknown_attacker_fields_are_rejected() {
+        assert!(Scenario::parse(&format!("{}htlc = 483\n", SCENARIO)).is_err());
+    }
+
+    #[test]
+    fn attackers_need_an_amount_and_a_number_of_htlcs() {
+        assert!(Scenario::parse(&SCENARIO.replace("amount = 1.0\n", "")).is_err());
+        assert!(Scenario::parse(&SCENARIO.replace("htlcs = 483\n", "")).is_err());
+    }
+
+    #[test]
+    fn unknown_workload_fields_are_rejected() {
+        let workload = "[workload]\narrivals = { type = \"poisson\", rate = 5.0 }\namounts = { type = \"fixed\", amount = 1000.0 }\ncost_model = { risk_factor = 0.0 }\n[[workload.node_classes]]\nname = \"exchanges\"\nnodes = [0]\nsender_weight = 2.0\nrecipient_weight = 2.0\n";
+        assert!(Scenario::parse(&format!("{}{}", SCENARIO, workload)).is_ok());
+        for typo in [("rate = 5.0", "rate = 5.0, burst = 2.0"), ("risk_factor", "risk_facter"), ("sender_weight", "sender_wieght"), ("[workload]\n", "[workload]\nseed = 1\n")] {
+            assert!(Scenario::parse(&format!("{}{}", SCENARIO, workload.replace(typo.0, typo.1))).is_err(), "{:?}", typo);
+        }
+    }
+
+    #[test]
+    fn zero_htlc_slots_are_rejected() {
+        let mut scenario = Scenario::parse(SCENARIO).unwrap();
+        scenario.graph.path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+        scenario.graph.max_accepted_htlcs = Some(0);
+        assert!(scenario.validate().is_err());
+        scenario.graph.max_accepted_htlcs = Some(30);
+        assert!(scenario.validate().is_ok());
+    }
+
+    #[test]
+    fn nodes_are_named_by_their_snapshot_ids() {
+        let mut graph = DiGraph::new();
+        for id in [7, 3, 5] {
+            graph.add_node(id);
+        }
+        let scenario = "[graph]\npath = \"missing.json\"\n[workload]\narrivals = { type = \"poisson\", rate = 5.0 }\namounts = { type = \"fixed\", amount = 1000.0 }\n[[workload.node_classes]]\nname = \"merchants\"\nnodes = [3, 7]\nsender_weight = 2.0\nrecipient_weight = 2.0\n[[rebalancers]]\nnode = 5\n[[attackers]]\nstrategy = \"slow\"\nsender = 7\nrecipient = 5\ntarget = [3, 5]\namount = 1.0\nhtlcs = 10\n";
+        let mut resolved = Scenario::parse(scenario).unwrap();
+        resolved.resolve_nodes(&graph).unwrap();
+        assert_eq!(resolved.workload.node_classes[0].nodes, vec![1, 0]);
+        assert_eq!(resolved.rebalancers[0].node, 2);
+        let attacker = &resolved.attackers[0];
+        assert_eq!((attacker.sender, attacker.recipient, attacker.target), (NodeIndex::new(0), NodeIndex::new(2), (NodeIndex::new(1), NodeIndex::new(2))));
+        let mut unknown = Scenario::parse(&scenario.replace("node = 5", "node = 2")).unwrap();
+        assert_eq!(unknown.resolve_nodes(&graph), Err("rebalancers: node 2 is not in the snapshot".to_string()));
+    }
+}
@@ -0,0 +1,128 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use log::{info, error};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use crate::autopilot;
+use crate::fee_agents;
+use crate::gossip::Gossip;
+use crate::jamming;
+use crate::liquidity;
+use crate::ledger::Ledger;
+use crate::network::Network;
+use crate::payment_scheduler::{self, PaymentHook, Progress, Recorder};
+use crate::rebalancing;
+use crate::report::{self, Summary};
+use crate::results::Results;
+use crate::scenario::Scenario;
+use crate::topology;
+use crate::trace;
+
+// Function to run the simulation of a scenario. The per-payment results and the summary report are
+// written to the output directory as requested by the scenario, and the summary is returned. The
+// simulation has to run on a runtime with a paused clock, so that the simulated time does not
+// depend on the machine.
+pub async fn simulate(scenario: Scenario) -> Result<Summary, String> {
+    simulate_with_hooks(scenario, Vec::new()).await
+}
+
+// Function to run the simulation of a scenario and call the hooks with the record of every payment
+// as soon as it finishes.
+pub async fn simulate_with_hooks(mut scenario: Scenario, hooks: Vec<PaymentHook>) -> Result<Summary, String> {
+    let output = scenario.output.clone();
+    info!("Starting the program");
+    // Seed of the simulation. Every random decision is drawn from generators seeded from it.
+    let mut rng = StdRng::seed_from_u64(scenario.seed);
+    // Obtain the graph structure from the snapshot, with the balances, HTLC limits and latencies of
+    // the scenario, and find the nodes the scenario names by their snapshot ids in it.
+    let graph = scenario.build_graph(&mut StdRng::seed_from_u64(rng.gen()))?;
+    scenario.resolve_nodes(&graph)?;
+    let depleted_at_start = report::depleted_channels(&graph);
+    let scheduler_rng = StdRng::seed_from_u64(rng.gen());
+    let topology_rng = StdRng::seed_from_u64(rng.gen());
+    let gossip_rng = StdRng::seed_from_u64(rng.gen());
+    let autopilot_rng = StdRng::seed_from_u64(rng.gen());
+    // Convert the graph so that it can be shared across concurrent payments. With gossip, every
+    // sender finds paths on the view of the network of its node class or its own.
+    let gossip = scenario.gossip.enabled.then(|| Gossip::new(scenario.gossip, &graph, &scenario.workload.node_classes, gossip_rng));
+    let mut network = Network::with_chain(graph, scenario.chain);
+    if let Some(gossip) = gossip {
+        network = network.with_gossip(gossip);
+    }
+    let network = Arc::new(network);
+    // Jamming attackers that compete with the scheduled payments. Without attackers, only honest
+    // payments are simulated.
+    let attackers = scenario.attackers;
+    let jamming_report = Arc::new(Mutex::new(jamming::JammingReport::default()));
+    // HTLC endorsement and local reputation mitigation against jamming. Enable it to compare runs
+    // with and without the mitigation on the same workload.
+    let endorsement = scenario.endorsement;
+    // Workload of the simulation, or the payment trace to replay instead of it. The time scale of
+    // the trace multiplies the recorded times, so that a scale below one replays the trace faster.
+    let workload = scenario.workload;
+    // Rebalancers find their circular routes with the cost models of their nodes.
+    let rebalancers = scenario.rebalancers.into_iter().map(|rebalancer| (rebalancer, workload.cost_model_of(rebalancer.node))).collect();
+    let trace = match scenario.trace {
+        Some(source) => Some((trace::load_trace(&source.path).map_err(|e| format!("{}: {}", source.path.display(), e))?, source.time_scale)),
+        None => None,
+    };
+    // Changes to the topology while the payments run, from the script and the churn model.
+    let topology_script = match &scenario.topology.script {
+        Some(path) => topology::load_script(path).map_err(|e| format!("{}: {}", path.display(), e))?,
+        None => Vec::new(),
+    };
+    // Conditions under which no new payment is started. The simulation ends once the payments in
+    // flight have finished, so that no payment is cut off before the final statistics.
+    let stop = scenario.stop;
+    // Records of the finished payments: the ledger of the fees earned and paid by every node and
+    // channel, the progress of the simulation and the per-payment results.
+    let results = Results::create(&output.dir, output.payments_csv, output.payments_jsonl)?;
+    let mut recorder = Recorder::new(Ledger::default(), Progress::new(stop.convergence), results);
+    for hook in hooks {
+        recorder.add_hook(hook);
+    }
+    // Flag telling the attackers to stop once the scheduler has drained.
+    let stopped = Arc::new(AtomicBool::new(false));
+    let scheduler = async {
+        let result = match trace {
+            Some((trace, time_scale)) => trace::replay_trace(Arc::clone(&network), trace, time_scale, &workload, endorsement, recorder.clone(), stop).await,
+            None => payment_scheduler::schedule_payments(Arc::clone(&network), workload, scheduler_rng, endorsement, recorder.clone(), stop).await,
+        };
+        if let Err(e) = result {
+            error!("Scheduler failed: {}", e);
+        }
+        stopped.store(true, Ordering::Relaxed);
+    };
+    let topology = topology::run_topology(Arc::clone(&network), topology_script, scenario.topology.churn, scenario.latency.clone(), topology_rng, Arc::clone(&stopped));
+    let fee_agents = fee_agents::run_fee_agents(Arc::clone(&network), scenario.fee_agents, Arc::clone(&recorder.ledger), Arc::clone(&stopped));
+    let rebalancers = rebalancing::run_rebalancers(Arc::clone(&network), rebalancers, endorsement, Arc::clone(&recorder.ledger), Arc::clone(&stopped));
+    let liquidity_agents = liquidity::run_liquidity_agents(Arc::clone(&network), scenario.liquidity_agents, scenario.liquidity, Arc::clone(&stopped));
+    let autopilots = autopilot::run_autopilots(Arc::clone(&network), scenario.autopilots, scenario.latency.clone(), Arc::clone(&recorder.ledger), autopilot_rng, Arc::clone(&stopped));
+    tokio::join!(scheduler, jamming::run_attackers(Arc::clone(&network), attackers.clone(), endorsement, Arc::clone(&recorder.ledger), Arc::clone(&jamming_report), Arc::clone(&stopped)), topology, fee_agents, rebalancers, liquidity_agents, autopilots);
+    info!("Simulation finished");
+
+    // Summary report of the run with the standard metrics.
+    let summary = {
+        let mut results = recorder.results.lock().map_err(|e| e.to_string())?;
+        let ledger = recorder.ledger.lock().map_err(|e| e.to_string())?;
+        results.flush()?;
+        Summary::new(results.records(), &ledger, &network.snapshot(), &network.onchain(), depleted_at_start)
+    };
+    if output.summary {
+        summary.write(&output.dir)?;
+    }
+    if let Ok(progress) = recorder.progress.lock() {
+        progress.log_report();
+    }
+    if let Ok(ledger) = recorder.ledger.lock() {
+        ledger.log_report();
+    }
+    if !attackers.is_empty() {
+        jamming::log_report(&network, jamming_report);
+    }
+    if let Some(gossip) = network.gossip() {
+        let stats = gossip.stats();
+        info!("Gossip: {} messages broadcast, {} delivered to the views and {} lost", stats.messages, stats.deliveries, stats.lost);
+    }
+    Ok(summary)
+}
@@ -0,0 +1,347 @@
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use crate::create_graph::BalanceStrategy;
+use crate::report::Summary;
+use crate::scenario::Scenario;
+use crate::simulation;
+use crate::workload::{AmountDistribution, ArrivalProcess};
+
+// Two-sided 97.5% quantiles of the Student t distribution for 1 to 30 degrees of freedom, used for
+// the 95% confidence intervals of small numbers of replications. Beyond 30 degrees of freedom the
+// normal quantile is close enough.
+const T_QUANTILES: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228,
+    2.201, 2.179, 2.160, 2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086,
+    2.080, 2.074, 2.069, 2.064, 2.060, 2.056, 2.052, 2.048, 2.045, 2.042,
+];
+const Z_QUANTILE: f64 = 1.96;
+
+// Names of the metrics compared across the grid, in the order of the columns of the results.
+const METRICS: [&str; 6] = ["success_rate", "mean_fee", "mean_path_length", "mean_latency", "revenue_gini", "depleted_channels"];
+
+// Parameter sweep of a scenario. Every combination of the listed values is run with the given
+// number of replications, and a list left empty keeps the value of the scenario.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sweep {
+    pub amounts: Vec<f64>, // Fixed payment amounts in satoshis
+    pub risk_factors: Vec<f32>, // Risk factors of the routing cost model of every sender
+    pub balances: Vec<BalanceStrategy>,
+    pub arrival_rates: Vec<f64>, // Payment arrival rates in payments per second
+    pub replications: u32, // Runs of every combination, seeded with the seed of the scenario plus the replication number
+    pub threads: Option<usize>, // Runs in parallel, by default one per available core
+}
+
+impl Default for Sweep {
+    fn default() -> Self {
+        Sweep {
+            amounts: Vec::new(),
+            risk_factors: Vec::new(),
+            balances: Vec::new(),
+            arrival_rates: Vec::new(),
+            replications: 10,
+            threads: None,
+        }
+    }
+}
+
+// A combination of the swept parameters. Parameters that are not swept are left empty.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Point {
+    pub amount: Option<f64>,
+    pub risk_factor: Option<f32>,
+    pub balances: Option<BalanceStrategy>,
+    pub arrival_rate: Option<f64>,
+}
+
+// Mean of a metric over the replications of a combination, with the half-width of its 95%
+// confidence interval.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Estimate {
+    pub mean: f64,
+    pub ci95: f64,
+}
+
+// Estimates of every metric for a combination of the swept parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PointResult {
+    pub point: Point,
+    pub replications: u32,
+    pub metrics: [Estimate; METRICS.len()],
+}
+
+// Results of a sweep, one row per combination.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SweepReport {
+    pub rows: Vec<PointResult>,
+}
+
+impl Sweep {
+    // Function to check that the swept values make sense.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.replications == 0 {
+            return Err("replications must be positive".to_string());
+        }
+        if self.threads == Some(0) {
+            return Err("threads must be positive".to_string());
+        }
+        if self.amounts.iter().any(|&amount| amount <= 0.0) {
+            return Err("amounts must be positive".to_string());
+        }
+        if self.risk_factors.iter().any(|&risk_factor| risk_factor < 0.0) {
+            return Err("risk factors must not be negative".to_string());
+        }
+        if self.arrival_rates.iter().any(|&rate| rate <= 0.0) {
+            return Err("arrival rates must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    // Function to list every combination of the swept parameters.
+    pub fn points(&self) -> Vec<Point> {
+        fn values<T: Copy>(list: &[T]) -> Vec<Option<T>> {
+            if list.is_empty() {
+                vec![None]
+            } else {
+                list.iter().copied().map(Some).collect()
+            }
+        }
+        let mut points = Vec::new();
+        for amount in values(&self.amounts) {
+            for risk_factor in values(&self.risk_factors) {
+                for balances in values(&self.balances) {
+                    for arrival_rate in values(&self.arrival_rates) {
+                        points.push(Point { amount, risk_factor, balances, arrival_rate });
+                    }
+                }
+            }
+        }
+        points
+    }
+}
+
+impl Point {
+    // Function to apply the combination to a scenario. The risk factor replaces the one of every
+    // sender, including the node classes with their own cost model. The arrival rate keeps the
+    // daily cycle of diurnal arrivals and turns any other arrival process into Poisson arrivals.
+    pub fn apply(&self, scenario: &mut Scenario) {
+        if let Some(amount) = self.amount {
+            scenario.workload.amounts = AmountDistribution::Fixed { amount };
+        }
+        if let Some(risk_factor) = self.risk_factor {
+            scenario.workload.cost_model.risk_factor = risk_factor;
+            for class in &mut scenario.workload.node_classes {
+                if let Some(cost_model) = &mut class.cost_model {
+                    cost_model.risk_factor = risk_factor;
+                }
+            }
+        }
+        if let Some(balances) = self.balances {
+            scenario.graph.balances = balances;
+        }
+        if let Some(rate) = self.arrival_rate {
+            scenario.workload.arrivals = match scenario.workload.arrivals {
+                ArrivalProcess::Diurnal { amplitude, period_secs, .. } => ArrivalProcess::Diurnal { rate, amplitude, period_secs },
+                _ => ArrivalProcess::Poisson { rate },
+            };
+        }
+    }
+}
+
+impl Estimate {
+    // Function to estimate the mean of the values of the replications with a 95% confidence
+    // interval from the Student t distribution.
+    pub fn new(values: &[f64]) -> Self {
+        let n = values.len();
+        if n == 0 {
+            return Estimate::default();
+        }
+        let mean = values.iter().sum::<f64>() / n as f64;
+        if n == 1 {
+            return Estimate { mean, ci95: 0.0 };
+        }
+        let variance = values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+        let quantile = T_QUANTILES.get(n - 2).copied().unwrap_or(Z_QUANTILE);
+        Estimate { mean, ci95: quantile * (variance / n as f64).sqrt() }
+    }
+}
+
+// Function to read the compared metrics from the summary of a run.
+fn metrics(summary: &Summary) -> [f64; METRICS.len()] {
+    [
+        summary.success_rate,
+        summary.fees.mean,
+        summary.path_length.mean,
+        summary.latency.mean,
+        summary.revenue_gini,
+        summary.depleted_channels as f64,
+    ]
+}
+
+// Function to run every combination of the sweep with its replications. Runs are spread over a pool
+// of threads, each with its own single-threaded runtime and paused clock, so that every run gives
+// the same results as when it is run alone. Runs write no per-payment results or summaries, and
+// the log is left to the caller.
+pub fn run_sweep(scenario: &Scenario, sweep: &Sweep) -> Result<SweepReport, String> {
+    sweep.validate()?;
+    let points = sweep.points();
+    let replications = sweep.replications as usize;
+    let jobs = points.len() * replications;
+    let threads = sweep.threads.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get())).min(jobs);
+    let next = AtomicUsize::new(0);
+    let summaries: Mutex<Vec<Option<Result<Summary, String>>>> = Mutex::new(vec![None; jobs]);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().start_paused(true).build() {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        // Leave the jobs to the other threads, and fail the sweep if none could start.
+                        log::error!("Failed to start a sweep runtime: {}", e);
+                        return;
+                    }
+                };
+                loop {
+                    let job = next.fetch_add(1, Ordering::Relaxed);
+                    if job >= jobs {
+                        break;
+                    }
+                    let mut run = scenario.clone();
+                    points[job / replications].apply(&mut run);
+                    run.seed = scenario.seed.wrapping_add((job % replications) as u64);
+                    run.output.payments_csv = false;
+                    run.output.payments_jsonl = false;
+                    run.output.summary = false;
+                    let summary = run.validate().and_then(|_| runtime.block_on(simulation::simulate(run)));
+                    summaries.lock().unwrap()[job] = Some(summary);
+                }
+            });
+        }
+    });
+    let summaries = summaries.into_inner().map_err(|e| e.to_string())?;
+    let mut rows = Vec::new();
+    for (point, runs) in points.into_iter().zip(summaries.chunks(replications)) {
+        let mut values = vec![Vec::new(); METRICS.len()];
+        for summary in runs {
+            let summary = summary.clone().ok_or("a run of the sweep did not start")??;
+            for (metric, value) in metrics(&summary).into_iter().enumerate() {
+                values[metric].push(value);
+            }
+        }
+        let metrics = std::array::from_fn(|metric| Estimate::new(&values[metric]));
+        rows.push(PointResult { point, replications: sweep.replications, metrics });
+    }
+    Ok(SweepReport { rows })
+}
+
+// Function to print a swept parameter, or a dash when the parameter is not swept.
+fn column<T: fmt::Debug>(value: Option<T>) -> String {
+    value.map_or("-".to_string(), |value| format!("{:?}", value).to_lowercase())
+}
+
+impl SweepReport {
+    // Function to write the results to sweep.csv, with the mean and confidence interval of every
+    // metric in two columns, and the table to sweep.txt.
+    pub fn write<P: AsRef<Path>>(&self, dir: P) -> Result<(), String> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        let mut writer = csv::Writer::from_path(dir.join("sweep.csv")).map_err(|e| e.to_string())?;
+        let mut header = vec!["amount".to_string(), "risk_factor".to_string(), "balances".to_string(), "arrival_rate".to_string(), "replications".to_string()];
+        for metric in METRICS {
+            header.push(format!("{}_mean", metric));
+            header.push(format!("{}_ci95", metric));
+        }
+        writer.write_record(&header).map_err(|e| e.to_string())?;
+        for row in &self.rows {
+            let point = row.point;
+            let mut record = vec![
+                point.amount.map_or(String::new(), |value| value.to_string()),
+                point.risk_factor.map_or(String::new(), |value| value.to_string()),
+                point.balances.map_or(String::new(), |value| column(Some(value))),
+                point.arrival_rate.map_or(String::new(), |value| value.to_string()),
+                row.replications.to_string(),
+            ];
+            for estimate in row.metrics {
+                record.push(estimate.mean.to_string());
+                record.push(estimate.ci95.to_string());
+            }
+            writer.write_record(&record).map_err(|e| e.to_string())?;
+        }
+        writer.flush().map_err(|e| e.to_string())?;
+        fs::write(dir.join("sweep.txt"), self.to_string()).map_err(|e| e.to_string())
+    }
+}
+
+// Table of the sweep with one row per combination and the mean ± the half-width of the 95%
+// confidence interval of every metric.
+impl fmt::Display for SweepReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>10} {:>12} {:>10} {:>8} {:>4}", "amount", "risk_factor", "balances", "rate", "runs")?;
+        for metric in METRICS {
+            write!(f, " {:>22}", metric)?;
+        }
+        writeln!(f)?;
+        for row in &self.rows {
+            let point = row.point;
+            write!(
+                f,
+                "{:>10} {:>12} {:>10} {:>8} {:>4}",
+                column(point.amount),
+                column(point.risk_factor),
+                column(point.balances),
+                column(point.arrival_rate),
+                row.replications
+            )?;
+            for estimate in row.metrics {
+                write!(f, " {:>22}", format!("{:.4} ± {:.4}", estimate.mean, estimate.ci95))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn confidence_intervals_use_the_t_quantile_of_the_replications() {
+        // Five replications: variance 2.5, standard error sqrt(0.5), 4 degrees of freedom.
+        let estimate = Estimate::new(&[1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_close(estimate.mean, 3.0);
+        assert_close(estimate.ci95, 2.776 * 0.5f64.sqrt());
+        // Two replications: variance 2, standard error 1, 1 degree of freedom.
+        assert_close(Estimate::new(&[2.0, 4.0]).ci95, 12.706);
+        // Forty replications fall back to the normal quantile.
+        let values: Vec<f64> = (0..40).map(|i| (i % 2 * 2) as f64).collect();
+        let estimate = Estimate::new(&values);
+        assert_close(estimate.mean, 1.0);
+        assert_close(estimate.ci95, 1.96 / 39f64.sqrt());
+        assert_eq!(Estimate::new(&[7.0]), Estimate { mean: 7.0, ci95: 0.0 });
+        assert_eq!(Estimate::new(&[]), Estimate::default());
+    }
+
+    #[test]
+    fn points_cover_every_combination_of_the_swept_values() {
+        let sweep = Sweep { amounts: vec![1000.0, 2000.0], arrival_rates: vec![0.5, 1.0, 2.0], ..Sweep::default() };
+        let points = sweep.points();
+        assert_eq!(points.len(), 6);
+        assert_eq!(points[4], Point { amount: Some(2000.0), arrival_rate: Some(1.0), ..Point::default() });
+
+        let mut scenario = Scenario::default();
+        scenario.workload.arrivals = ArrivalProcess::Diurnal { rate: 3.0, amplitude: 0.5, period_secs: 3600.0 };
+        points[4].apply(&mut scenario);
+        assert_eq!(scenario.workload.amounts, AmountDistribution::Fixed { amount: 2000.0 });
+        assert_eq!(scenario.workload.arrivals, ArrivalProcess::Diurnal { rate: 1.0, amplitude: 0.5, period_secs: 3600.0 });
+    }
+}
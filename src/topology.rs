@@ -0,0 +1,469 @@
+use log::{info, error};
+use petgraph::graph::{EdgeIndex, NodeIndex};
+use rand::Rng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Instant};
+use crate::create_graph::{sat_to_msat, EdgeAttributes, MAX_ACCEPTED_HTLCS};
+use crate::latency::LatencyModel;
+use crate::network::Network;
+use crate::payment_scheduler;
+use crate::reputation::ChannelReputation;
+use crate::workload::exponential;
+
+// Time between two checks of whether the HTLCs of a closing channel have resolved.
+const CLOSE_POLL: Duration = Duration::from_millis(10);
+
+// Change to the topology of the network during a run. Nodes are named by their id in the snapshot.
+// A node that leaves closes all of its channels and opens no new ones, but stays in the graph so
+// that the payments in flight through it can resolve.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TopologyEvent {
+    // Channel funded by node1 with the given capacity in satoshis, of which node1 holds balance1
+    // (the whole capacity by default), and the same forwarding policy in both directions.
+    OpenChannel {
+        node1: usize,
+        node2: usize,
+        capacity: f64,
+        balance1: Option<f64>,
+        #[serde(default = "default_base_fee_msat")]
+        base_fee_msat: u64,
+        #[serde(default = "default_fee_rate_ppm")]
+        fee_rate_ppm: u64,
+        #[serde(default = "default_cltv_expiry_delta")]
+        cltv_expiry_delta: u32,
+    },
+    // Cooperative close of the channel between two nodes. The channel takes no new HTLC and closes
+    // once the HTLCs in flight over it have resolved.
+    CloseChannel { node1: usize, node2: usize },
+    AddNode { node: usize },
+    RemoveNode { node: usize },
+}
+
+// Defaults of the forwarding policy of a new channel, those of LND.
+pub fn default_base_fee_msat() -> u64 {
+    1000
+}
+
+pub fn default_fee_rate_ppm() -> u64 {
+    1
+}
+
+pub fn default_cltv_expiry_delta() -> u32 {
+    80
+}
+
+// Function to create the state of a direction of a new channel with the given balance in msat and
+// forwarding policy, without HTLCs, reputation or latency.
+pub fn new_channel(balance: u64, base_fee_msat: u64, fee_rate_ppm: u64, cltv_expiry_delta: u32) -> EdgeAttributes {
+    EdgeAttributes {
+        basefee: base_fee_msat,
+        feerate: fee_rate_ppm,
+        upfrontfee: 0,
+        holdfee: 0,
+        balance,
+        delay: cltv_expiry_delta,
+        age: 0,
+        max_accepted_htlcs: MAX_ACCEPTED_HTLCS,
+        max_htlc_value_in_flight: u64::MAX,
+        pending_htlcs: 0,
+        value_in_flight: 0,
+        rejected_htlcs: 0,
+        reputation: ChannelReputation::default(),
+        latency: Duration::ZERO,
+        closed: false,
+        closing: false,
+    }
+}
+
+// Event of a topology script, applied at the given virtual time from the start of the run.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ScheduledEvent {
+    pub time_secs: f64,
+    #[serde(flatten)]
+    pub event: TopologyEvent,
+}
+
+// Model of the churn of the network, with the rate per virtual second of every kind of change. A new
+// channel copies the capacity and forwarding policy of an open channel drawn at random, and a node
+// that joins opens join_channels such channels to random nodes.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChurnModel {
+    pub open_rate: f64,
+    pub close_rate: f64,
+    pub join_rate: f64,
+    pub leave_rate: f64,
+    pub join_channels: usize,
+}
+
+impl Default for ChurnModel {
+    fn default() -> Self {
+        ChurnModel { open_rate: 0.0, close_rate: 0.0, join_rate: 0.0, leave_rate: 0.0, join_channels: 2 }
+    }
+}
+
+impl ChurnModel {
+    // Function to check that the rates of the model make sense.
+    pub fn validate(&self) -> Result<(), String> {
+        let rates = [self.open_rate, self.close_rate, self.join_rate, self.leave_rate];
+        if rates.iter().any(|rate| !rate.is_finite() || *rate < 0.0) {
+            return Err("Churn rates must be non-negative".to_string());
+        }
+        Ok(())
+    }
+
+    fn total_rate(&self) -> f64 {
+        self.open_rate + self.close_rate + self.join_rate + self.leave_rate
+    }
+}
+
+// Changes to the topology of a scenario: a script of events and a churn model, which can be used
+// together.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TopologyConfig {
+    pub script: Option<PathBuf>, // JSON file of scheduled events
+    pub churn: Option<ChurnModel>,
+}
+
+impl TopologyConfig {
+    // Function to check that the script exists and that the churn model makes sense.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(script) = &self.script {
+            if !script.is_file() {
+                return Err(format!("script {} not found", script.display()));
+            }
+        }
+        if let Some(churn) = &self.churn {
+            churn.validate()?;
+        }
+        Ok(())
+    }
+}
+
+// Function to read a topology script from a JSON file, for example
+// [{"time_secs": 60.0, "type": "add_node", "node": 1000},
+//  {"time_secs": 60.0, "type": "open_channel", "node1": 1000, "node2": 4, "capacity": 5000000.0},
+//  {"time_secs": 300.0, "type": "close_channel", "node1": 2, "node2": 7},
+//  {"time_secs": 900.0, "type": "remove_node", "node": 12}]
+// The events are returned in the order of their times.
+pub fn load_script<P: AsRef<Path>>(file_path: P) -> Result<Vec<ScheduledEvent>, String> {
+    let file_content = fs::read_to_string(file_path).map_err(|e| e.to_string())?;
+    let mut events: Vec<ScheduledEvent> = serde_json::from_str(&file_content).map_err(|e| e.to_string())?;
+    if let Some(event) = events.iter().find(|event| !event.time_secs.is_finite() || event.time_secs < 0.0) {
+        return Err(format!("Event {:?} needs a non-negative time", event.event));
+    }
+    events.sort_by(|a, b| a.time_secs.total_cmp(&b.time_secs));
+    Ok(events)
+}
+
+// Counts of the changes applied to the topology during a run.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TopologyChanges {
+    pub opened: usize,
+    pub closed: usize,
+    pub joined: usize,
+    pub left: usize,
+    pub failed: usize,
+}
+
+// State of the topology runner: the network it changes, the latency model of the new channels and
+// the waiters of the closing channels.
+struct Runner {
+    network: Arc<Network>,
+    latency: LatencyModel,
+    rng: StdRng,
+    closing: Vec<JoinHandle<()>>,
+    changes: TopologyChanges,
+}
+
+impl Runner {
+    fn node(&self, id: usize) -> Result<NodeIndex, String> {
+        self.network.node_index(id).ok_or_else(|| format!("Node {} is not in the network", id))
+    }
+
+    // Function to apply an event of the script, or one drawn by the churn model.
+    fn apply(&mut self, event: &TopologyEvent) -> Result<(), String> {
+        match *event {
+            TopologyEvent::OpenChannel { node1, node2, capacity, balance1, base_fee_msat, fee_rate_ppm, cltv_expiry_delta } => {
+                let capacity = sat_to_msat(capacity);
+                let balance1 = balance1.map_or(capacity, sat_to_msat).min(capacity);
+                let policy = new_channel(balance1, base_fee_msat, fee_rate_ppm, cltv_expiry_delta);
+                let (from, to) = (self.node(node1)?, self.node(node2)?);
+                self.open(from, to, policy, policy, capacity)
+            }
+            TopologyEvent::CloseChannel { node1, node2 } => {
+                let (from, to) = (self.node(node1)?, self.node(node2)?);
+                self.close(from, to)
+            }
+            TopologyEvent::AddNode { node } => {
+                self.network.add_node(node)?;
+                self.changes.joined += 1;
+                info!("Node {:?} joined the network", node);
+                Ok(())
+            }
+            TopologyEvent::RemoveNode { node } => {
+                let node = self.node(node)?;
+                self.leave(node)
+            }
+        }
+    }
+
+    // Function to open a channel of the given capacity in msat, with the policies of both directions.
+    // The balance of the forward policy is that of the funder, and the rest of the capacity goes to
+    // the other node. Both directions get their own latency.
+    fn open(&mut self, from: NodeIndex, to: NodeIndex, mut forward: EdgeAttributes, mut backward: EdgeAttributes, capacity: u64) -> Result<(), String> {
+        let departed = self.network.departed();
+        if departed.contains(&from) || departed.contains(&to) {
+            return Err(format!("Cannot open a channel from {:?} to {:?} as a node left", from, to));
+        }
+        backward.balance = capacity - forward.balance;
+        forward.latency = self.latency.sample(&mut self.rng)?;
+        backward.latency = self.latency.sample(&mut self.rng)?;
+        self.network.open_channel(from, to, forward, backward)?;
+        self.changes.opened += 1;
+        info!("Channel opened from {:?} to {:?} with a capacity of {:?} msat", from, to, capacity);
+        Ok(())
+    }
+
+    // Function to start the cooperative close of a channel, and wait in the background for its HTLCs
+    // to resolve before it closes.
+    fn close(&mut self, from: NodeIndex, to: NodeIndex) -> Result<(), String> {
+        self.network.start_close(from, to)?;
+        self.changes.closed += 1;
+        info!("Channel between {:?} and {:?} closing", from, to);
+        let network = Arc::clone(&self.network);
+        self.closing.push(tokio::spawn(async move {
+            while !network.finish_close(from, to) {
+                sleep(CLOSE_POLL).await;
+            }
+            info!("Channel between {:?} and {:?} closed", from, to);
+        }));
+        Ok(())
+    }
+
+    // Function to make a node leave the network by closing all of its open channels.
+    fn leave(&mut self, node: NodeIndex) -> Result<(), String> {
+        if !self.network.depart(node) {
+            return Err(format!("Node {:?} already left", node));
+        }
+        let peers: BTreeSet<NodeIndex> = self.network.topology().neighbors(node).collect();
+        for peer in peers {
+            if self.network.find_edge(node, peer).is_some_and(|edge| self.network.channel(edge).is_open()) {
+                self.close(node, peer)?;
+            }
+        }
+        self.changes.left += 1;
+        info!("Node {:?} left the network", node);
+        Ok(())
+    }
+
+    // Open channels of the network, one direction each, in the order of the edge indices.
+    fn open_channels(&self) -> Vec<EdgeIndex> {
+        let departed = self.network.departed();
+        let topology = self.network.topology();
+        topology.edge_indices()
+            .filter(|&edge| topology.edge_endpoints(edge).is_some_and(|(from, to)| from < to && !departed.contains(&from) && !departed.contains(&to)))
+            .filter(|&edge| self.network.channel(edge).is_open())
+            .collect()
+    }
+
+    // Nodes of the network that have not left.
+    fn active_nodes(&self) -> Vec<NodeIndex> {
+        let departed = self.network.departed();
+        self.network.topology().node_indices().filter(|node| !departed.contains(node)).collect()
+    }
+
+    // Function to open a channel from a node to another, copying the capacity and the policies of an
+    // open channel drawn at random.
+    fn open_like(&mut self, from: NodeIndex, to: NodeIndex, channels: &[EdgeIndex]) -> Result<(), String> {
+        let &template = channels.choose(&mut self.rng).ok_or("No open channel to copy")?;
+        let (start, end) = self.network.topology().edge_endpoints(template).ok_or("Channel not found")?;
+        let mut forward = self.network.attributes(template);
+        let mut backward = self.network.find_edge(end, start).map_or(forward, |reverse| self.network.attributes(reverse));
+        let capacity = forward.balance + forward.value_in_flight + backward.balance + backward.value_in_flight;
+        for attrs in [&mut forward, &mut backward] {
+            *attrs = EdgeAttributes { age: 0, pending_htlcs: 0, value_in_flight: 0, rejected_htlcs: 0, reputation: ChannelReputation::default(), ..*attrs };
+        }
+        forward.balance = capacity;
+        self.open(from, to, forward, backward, capacity)
+    }
+
+    // Function to draw and apply a change of the churn model, chosen with a probability proportional
+    // to its rate.
+    fn churn(&mut self, model: &ChurnModel) -> Result<(), String> {
+        let channels = self.open_channels();
+        let nodes = self.active_nodes();
+        let mut draw = self.rng.gen::<f64>() * model.total_rate();
+        if draw < model.open_rate {
+            // The new channel links a node to one it has no open channel with.
+            let &from = nodes.choose(&mut self.rng).ok_or("No node to open a channel")?;
+            let peers: Vec<NodeIndex> = nodes.iter().copied()
+                .filter(|&to| to != from && self.network.find_edge(from, to).is_none_or(|edge| self.network.channel(edge).closed))
+                .collect();
+            let &to = peers.choose(&mut self.rng).ok_or("No peer to open a channel with")?;
+            return self.open_like(from, to, &channels);
+        }
+        draw -= model.open_rate;
+        if draw < model.close_rate {
+            let &edge = channels.choose(&mut self.rng).ok_or("No open channel to close")?;
+            let (from, to) = self.network.topology().edge_endpoints(edge).ok_or("Channel not found")?;
+            return self.close(from, to);
+        }
+        draw -= model.close_rate;
+        if draw < model.join_rate {
+            // The new node copies the channels it opens from open channels, so it only joins if there
+            // are some, rather than staying without the channels it was drawn with.
+            if model.join_channels > 0 && channels.is_empty() {
+                return Err("No open channel to copy for a joining node".to_string());
+            }
+            let id = self.network.topology().node_weights().max().map_or(0, |id| id + 1);
+            let node = self.network.add_node(id)?;
+            self.changes.joined += 1;
+            info!("Node {:?} joined the network as {:?}", id, node);
+            for &peer in nodes.choose_multiple(&mut self.rng, model.join_channels) {
+                self.open_like(node, peer, &channels)?;
+            }
+            return Ok(());
+        }
+        let &node = nodes.choose(&mut self.rng).ok_or("No node to leave")?;
+        self.leave(node)
+    }
+}
+
+// Function to change the topology of the network while the payments run. The events of the script
+// are applied at their times, and the changes of the churn model at the times of a Poisson process of
+// its total rate. Changes stop once the payments have stopped, and the function returns once the
+// closing channels have closed. Events that cannot be applied, such as a channel opened to a node
+// that is not in the network, are logged and skipped. The workload keeps drawing its senders and
+// recipients among the nodes of the snapshot that have not left, so new nodes only forward payments.
+pub async fn run_topology(network: Arc<Network>, script: Vec<ScheduledEvent>, churn: Option<ChurnModel>, latency: LatencyModel, rng: StdRng, stopped: Arc<AtomicBool>) -> TopologyChanges {
+    let mut runner = Runner { network, latency, rng, closing: Vec::new(), changes: TopologyChanges::default() };
+    let churn = churn.filter(|model| model.total_rate() > 0.0);
+    let start = Instant::now();
+    let mut script = script.into_iter().peekable();
+    let mut next_churn = churn.map(|model| exponential(&mut runner.rng, model.total_rate()));
+    loop {
+        let next_script = script.peek().map(|event| event.time_secs);
+        let due = match (next_script, next_churn) {
+            (Some(a), Some(b)) => a.min(b),
+            (Some(a), None) | (None, Some(a)) => a,
+            (None, None) => break,
+        };
+        if !payment_scheduler::wait_until(start + Duration::from_secs_f64(due), &stopped).await {
+            break;
+        }
+        // Events of the script go before changes of the churn model due at the same time.
+        let result = match (script.next_if(|event| next_churn.is_none_or(|time| event.time_secs <= time)), churn) {
+            (Some(scheduled), _) => runner.apply(&scheduled.event),
+            (None, Some(model)) => {
+                next_churn = next_churn.map(|time| time + exponential(&mut runner.rng, model.total_rate()));
+                runner.churn(&model)
+            }
+            (None, None) => Ok(()),
+        };
+        if let Err(e) = result {
+            error!("Topology change skipped: {}", e);
+            runner.changes.failed += 1;
+        }
+    }
+    for handle in runner.closing.drain(..) {
+        let _ = handle.await;
+    }
+    let changes = runner.changes;
+    info!("Topology changes: {} channels opened, {} closed, {} nodes joined, {} left, {} skipped", changes.opened, changes.closed, changes.joined, changes.left, changes.failed);
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_graph::Graph;
+    use rand::SeedableRng;
+    use std::sync::atomic::Ordering;
+
+    // Function to share a network whose node ids differ from their indices: nodes 10, 11 and 12, with
+    // a channel of 1000 sats between 10 and 11 held by 10.
+    fn network() -> Arc<Network> {
+        let mut graph = Graph::new();
+        let nodes: Vec<NodeIndex> = [10, 11, 12].into_iter().map(|id| graph.add_node(id)).collect();
+        graph.add_edge(nodes[0], nodes[1], new_channel(1_000_000, 1000, 1, 40));
+        graph.add_edge(nodes[1], nodes[0], new_channel(0, 1000, 1, 40));
+        Arc::new(Network::new(graph))
+    }
+
+    fn event(time_secs: f64, event: TopologyEvent) -> ScheduledEvent {
+        ScheduledEvent { time_secs, event }
+    }
+
+    fn channel(network: &Network, from: usize, to: usize) -> EdgeAttributes {
+        let edge = network.find_edge(network.node_index(from).unwrap(), network.node_index(to).unwrap()).unwrap();
+        network.attributes(edge)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn script_names_nodes_by_their_snapshot_ids() {
+        let network = network();
+        let script = vec![
+            event(1.0, TopologyEvent::AddNode { node: 13 }),
+            event(2.0, TopologyEvent::OpenChannel { node1: 13, node2: 10, capacity: 500.0, balance1: Some(200.0), base_fee_msat: 0, fee_rate_ppm: 10, cltv_expiry_delta: 40 }),
+            event(3.0, TopologyEvent::CloseChannel { node1: 11, node2: 10 }),
+            event(4.0, TopologyEvent::RemoveNode { node: 12 }),
+            event(5.0, TopologyEvent::CloseChannel { node1: 10, node2: 99 }),
+        ];
+        let changes = run_topology(Arc::clone(&network), script, None, LatencyModel::None, StdRng::seed_from_u64(1), Arc::new(AtomicBool::new(false))).await;
+        assert_eq!(changes, TopologyChanges { opened: 1, closed: 1, joined: 1, left: 1, failed: 1 });
+        assert_eq!((channel(&network, 13, 10).balance, channel(&network, 10, 13).balance), (200_000, 300_000));
+        assert!(channel(&network, 13, 10).is_open());
+        assert!(channel(&network, 10, 11).closed && channel(&network, 11, 10).closed);
+        assert_eq!(network.departed(), BTreeSet::from([network.node_index(12).unwrap()]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cooperative_close_waits_for_the_htlcs_in_flight() {
+        let network = network();
+        let edge = network.find_edge(NodeIndex::new(0), NodeIndex::new(1)).unwrap();
+        network.channel(edge).pending_htlcs = 1;
+        let script = vec![event(1.0, TopologyEvent::CloseChannel { node1: 10, node2: 11 })];
+        let runner = tokio::spawn(run_topology(Arc::clone(&network), script, None, LatencyModel::None, StdRng::seed_from_u64(1), Arc::new(AtomicBool::new(false))));
+        sleep(Duration::from_secs(10)).await;
+        assert!(network.channel(edge).closing && !network.channel(edge).closed);
+        network.channel(edge).pending_htlcs = 0;
+        let changes = runner.await.unwrap();
+        assert_eq!(changes.closed, 1);
+        assert!(network.channel(edge).closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn joining_nodes_take_new_ids_and_copy_open_channels() {
+        let network = network();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let churn = ChurnModel { join_rate: 1.0, join_channels: 1, ..ChurnModel::default() };
+        let runner = tokio::spawn(run_topology(Arc::clone(&network), Vec::new(), Some(churn), LatencyModel::None, StdRng::seed_from_u64(1), Arc::clone(&stopped)));
+        sleep(Duration::from_secs(5)).await;
+        stopped.store(true, Ordering::Relaxed);
+        let changes = runner.await.unwrap();
+        assert!(changes.joined > 0);
+        assert_eq!((changes.opened, changes.failed), (changes.joined, 0));
+        let topology = network.topology();
+        let ids: Vec<usize> = topology.node_weights().copied().collect();
+        assert_eq!(ids, (10..13 + changes.joined).collect::<Vec<_>>());
+        // Every channel of a new node copies the capacity of the only template, 1000 sats.
+        for node in topology.node_indices().skip(3) {
+            for peer in topology.neighbors(node) {
+                let capacity = channel(&network, topology[node], topology[peer]).balance + channel(&network, topology[peer], topology[node]).balance;
+                assert_eq!(capacity, 1_000_000);
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use petgraph::graph::NodeIndex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use log::{info, error};
+use tokio::time::sleep;
+use tokio::task::JoinHandle;
+use crate::create_graph::sat_to_msat;
+use crate::find_path;
+use crate::network::Network;
+use crate::payment_router::Payment;
+use crate::payment_scheduler::{self, Recorder, StopConditions};
+use crate::results::Request;
+use crate::reputation::EndorsementConfig;
+use crate::workload::Workload;
+
+// A payment recorded in a trace. The timestamp is in seconds, and the sender and recipient are the
+// node ids used in the graph snapshot.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+pub struct TraceRecord {
+    pub timestamp: f64,
+    pub sender: usize,
+    pub recipient: usize,
+    pub amount: f64, // Amount in satoshis
+}
+
+// Function to read a payment trace from a CSV file with a header row of timestamp, sender,
+// recipient and amount, or from a JSON file holding a list of records with the same fields. The
+// records are sorted by their timestamps.
+pub fn load_trace<P: AsRef<Path>>(file_path: P) -> Result<Vec<TraceRecord>, String> {
+    let file_path = file_path.as_ref();
+    let mut records: Vec<TraceRecord> = if file_path.extension().is_some_and(|extension| extension == "json") {
+        let file_content = fs::read_to_string(file_path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&file_content).map_err(|e| e.to_string())?
+    } else {
+        let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_path(file_path).map_err(|e| e.to_string())?;
+        reader.deserialize().collect::<Result<_, _>>().map_err(|e| e.to_string())?
+    };
+    records.sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+    Ok(records)
+}
+
+// Function to replay a payment trace. Every record is started at its recorded time relative to the
+// first record, multiplied by the time scale, so that a scale below one replays the trace faster.
+// Records whose nodes are not in the graph, or have left the network by the time of the record, are
+// skipped. Senders find paths with the cost model the workload gives them, as in the scheduler. The
+// replay stops early once a stop condition is met, and returns after the payments in flight have
+// finished.
+pub async fn replay_trace(network: Arc<Network>, trace: Vec<TraceRecord>, time_scale: f64, workload: &Workload, config: EndorsementConfig, recorder: Recorder, stop: StopConditions) -> Result<(), String> {
+    // Map the node ids of the snapshot onto the indices of the graph.
+    let node_ids: HashMap<usize, NodeIndex> = {
+        let topology = network.topology();
+        topology.node_indices().map(|index| (topology[index], index)).collect()
+    };
+    let Some(first) = trace.first() else {
+        return Ok(());
+    };
+    let first_timestamp = first.timestamp;
+    let start = Instant::now();
+    let mut skipped = 0;
+    let mut payments = Vec::new();
+    for (i, record) in trace.iter().enumerate() {
+        let payment_id = i as u64 + 1;
+        let (Some(&sender), Some(&recipient)) = (node_ids.get(&record.sender), node_ids.get(&record.recipient)) else {
+            error!("Trace record {:?} skipped as its nodes are not in the graph", payment_id);
+            skipped += 1;
+            continue;
+        };
+        // Wait until the recorded time of the payment.
+        let due = Duration::from_secs_f64(((record.timestamp - first_timestamp) * time_scale).max(0.0));
+        if let Some(wait) = due.checked_sub(start.elapsed()) {
+            sleep(wait).await;
+        }
+        if stop.check(&recorder.progress, start.elapsed())? {
+            break;
+        }
+        let departed = network.departed();
+        if departed.contains(&sender) || departed.contains(&recipient) {
+            error!("Trace record {:?} skipped as its nodes left the network", payment_id);
+            skipped += 1;
+            continue;
+        }
+        let amount = sat_to_msat(record.amount);
+        let (path,timelocks,amounts) = find_path::dijkstra_with_cost(&network, recipient, sender, amount, workload.cost_model_of(sender.index()));
+        let payment = Payment::new(payment_id, path, timelocks, amounts);
+        info!("Payment no. {:?} started from {:?} to {:?}", payment_id, sender, recipient);
+        payments.retain(|handle: &JoinHandle<()>| !handle.is_finished());
+        let request = Request { payment_id, sender, recipient, amount };
+        payments.push(payment_scheduler::start_payment(Arc::clone(&network), payment, request, config, recorder.clone()));
+    }
+    info!("Trace replayed with {:?} of {:?} records skipped", skipped, trace.len());
+    payment_scheduler::drain(payments).await;
+    Ok(())
+}
@@ -0,0 +1,304 @@
+use petgraph::graph::NodeIndex;
+use rand::distributions::WeightedIndex;
+use rand::prelude::Distribution;
+use rand::Rng;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use crate::create_graph::sat_to_msat;
+use crate::find_path::CostModel;
+
+// Lowest arrival rate in payments per second, about one payment every 30 years. Lower rates draw
+// delays too long for the clock of the simulation.
+const MIN_RATE: f64 = 1e-9;
+
+// Arrival process of the payments, deciding the delay between starting two payments.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ArrivalProcess {
+    // Delay drawn uniformly between zero and the maximum delay.
+    Uniform { max_delay_ms: u64 },
+    // Poisson arrivals with the given rate in payments per second.
+    Poisson { rate: f64 },
+    // Poisson arrivals whose rate follows a daily cycle. The rate swings by the amplitude (as a
+    // fraction of the rate) around the mean rate over every period.
+    Diurnal { rate: f64, amplitude: f64, period_secs: f64 },
+}
+
+// Distribution of the payment amounts in satoshis.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AmountDistribution {
+    Fixed { amount: f64 },
+    // Amounts whose logarithm is normally distributed with mean mu and standard deviation sigma.
+    LogNormal { mu: f64, sigma: f64 },
+    // Amounts of at least the scale, with a tail that gets heavier as the shape gets smaller.
+    Pareto { scale: f64, shape: f64 },
+    // Amounts drawn from the samples in a file, given as a JSON list of numbers.
+    Empirical {
+        file: String,
+        #[serde(skip)]
+        samples: Vec<f64>,
+    },
+}
+
+// A class of nodes, such as merchants, custodial wallets or exchanges, that send and receive payments
+// more often than the other nodes. Nodes outside of every class have a weight of one.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NodeClass {
+    pub name: String,
+    pub nodes: Vec<usize>, // Ids of the nodes in the snapshot, their indices in the graph once resolved
+    pub sender_weight: f64, // Weight of every node in the class when picking the sender
+    pub recipient_weight: f64, // Weight of every node in the class when picking the recipient
+    #[serde(default)]
+    pub cost_model: Option<CostModel>, // Cost model of the nodes in the class when they send payments
+}
+
+// Structure to hold the workload of a simulation.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Workload {
+    pub arrivals: ArrivalProcess,
+    pub amounts: AmountDistribution,
+    #[serde(default)]
+    pub node_classes: Vec<NodeClass>,
+    #[serde(default)]
+    pub cost_model: CostModel, // Cost model of the senders outside of every class with a cost model
+}
+
+// The default workload starts payments of 1000 satoshis between uniformly chosen nodes, with a delay
+// of up to 10 milliseconds between them.
+impl Default for Workload {
+    fn default() -> Self {
+        Workload {
+            arrivals: ArrivalProcess::Uniform { max_delay_ms: 10 },
+            amounts: AmountDistribution::Fixed { amount: 1000.0 },
+            node_classes: Vec::new(),
+            cost_model: CostModel::default(),
+        }
+    }
+}
+
+// Weighted choice of senders and recipients among the nodes of the graph, along with the cost model
+// every node uses to find the path of its payments.
+pub struct NodePicker {
+    senders: WeightedIndex<f64>,
+    recipients: WeightedIndex<f64>,
+    cost_models: Vec<CostModel>,
+}
+
+impl NodePicker {
+    pub fn sender<R: Rng + ?Sized>(&self, rng: &mut R) -> NodeIndex {
+        NodeIndex::new(self.senders.sample(rng))
+    }
+
+    pub fn recipient<R: Rng + ?Sized>(&self, rng: &mut R) -> NodeIndex {
+        NodeIndex::new(self.recipients.sample(rng))
+    }
+
+    pub fn cost_model(&self, sender: NodeIndex) -> CostModel {
+        self.cost_models[sender.index()]
+    }
+
+    // Function to stop drawing the given nodes as senders and recipients, such as the nodes that left
+    // the network. Nodes that joined during the run are never drawn in the first place.
+    pub fn exclude(&mut self, nodes: &BTreeSet<NodeIndex>) -> Result<(), String> {
+        let weights: Vec<(usize, &f64)> = nodes.iter().map(|node| node.index()).filter(|&node| node < self.cost_models.len()).map(|node| (node, &0.0)).collect();
+        self.senders.update_weights(&weights).map_err(|_| "No sender is left in the network".to_string())?;
+        self.recipients.update_weights(&weights).map_err(|_| "No recipient is left in the network".to_string())
+    }
+}
+
+// Function to convert a delay in seconds into a duration, saturating delays too long for a duration.
+fn seconds(secs: f64) -> Duration {
+    Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX)
+}
+
+// Function to draw a sample from the standard normal distribution using the Box-Muller transform.
+pub fn standard_normal<R: Rng + ?Sized>(rng: &mut R) -> f64 {
+    let u1: f64 = 1.0 - rng.gen::<f64>();
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+// Function to draw a sample from the exponential distribution with the given rate.
+pub fn exponential<R: Rng + ?Sized>(rng: &mut R, rate: f64) -> f64 {
+    -(1.0 - rng.gen::<f64>()).ln() / rate
+}
+
+impl Workload {
+    // Function to read a workload from a JSON file, for example
+    // {"arrivals": {"type": "poisson", "rate": 50.0},
+    //  "amounts": {"type": "log_normal", "mu": 8.0, "sigma": 1.5},
+    //  "node_classes": [{"name": "exchanges", "nodes": [1, 2], "sender_weight": 20.0, "recipient_weight": 20.0,
+    //                    "cost_model": {"risk_factor": 0.000000015}}]}
+    pub fn load<P: AsRef<Path>>(file_path: P) -> Result<Workload, String> {
+        let file_content = fs::read_to_string(file_path).map_err(|e| e.to_string())?;
+        let mut workload: Workload = serde_json::from_str(&file_content).map_err(|e| e.to_string())?;
+        workload.load_samples()?;
+        workload.validate()?;
+        Ok(workload)
+    }
+
+    // Function to read the samples of an empirical amount distribution from its file.
+    pub fn load_samples(&mut self) -> Result<(), String> {
+        if let AmountDistribution::Empirical { file, samples } = &mut self.amounts {
+            let file_content = fs::read_to_string(&*file).map_err(|e| format!("{}: {}", file, e))?;
+            *samples = serde_json::from_str(&file_content).map_err(|e| format!("{}: {}", file, e))?;
+        }
+        Ok(())
+    }
+
+    // Function to check that the parameters of the workload make sense.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.arrivals {
+            ArrivalProcess::Poisson { rate } if !rate.is_finite() || rate < MIN_RATE => return Err(format!("Poisson arrival rate must be at least {} payments per second", MIN_RATE)),
+            ArrivalProcess::Diurnal { rate, amplitude, period_secs } if !rate.is_finite() || rate < MIN_RATE || !(0.0..=1.0).contains(&amplitude) || !period_secs.is_finite() || period_secs <= 0.0 => {
+                return Err(format!("Diurnal arrivals need a rate of at least {} payments per second, a positive period and an amplitude between 0 and 1", MIN_RATE));
+            }
+            _ => {}
+        }
+        match &self.amounts {
+            AmountDistribution::Fixed { amount } if !amount.is_finite() || *amount <= 0.0 => return Err("Fixed amount must be positive".to_string()),
+            AmountDistribution::LogNormal { mu, sigma } if !mu.is_finite() || !sigma.is_finite() || *sigma < 0.0 => return Err("Log-normal amounts need a finite mu and sigma, and sigma must not be negative".to_string()),
+            AmountDistribution::Pareto { scale, shape } if !scale.is_finite() || !shape.is_finite() || *scale <= 0.0 || *shape <= 0.0 => return Err("Pareto scale and shape must be positive".to_string()),
+            AmountDistribution::Empirical { file, samples } if samples.is_empty() => return Err(format!("Empirical amount file {} has no samples", file)),
+            _ => {}
+        }
+        if self.cost_model.risk_factor < 0.0 || self.cost_model.expected_hold_time < 0.0 {
+            return Err("Cost model parameters must not be negative".to_string());
+        }
+        for class in &self.node_classes {
+            if class.sender_weight < 0.0 || class.recipient_weight < 0.0 {
+                return Err(format!("Node class {} has a negative weight", class.name));
+            }
+            if class.cost_model.is_some_and(|model| model.risk_factor < 0.0 || model.expected_hold_time < 0.0) {
+                return Err(format!("Node class {} has a negative cost model parameter", class.name));
+            }
+        }
+        Ok(())
+    }
+
+    // Function to compute the delay before starting the next payment. The elapsed time since the
+    // start of the simulation decides the current rate of diurnal arrivals.
+    pub fn next_delay<R: Rng + ?Sized>(&self, rng: &mut R, elapsed: Duration) -> Duration {
+        match self.arrivals {
+            ArrivalProcess::Uniform { max_delay_ms } => Duration::from_millis(rng.gen_range(0..max_delay_ms.max(1))),
+            ArrivalProcess::Poisson { rate } => seconds(exponential(rng, rate)),
+            ArrivalProcess::Diurnal { rate, amplitude, period_secs } => {
+                // Draw arrivals at the peak rate and thin them to the rate at their arrival time.
+                let peak = rate * (1.0 + amplitude);
+                let start = elapsed.as_secs_f64();
+                let mut time = start;
+                loop {
+                    time += exponential(rng, peak);
+                    let current = rate * (1.0 + amplitude * (2.0 * PI * time / period_secs).sin());
+                    if rng.gen::<f64>() * peak <= current {
+                        return seconds(time - start);
+                    }
+                }
+            }
+        }
+    }
+
+    // Function to draw the amount of the next payment, in msat.
+    pub fn next_amount<R: Rng + ?Sized>(&self, rng: &mut R) -> u64 {
+        let amount = match &self.amounts {
+            AmountDistribution::Fixed { amount } => *amount,
+            AmountDistribution::LogNormal { mu, sigma } => (mu + sigma * standard_normal(rng)).exp(),
+            AmountDistribution::Pareto { scale, shape } => scale / (1.0 - rng.gen::<f64>()).powf(1.0 / shape),
+            AmountDistribution::Empirical { samples, .. } => samples[rng.gen_range(0..samples.len())],
+        };
+        sat_to_msat(amount)
+    }
+
+    // Function to get the cost model a node finds paths with, the one of the last class of the node
+    // that has a cost model, as in node_picker.
+    pub fn cost_model_of(&self, node: usize) -> CostModel {
+        self.node_classes.iter().rev()
+            .filter(|class| class.nodes.contains(&node))
+            .find_map(|class| class.cost_model)
+            .unwrap_or(self.cost_model)
+    }
+
+    // Function to build the weighted choice of senders and recipients for a graph with the given
+    // number of nodes.
+    pub fn node_picker(&self, node_count: usize) -> Result<NodePicker, String> {
+        let mut sender_weights = vec![1.0; node_count];
+        let mut recipient_weights = vec![1.0; node_count];
+        let mut cost_models = vec![self.cost_model; node_count];
+        for class in &self.node_classes {
+            for &node in &class.nodes {
+                if node >= node_count {
+                    return Err(format!("Node {} of class {} is not in the graph", node, class.name));
+                }
+                sender_weights[node] = class.sender_weight;
+                recipient_weights[node] = class.recipient_weight;
+                if let Some(cost_model) = class.cost_model {
+                    cost_models[node] = cost_model;
+                }
+            }
+        }
+        Ok(NodePicker {
+            senders: WeightedIndex::new(sender_weights).map_err(|e| e.to_string())?,
+            recipients: WeightedIndex::new(recipient_weights).map_err(|e| e.to_string())?,
+            cost_models,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn workload(arrivals: ArrivalProcess, amounts: AmountDistribution) -> Workload {
+        Workload { arrivals, amounts, ..Workload::default() }
+    }
+
+    #[test]
+    fn validate_rejects_rates_and_amounts_that_cannot_be_drawn() {
+        let fixed = AmountDistribution::Fixed { amount: 1000.0 };
+        for rate in [0.0, -1.0, 1e-12, f64::NAN, f64::INFINITY] {
+            assert!(workload(ArrivalProcess::Poisson { rate }, fixed.clone()).validate().is_err(), "{}", rate);
+            assert!(workload(ArrivalProcess::Diurnal { rate, amplitude: 0.5, period_secs: 86400.0 }, fixed.clone()).validate().is_err(), "{}", rate);
+        }
+        assert!(workload(ArrivalProcess::Diurnal { rate: 1.0, amplitude: f64::NAN, period_secs: 86400.0 }, fixed.clone()).validate().is_err());
+        assert!(workload(ArrivalProcess::Diurnal { rate: 1.0, amplitude: 0.5, period_secs: f64::INFINITY }, fixed.clone()).validate().is_err());
+        let poisson = ArrivalProcess::Poisson { rate: 1.0 };
+        assert!(workload(poisson.clone(), AmountDistribution::Fixed { amount: f64::INFINITY }).validate().is_err());
+        assert!(workload(poisson.clone(), AmountDistribution::LogNormal { mu: f64::NAN, sigma: 1.0 }).validate().is_err());
+        assert!(workload(poisson.clone(), AmountDistribution::Pareto { scale: 1000.0, shape: 0.0 }).validate().is_err());
+        assert!(workload(poisson, fixed).validate().is_ok());
+    }
+
+    #[test]
+    fn seeded_generator_draws_the_same_delays_and_amounts() {
+        let workload = workload(ArrivalProcess::Uniform { max_delay_ms: 100 }, AmountDistribution::Pareto { scale: 1000.0, shape: 2.0 });
+        let mut rng = StdRng::seed_from_u64(7);
+        let draws: Vec<(Duration, u64)> = (0..4).map(|_| (workload.next_delay(&mut rng, Duration::ZERO), workload.next_amount(&mut rng))).collect();
+        let expected = [(3, 1_201_325), (14, 1_478_470), (27, 4_523_604), (17, 1_159_944)];
+        assert_eq!(draws, expected.map(|(ms, msat)| (Duration::from_millis(ms), msat)));
+    }
+
+    #[test]
+    fn poisson_delays_average_the_inverse_of_the_rate() {
+        let workload = workload(ArrivalProcess::Poisson { rate: 10.0 }, AmountDistribution::Fixed { amount: 1000.0 });
+        let mut rng = StdRng::seed_from_u64(1);
+        let total: Duration = (0..10_000).map(|_| workload.next_delay(&mut rng, Duration::ZERO)).sum();
+        assert!((total.as_secs_f64() / 10_000.0 - 0.1).abs() < 0.005, "{:?}", total);
+        assert_eq!(workload.next_amount(&mut rng), 1_000_000);
+    }
+
+    #[test]
+    fn delays_too_long_for_a_duration_saturate() {
+        let workload = workload(ArrivalProcess::Poisson { rate: 1e-30 }, AmountDistribution::Fixed { amount: 1000.0 });
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(workload.next_delay(&mut rng, Duration::ZERO), Duration::MAX);
+    }
+}
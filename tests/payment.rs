@@ -0,0 +1,52 @@
+// Integration tests of the lifecycle of a single payment through the API of the library.
+use lnsimulator::{dijkstra, new_channel, EndorsementConfig, Graph, Network, Payment, RecipientBehaviour};
+use petgraph::graph::NodeIndex;
+use std::sync::Arc;
+
+// Function to build a line of three nodes, 0 - 1 - 2, with channels of 1000 sats in both directions
+// and a base fee of one sat.
+fn line() -> Arc<Network> {
+    let mut graph = Graph::new();
+    let nodes: Vec<NodeIndex> = (0..3).map(|id| graph.add_node(id)).collect();
+    for pair in nodes.windows(2) {
+        graph.add_edge(pair[0], pair[1], new_channel(1_000_000, 1000, 0, 40));
+        graph.add_edge(pair[1], pair[0], new_channel(1_000_000, 1000, 0, 40));
+    }
+    Arc::new(Network::new(graph))
+}
+
+// Function to get the balance in msat of the channel from one node to another.
+fn balance(network: &Network, from: usize, to: usize) -> u64 {
+    let edge = network.find_edge(NodeIndex::new(from), NodeIndex::new(to)).unwrap();
+    let balance = network.channel(edge).balance;
+    balance
+}
+
+#[tokio::test(start_paused = true)]
+async fn settled_payment_moves_the_amount_and_the_fee() {
+    let network = line();
+    let (path, timelocks, amounts) = dijkstra(&network, NodeIndex::new(2), NodeIndex::new(0), 100_000);
+    assert_eq!(path, vec![NodeIndex::new(0), NodeIndex::new(1), NodeIndex::new(2)]);
+    // The sender offers the amount plus the base fee of the channel from node 1 to node 2.
+    assert_eq!(amounts, vec![101_000, 100_000, 100_000]);
+    let mut payment = Payment::new(1, path, timelocks, amounts);
+    Payment::payment_manager(Arc::clone(&network), &mut payment, EndorsementConfig::default()).await.unwrap();
+    assert_eq!(payment.failure(), None);
+    assert_eq!(balance(&network, 0, 1), 1_000_000 - 101_000);
+    assert_eq!(balance(&network, 1, 0), 1_000_000 + 101_000);
+    assert_eq!(balance(&network, 1, 2), 1_000_000 - 100_000);
+    assert_eq!(balance(&network, 2, 1), 1_000_000 + 100_000);
+}
+
+#[tokio::test(start_paused = true)]
+async fn rejected_payment_returns_the_funds() {
+    let network = line();
+    let (path, timelocks, amounts) = dijkstra(&network, NodeIndex::new(2), NodeIndex::new(0), 100_000);
+    let mut payment = Payment::new(1, path, timelocks, amounts);
+    payment.set_recipient_behaviour(RecipientBehaviour::Reject);
+    assert!(Payment::payment_manager(Arc::clone(&network), &mut payment, EndorsementConfig::default()).await.is_err());
+    assert_eq!(payment.failed_at(), Some(2));
+    for (from, to) in [(0, 1), (1, 0), (1, 2), (2, 1)] {
+        assert_eq!(balance(&network, from, to), 1_000_000);
+    }
+}
@@ -0,0 +1,103 @@
+// Integration tests of the simulation of a scenario through the API of the library.
+use lnsimulator::{new_channel, write_graph, ArrivalProcess, CostModel, Graph, NodeClass, PaymentHook, PaymentRecord, Scenario, TraceSource};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Function to write a ring of five nodes with well funded channels in both directions, and to return
+// a scenario that runs 50 payments on it without writing any output.
+fn ring_scenario(name: &str) -> Scenario {
+    let dir: PathBuf = std::env::temp_dir().join(format!("lnsimulator-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut graph = Graph::new();
+    let nodes: Vec<_> = (0..5).map(|id| graph.add_node(id)).collect();
+    for (i, &node) in nodes.iter().enumerate() {
+        let peer = nodes[(i + 1) % nodes.len()];
+        graph.add_edge(node, peer, new_channel(1_000_000_000, 1000, 1, 40));
+        graph.add_edge(peer, node, new_channel(1_000_000_000, 1000, 1, 40));
+    }
+    let path = dir.join("ring.json");
+    write_graph(&graph, &path).unwrap();
+    let mut scenario = Scenario::default();
+    scenario.graph.path = path;
+    scenario.stop.max_payments = Some(50);
+    scenario.output.dir = dir;
+    scenario.output.payments_csv = false;
+    scenario.output.payments_jsonl = false;
+    scenario.output.summary = false;
+    scenario.validate().unwrap();
+    scenario
+}
+
+#[tokio::test(start_paused = true)]
+async fn payments_succeed_on_a_well_funded_ring() {
+    let summary = lnsimulator::simulate(ring_scenario("ring")).await.unwrap();
+    assert_eq!(summary.payments, 50);
+    assert_eq!(summary.succeeded, 50);
+}
+
+#[tokio::test(start_paused = true)]
+async fn hooks_see_every_payment() {
+    let calls = Arc::new(AtomicU64::new(0));
+    let counter = calls.clone();
+    let hook: PaymentHook = Arc::new(move |_: &PaymentRecord| {
+        counter.fetch_add(1, Ordering::Relaxed);
+    });
+    let summary = lnsimulator::simulate_with_hooks(ring_scenario("hooks"), vec![hook]).await.unwrap();
+    assert_eq!(calls.load(Ordering::Relaxed), summary.payments);
+}
+
+#[tokio::test(start_paused = true)]
+async fn payments_settle_when_they_arrive_blocks_apart() {
+    // A mean delay of ten seconds is ten blocks of the default chain, more than the final expiry
+    // margin of the recipient, so the expiries have to be counted from the height at which every
+    // payment starts.
+    let mut scenario = ring_scenario("poisson");
+    scenario.workload.arrivals = ArrivalProcess::Poisson { rate: 0.1 };
+    scenario.stop.max_payments = Some(20);
+    let summary = lnsimulator::simulate(scenario).await.unwrap();
+    assert_eq!(summary.payments, 20);
+    assert_eq!(summary.succeeded, 20);
+}
+
+#[tokio::test(start_paused = true)]
+async fn replayed_senders_use_the_cost_model_of_their_class() {
+    // Two paths from node 0 to node 3: through node 1 with low fees and long delays, and through node
+    // 2 with higher fees and short delays. The default cost model barely weighs the delays, while the
+    // class of node 0 weighs them heavily.
+    let dir = std::env::temp_dir().join(format!("lnsimulator-trace-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut graph = Graph::new();
+    let nodes: Vec<_> = (0..4).map(|id| graph.add_node(id)).collect();
+    for (via, basefee, delay) in [(1, 1000, 1000), (2, 5000, 40)] {
+        for (from, to) in [(0, via), (via, 3)] {
+            graph.add_edge(nodes[from], nodes[to], new_channel(1_000_000_000, basefee, 0, delay));
+            graph.add_edge(nodes[to], nodes[from], new_channel(1_000_000_000, basefee, 0, delay));
+        }
+    }
+    let graph_path = dir.join("paths.json");
+    write_graph(&graph, &graph_path).unwrap();
+    let trace_path = dir.join("trace.csv");
+    std::fs::write(&trace_path, "timestamp,sender,recipient,amount\n0,0,3,1000\n").unwrap();
+    let mut scenario = Scenario::default();
+    scenario.graph.path = graph_path;
+    scenario.trace = Some(TraceSource { path: trace_path, time_scale: 1.0 });
+    scenario.workload.node_classes.push(NodeClass {
+        name: "patient".to_string(),
+        nodes: vec![0],
+        sender_weight: 1.0,
+        recipient_weight: 1.0,
+        cost_model: Some(CostModel { risk_factor: 1.0, ..CostModel::default() }),
+    });
+    scenario.output.dir = dir;
+    scenario.output.payments_csv = false;
+    scenario.output.payments_jsonl = false;
+    scenario.output.summary = false;
+    scenario.validate().unwrap();
+    let paths = Arc::new(Mutex::new(Vec::new()));
+    let recorded = paths.clone();
+    let hook: PaymentHook = Arc::new(move |record: &PaymentRecord| recorded.lock().unwrap().push(record.path.clone()));
+    let summary = lnsimulator::simulate_with_hooks(scenario, vec![hook]).await.unwrap();
+    assert_eq!(summary.succeeded, 1);
+    assert_eq!(*paths.lock().unwrap(), vec![vec![0, 2, 3]]);
+}